@@ -13,81 +13,15 @@ struct Atm {
     balance: u64
 }
 
-type AtmProtocol = proto!(
-    Recv String, // get the account id
-    loop {
-        goto AtmMenu
-    }
-);
-
-type AtmMenu = proto!(
-    Accept {
-        {goto AtmDeposit}, // user wants to deposit
-        {goto AtmWithdraw}, // user wants to withdraw
-        {goto AtmGetBalance}, // user wants to get balance
-        End // user is done
-    }
-);
-
-type AtmDeposit = proto!(
-    Recv u64, // get the amount they're depositing
-    Send u64, // tell them their new balance
-    continue
-);
-
-type AtmWithdraw = proto!(
-    Recv u64,  // get the amount they're withdrawing
-    Send bool, // tell them if withdrawal succeeded
-    continue
-);
-
-type AtmGetBalance = proto!(
-    Send u64,
-    continue
-);
-
-impl Protocol for Atm {
-    type Initial = AtmProtocol;
-}
-
-handlers!(
-    Atm(String, u64, bool);
-
-    this(AtmMenu => End) => {
-        this.close()
-    }
-
-    this(AtmMenu => AtmGetBalance) => {
-        let cur_balance = this.proto.balance;
-        this.send(cur_balance).pop().accept().ok().unwrap()
-    }
-
-    this(AtmMenu => AtmDeposit) => {
-        match this.recv() {
-            Ok((amt, mut this)) => {
-                this.proto.balance += amt;
-                let new_balance = this.proto.balance;
-                this.send(new_balance).pop().accept().ok().unwrap()
-            },
-            _ => panic!("Client unexpectedly dropped")
-        }
-    }
+session! {
+    Atm(String, u64, bool) = AtmProtocol;
 
-    this(AtmMenu => AtmWithdraw) => {
-        match this.recv() {
-            Ok((amt, mut this)) => {
-                if this.proto.balance < amt {
-                    this.send(false)
-                } else {
-                    this.proto.balance -= amt;
-                    this.send(true)
-                }.pop().accept().ok().unwrap()
-            },
-            _ => panic!("Client unexpectedly dropped")
+    AtmProtocol = {
+        Recv String, // get the account id
+        loop {
+            goto AtmMenu
         }
-    }
-
-    this(AtmProtocol) => {
+    } => |this| {
         match this.recv() {
             Ok((_, this)) => {
                 this.enter().accept().ok().unwrap()
@@ -97,7 +31,61 @@ handlers!(
             }
         }
     }
-);
+
+    loop AtmMenu = {
+        Accept {
+            {goto AtmDeposit}, // user wants to deposit
+            {goto AtmWithdraw}, // user wants to withdraw
+            {goto AtmGetBalance}, // user wants to get balance
+            End // user is done
+        }
+    } {
+        AtmDeposit = {
+            Recv u64, // get the amount they're depositing
+            Send u64, // tell them their new balance
+            continue
+        } => |this| {
+            match this.recv() {
+                Ok((amt, mut this)) => {
+                    this.proto.balance += amt;
+                    let new_balance = this.proto.balance;
+                    this.send(new_balance).pop().accept().ok().unwrap()
+                },
+                _ => panic!("Client unexpectedly dropped")
+            }
+        }
+
+        AtmWithdraw = {
+            Recv u64,  // get the amount they're withdrawing
+            Send bool, // tell them if withdrawal succeeded
+            continue
+        } => |this| {
+            match this.recv() {
+                Ok((amt, mut this)) => {
+                    if this.proto.balance < amt {
+                        this.send(false)
+                    } else {
+                        this.proto.balance -= amt;
+                        this.send(true)
+                    }.pop().accept().ok().unwrap()
+                },
+                _ => panic!("Client unexpectedly dropped")
+            }
+        }
+
+        AtmGetBalance = {
+            Send u64,
+            continue
+        } => |this| {
+            let cur_balance = this.proto.balance;
+            this.send(cur_balance).pop().accept().ok().unwrap()
+        }
+
+        End => |this| {
+            this.close()
+        }
+    }
+}
 
 fn main() {
     use std::thread;
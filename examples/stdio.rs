@@ -0,0 +1,68 @@
+// Runs a protocol between this process and a copy of itself spawned as
+// a child, over the child's stdin and stdout.
+
+#[macro_use]
+extern crate nemo;
+use nemo::*;
+use nemo::session_types::*;
+use nemo::channels::Stdio;
+use std::env;
+use std::process::{Command, Stdio as Pipe};
+
+struct Upper;
+
+type Shout = proto!(
+    Send String,
+    Recv String,
+    End
+);
+
+type Listen = proto!(
+    Recv String,
+    Send String,
+    End
+);
+
+impl Protocol for Upper {
+    type Initial = Shout;
+}
+
+impl<I: Transfers<String>, E: SessionType> Handler<I, E, Shout> for Upper {
+    fn with(this: Channel<Self, I, E, Shout>) -> Defer<Self, I> {
+        match this.send(String::from("hello from the parent")).recv() {
+            Ok((msg, this)) => {
+                println!("child replied: {}", msg);
+                this.close()
+            },
+            Err(_) => panic!("child hung up")
+        }
+    }
+}
+
+impl<I: Transfers<String>, E: SessionType> Handler<I, E, Listen> for Upper {
+    fn with(this: Channel<Self, I, E, Listen>) -> Defer<Self, I> {
+        match this.recv() {
+            Ok((msg, this)) => this.send(msg.to_uppercase()).close(),
+            Err(_) => panic!("parent hung up")
+        }
+    }
+}
+
+fn main() {
+    if env::args().nth(1).map_or(false, |arg| arg == "child") {
+        channel_dual(Stdio::current(), Upper).defer().with();
+        return;
+    }
+
+    let mut child = Command::new(env::current_exe().unwrap())
+                            .arg("child")
+                            .stdin(Pipe::piped())
+                            .stdout(Pipe::piped())
+                            .spawn()
+                            .unwrap();
+
+    let io = Stdio::child(&mut child).unwrap();
+    channel(io, Upper).defer().with();
+
+    assert!(child.wait().unwrap().success());
+}
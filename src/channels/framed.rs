@@ -0,0 +1,499 @@
+//! A framing layer for running sessions over byte streams. Any
+//! `Read + Write` stream wrapped in `Framed` is an `IO` backend: payloads
+//! are sent in their wire encoding, discriminants are sent as variable
+//! length integers, and closing the channel sends a close frame.
+//!
+//! Every frame starts with a variable length integer giving its kind.
+//! A discriminant frame is followed by the discriminant, a payload frame
+//! by the payload's length and then its bytes, and a close frame by
+//! nothing.
+//!
+//! As an `IO` backend, `Framed` buffers everything a handler sends until
+//! the channel is flushed, so a chain of small sends goes out in one
+//! write. Large payloads sent by reference are the exception: their
+//! frame header joins whatever is buffered, which goes out in one write,
+//! and the payload follows in a second write straight from the caller's
+//! memory. That is the gather a vectored write would do; the std this
+//! crate builds with has none, so it takes two writes instead of one, but
+//! the payload is never copied.
+//!
+//! Non-blocking streams are supported: partially read frames are kept
+//! until the rest arrives, and partially written ones are finished by
+//! the next write or `flush`. Receiving from a stream which would block
+//! fails the same way as receiving from a peer which hung up, so the
+//! handler should `defer` and try again later.
+
+use std::io::{self, Read, Write};
+use wire::{self, Encode, Decode};
+use super::super::{Transfers, TransfersRef, IO};
+
+/// The default limit on the size of a payload frame, 16 MiB.
+pub const DEFAULT_MAX_FRAME: usize = 16 * 1024 * 1024;
+
+/// Payloads sent by reference which are at least this large are written
+/// from the caller's memory rather than copied into the write buffer.
+pub const DIRECT_WRITE: usize = 4096;
+
+const DISCRIMINANT: u64 = 0;
+const PAYLOAD: u64 = 1;
+const CLOSE: u64 = 2;
+
+/// A frame on the stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Frame {
+    Discriminant(usize),
+    Payload(Vec<u8>),
+    Close
+}
+
+enum Parse {
+    Done(Frame, usize),
+    Incomplete,
+    Malformed
+}
+
+fn parse_varint(input: &[u8], at: &mut usize) -> Result<u64, Parse> {
+    let mut num: u64 = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = match input.get(*at) {
+            Some(&byte) => byte,
+            None => return Err(Parse::Incomplete)
+        };
+        *at += 1;
+
+        if shift == 63 && byte > 1 {
+            return Err(Parse::Malformed);
+        }
+
+        num |= ((byte & 0x7f) as u64) << shift;
+
+        if byte & 0x80 == 0 {
+            return Ok(num);
+        }
+
+        shift += 7;
+    }
+}
+
+fn parse(input: &[u8], max_frame: usize) -> Parse {
+    let mut at = 0;
+
+    macro_rules! varint {
+        () => (match parse_varint(input, &mut at) {
+            Ok(num) => num,
+            Err(parse) => return parse
+        })
+    }
+
+    match varint!() {
+        DISCRIMINANT => {
+            let num = varint!();
+            if num > usize::max_value() as u64 {
+                return Parse::Malformed;
+            }
+
+            Parse::Done(Frame::Discriminant(num as usize), at)
+        },
+        PAYLOAD => {
+            let len = varint!();
+            if len > max_frame as u64 {
+                return Parse::Malformed;
+            }
+
+            let len = len as usize;
+            if input.len() - at < len {
+                return Parse::Incomplete;
+            }
+
+            Parse::Done(Frame::Payload(input[at..at + len].to_vec()), at + len)
+        },
+        CLOSE => Parse::Done(Frame::Close, at),
+        _ => Parse::Malformed
+    }
+}
+
+// Write as much of `bytes` as `stream` accepts without blocking, and
+// return how much that was.
+fn write_some<S: Write>(stream: &mut S, bytes: &[u8]) -> (usize, io::Result<()>) {
+    let mut written = 0;
+    while written < bytes.len() {
+        match stream.write(&bytes[written..]) {
+            Ok(0) => return (written, Err(io::Error::new(io::ErrorKind::WriteZero, "stream closed"))),
+            Ok(n) => written += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Err(err) => return (written, Err(err))
+        }
+    }
+
+    (written, Ok(()))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// A byte stream carrying frames.
+pub struct Framed<S> {
+    stream: S,
+    max_frame: usize,
+    rbuf: Vec<u8>,
+    wbuf: Vec<u8>,
+    // the stream failed, the peer broke the framing or sent something
+    // the session didn't expect, or the peer closed; nothing more is
+    // received
+    done: bool
+}
+
+impl<S: Read + Write> Framed<S> {
+    pub fn new(stream: S) -> Framed<S> {
+        Framed {
+            stream: stream,
+            max_frame: DEFAULT_MAX_FRAME,
+            rbuf: vec![],
+            wbuf: vec![],
+            done: false
+        }
+    }
+
+    /// Limit payload frames, in either direction, to `max` bytes.
+    pub fn max_frame(mut self, max: usize) -> Framed<S> {
+        self.max_frame = max;
+        self
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Queue a frame without writing anything.
+    pub fn queue_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        match *frame {
+            Frame::Discriminant(num) => {
+                wire::write_varint(&mut self.wbuf, DISCRIMINANT);
+                wire::write_varint(&mut self.wbuf, num as u64);
+            },
+            Frame::Payload(ref bytes) => try!(self.queue_payload(&[], bytes)),
+            Frame::Close => wire::write_varint(&mut self.wbuf, CLOSE)
+        }
+
+        Ok(())
+    }
+
+    /// Queue a frame and try to write everything queued so far. If the
+    /// stream would block the frame stays queued, and `Ok` is returned.
+    pub fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        try!(self.queue_frame(frame));
+        self.flush()
+    }
+
+    // The frame header of a payload made of `head` and `tail`, followed
+    // by `head`.
+    fn payload_prefix(&self, head: &[u8], tail: &[u8]) -> io::Result<Vec<u8>> {
+        let len = head.len() + tail.len();
+        if len > self.max_frame {
+            return Err(invalid("payload frame too large"));
+        }
+
+        let mut prefix = Vec::with_capacity(head.len() + 11);
+        wire::write_varint(&mut prefix, PAYLOAD);
+        wire::write_varint(&mut prefix, len as u64);
+        prefix.extend_from_slice(head);
+
+        Ok(prefix)
+    }
+
+    /// Queue a payload frame whose contents are `head` followed by `tail`.
+    pub fn queue_payload(&mut self, head: &[u8], tail: &[u8]) -> io::Result<()> {
+        let prefix = try!(self.payload_prefix(head, tail));
+        self.wbuf.extend_from_slice(&prefix);
+        self.wbuf.extend_from_slice(tail);
+
+        Ok(())
+    }
+
+    /// Write everything queued so far and then a payload frame whose
+    /// contents are `head` followed by `tail`. The frame header and
+    /// `head` are queued and written along with everything else, and
+    /// then `tail` is written straight from the caller's memory; only
+    /// what the stream doesn't take is copied into the queue.
+    pub fn write_payload(&mut self, head: &[u8], tail: &[u8]) -> io::Result<()> {
+        let prefix = try!(self.payload_prefix(head, tail));
+        self.wbuf.extend_from_slice(&prefix);
+        let mut result = self.flush();
+
+        let mut written = 0;
+        if result.is_ok() && self.wbuf.is_empty() {
+            let (n, res) = write_some(&mut self.stream, tail);
+            written = n;
+            result = res;
+        }
+
+        // whatever the stream didn't take waits for the next write
+        self.wbuf.extend_from_slice(&tail[written..]);
+
+        match result {
+            Ok(()) if self.wbuf.is_empty() => self.stream.flush(),
+            result => result
+        }
+    }
+
+    /// Write as much of the queued data as the stream accepts.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let (written, result) = write_some(&mut self.stream, &self.wbuf);
+        self.wbuf.drain(..written);
+
+        match result {
+            Ok(()) if self.wbuf.is_empty() => self.stream.flush(),
+            result => result
+        }
+    }
+
+    /// The number of bytes queued but not yet written.
+    pub fn pending(&self) -> usize {
+        self.wbuf.len()
+    }
+
+    /// Read the next frame. Returns `Ok(None)` if the stream would block
+    /// before a whole frame arrived. A stream which ends is an
+    /// `UnexpectedEof` error even between frames, since a peer which is
+    /// done sends a close frame first.
+    pub fn read_frame(&mut self) -> io::Result<Option<Frame>> {
+        loop {
+            match parse(&self.rbuf, self.max_frame) {
+                Parse::Done(frame, len) => {
+                    self.rbuf.drain(..len);
+                    return Ok(Some(frame));
+                },
+                Parse::Malformed => return Err(invalid("malformed frame")),
+                Parse::Incomplete => {}
+            }
+
+            let mut chunk = [0; 4096];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    let message = if self.rbuf.is_empty() {
+                        "stream ended without a close frame"
+                    } else {
+                        "stream ended inside a frame"
+                    };
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, message));
+                },
+                Ok(n) => self.rbuf.extend_from_slice(&chunk[..n]),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(err) => return Err(err)
+            }
+        }
+    }
+
+    fn recv_frame(&mut self) -> Option<Frame> {
+        if self.done {
+            return None;
+        }
+
+        // anything we still owe the peer must go out before we wait on it
+        if self.flush().is_err() {
+            self.done = true;
+            return None;
+        }
+
+        match self.read_frame() {
+            Ok(Some(Frame::Close)) | Err(_) => {
+                self.done = true;
+                None
+            },
+            Ok(frame) => frame
+        }
+    }
+}
+
+unsafe impl<S: Read + Write> IO for Framed<S> {
+    unsafe fn close(&mut self) {
+        let _ = self.write_frame(&Frame::Close);
+    }
+
+    unsafe fn send_discriminant(&mut self, num: usize) {
+        let _ = self.queue_frame(&Frame::Discriminant(num));
+    }
+
+    unsafe fn flush(&mut self) {
+        let _ = Framed::flush(self);
+    }
+
+    unsafe fn recv_discriminant(&mut self) -> Option<usize> {
+        match self.recv_frame() {
+            Some(Frame::Discriminant(num)) => Some(num),
+            Some(_) => {
+                self.done = true;
+                None
+            },
+            None => None
+        }
+    }
+}
+
+unsafe impl<S: Read + Write, T: Encode + Decode> Transfers<T> for Framed<S> {
+    unsafe fn send(&mut self, obj: T) {
+        self.send_ref(&obj)
+    }
+
+    unsafe fn recv(&mut self) -> Option<T> {
+        match self.recv_frame() {
+            Some(Frame::Payload(bytes)) => match wire::from_bytes(&bytes) {
+                Some(obj) => Some(obj),
+                None => {
+                    self.done = true;
+                    None
+                }
+            },
+            Some(_) => {
+                self.done = true;
+                None
+            },
+            None => None
+        }
+    }
+
+    fn size_of(&self, obj: &T) -> Option<usize> {
+        Some(wire::to_bytes(obj).len())
+    }
+}
+
+unsafe impl<S: Read + Write, T: Encode + ?Sized> TransfersRef<T> for Framed<S> {
+    unsafe fn send_ref(&mut self, obj: &T) {
+        let mut head = vec![];
+        let tail = obj.encode_split(&mut head).unwrap_or(&[]);
+
+        let result = if tail.len() >= DIRECT_WRITE {
+            self.write_payload(&head, tail)
+        } else {
+            self.queue_payload(&head, tail)
+        };
+
+        match result {
+            // the peer would reject the frame; better to stop here
+            Err(ref err) if err.kind() == io::ErrorKind::InvalidData => self.done = true,
+            _ => {}
+        }
+    }
+
+    fn size_of_ref(&self, obj: &T) -> Option<usize> {
+        Some(wire::to_bytes(obj).len())
+    }
+}
+
+#[test]
+fn check_partial_frames() {
+    use std::collections::VecDeque;
+    use std::cmp;
+
+    // a non-blocking stream which moves at most three bytes at a time
+    struct Trickle {
+        data: VecDeque<u8>
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.data.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "empty"));
+            }
+
+            let n = cmp::min(3, cmp::min(buf.len(), self.data.len()));
+            for (i, byte) in self.data.drain(..n).enumerate() {
+                buf[i] = byte;
+            }
+
+            Ok(n)
+        }
+    }
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = cmp::min(3, buf.len());
+            self.data.extend(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut framed = Framed::new(Trickle { data: VecDeque::new() }).max_frame(64);
+
+    framed.write_frame(&Frame::Discriminant(300)).unwrap();
+    framed.write_frame(&Frame::Payload(b"hello world".to_vec())).unwrap();
+    framed.write_frame(&Frame::Close).unwrap();
+    assert_eq!(framed.pending(), 0);
+
+    assert_eq!(framed.read_frame().unwrap(), Some(Frame::Discriminant(300)));
+    assert_eq!(framed.read_frame().unwrap(), Some(Frame::Payload(b"hello world".to_vec())));
+    assert_eq!(framed.read_frame().unwrap(), Some(Frame::Close));
+    assert_eq!(framed.read_frame().unwrap(), None);
+
+    // half a frame waits for the rest
+    framed.get_mut().data.extend(&[1, 2, b'h']);
+    assert_eq!(framed.read_frame().unwrap(), None);
+    framed.get_mut().data.extend(&[b'i']);
+    assert_eq!(framed.read_frame().unwrap(), Some(Frame::Payload(b"hi".to_vec())));
+
+    // oversized frames are refused in both directions
+    assert!(framed.write_frame(&Frame::Payload(vec![0; 65])).is_err());
+    framed.get_mut().data.extend(&[1, 65]);
+    assert!(framed.read_frame().is_err());
+
+    // a stream which ends is never read again
+    let mut ended = Framed::new(io::Cursor::new(vec![]));
+    assert_eq!(ended.read_frame().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    assert_eq!(unsafe { ended.recv_discriminant() }, None);
+    assert!(ended.done);
+}
+
+#[test]
+fn check_direct_writes() {
+    // records the size of every write
+    struct Writes(Vec<usize>);
+
+    impl Read for Writes {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    impl Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.push(buf.len());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut framed = Framed::new(Writes(vec![]));
+    let big = vec![7u8; DIRECT_WRITE * 2];
+
+    unsafe {
+        framed.send_discriminant(1);
+        TransfersRef::<[u8]>::send_ref(&mut framed, &big[..]);
+    }
+
+    // the discriminant and the frame header go out together, and the
+    // payload on its own
+    let writes = &framed.get_ref().0;
+    assert_eq!(writes.len(), 2);
+    assert_eq!(writes[1], DIRECT_WRITE * 2);
+    assert_eq!(framed.pending(), 0);
+}
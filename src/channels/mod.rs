@@ -0,0 +1,170 @@
+//! Channels are implementations of `IO` which can be used when building
+//! `Session` and designing protocols.
+
+pub mod framed;
+pub mod mux;
+mod pipe;
+mod record;
+mod sim;
+mod stdio;
+pub mod traced;
+
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::time::Duration;
+use std::mem;
+use super::{Protocol, Split, Timer, Transfers, TransfersRef, Wait, IO};
+use super::session_types::{SessionType, WellFormed};
+pub use self::framed::Framed;
+pub use self::mux::{Mux, Stream};
+pub use self::pipe::{BytePipe, PipeStream};
+pub use self::record::{Record, Replay, Trace, Op};
+pub use self::sim::{Sim, SimEndpoint};
+pub use self::stdio::Stdio;
+pub use self::traced::Traced;
+
+/// This is an implementation of a blocking channel IO backend. Internally
+/// it uses MPSC queues.
+pub struct Blocking {
+    tx: Sender<Box<usize>>,
+    rx: Receiver<Box<usize>>,
+    // late discriminants to drop before receiving anything else
+    skip: usize
+}
+
+impl Blocking {
+    /// Create a new bi-directional channel for protocols.
+    pub fn new<P: Protocol>(a: P, b: P) -> (super::Channel<P, Blocking, (), P::Initial>, super::Channel<P, Blocking, (), <P::Initial as SessionType>::Dual>)
+        where P::Initial: WellFormed, <P::Initial as SessionType>::Dual: WellFormed
+    {
+        let (io1, io2) = Blocking::pair();
+
+        (
+            super::channel(io1, a),
+            super::channel_dual(io2, b)
+        )
+    }
+
+    /// Create the two ends of a bi-directional channel without starting
+    /// a session on them, so that they can be wrapped by another `IO`.
+    pub fn pair() -> (Blocking, Blocking) {
+        let (tx1, rx1) = channel();
+        let (tx2, rx2) = channel();
+
+        (
+            Blocking {
+                tx: tx1,
+                rx: rx2,
+                skip: 0
+            },
+            Blocking {
+                tx: tx2,
+                rx: rx1,
+                skip: 0
+            }
+        )
+    }
+
+    // Drop the answers to deadlines which had already passed, which the
+    // peer sent before anything else. Returns false if it hung up.
+    fn skip_late(&mut self) -> bool {
+        while self.skip > 0 {
+            if self.rx.recv().is_err() {
+                return false;
+            }
+            self.skip -= 1;
+        }
+
+        true
+    }
+}
+
+unsafe impl IO for Blocking {
+    unsafe fn close(&mut self) {
+        // we can close the channel now
+    }
+
+    /// Send a variable length integer over the channel.
+    unsafe fn send_discriminant(&mut self, num: usize) {
+        self.send(num)
+    }
+
+    /// Receive a variable length integer from the channel.
+    unsafe fn recv_discriminant(&mut self) -> Option<usize> {
+        self.recv()
+    }
+}
+
+unsafe impl<T: Send + 'static> Transfers<T> for Blocking {
+    unsafe fn send(&mut self, obj: T) {
+        self.tx.send(mem::transmute(Box::new(obj))).unwrap();
+    }
+
+    unsafe fn recv(&mut self) -> Option<T> {
+        if !self.skip_late() {
+            return None;
+        }
+
+        // the other side hung up
+        let tmp: Box<usize> = match self.rx.recv() {
+            Ok(tmp) => tmp,
+            Err(_) => return None
+        };
+        let tmp: Box<T> = mem::transmute(tmp);
+
+        Some(*tmp)
+    }
+}
+
+unsafe impl Timer for Blocking {
+    unsafe fn recv_discriminant_within(&mut self, limit: Duration) -> Wait<usize> {
+        // the other side hung up
+        if !self.skip_late() {
+            return Wait::Closed;
+        }
+
+        match self.rx.recv_timeout(limit) {
+            Ok(num) => Wait::Arrived(*num),
+            Err(RecvTimeoutError::Timeout) => Wait::Expired,
+            Err(RecvTimeoutError::Disconnected) => Wait::Closed
+        }
+    }
+
+    unsafe fn skip_discriminant(&mut self) {
+        self.skip += 1;
+    }
+}
+
+// Each side makes a queue for each half to receive on, and hands the
+// sending ends to its peer over the parent channel.
+unsafe impl Split for Blocking {
+    type Half = Blocking;
+
+    unsafe fn split(&mut self) -> Option<(Blocking, Blocking)> {
+        let (tx1, rx1) = channel();
+        let (tx2, rx2) = channel();
+        Transfers::<Sender<Box<usize>>>::send(self, tx1);
+        Transfers::<Sender<Box<usize>>>::send(self, tx2);
+
+        match (self.recv(), self.recv()) {
+            (Some(peer1), Some(peer2)) => Some((
+                Blocking {
+                    tx: peer1,
+                    rx: rx1,
+                    skip: 0
+                },
+                Blocking {
+                    tx: peer2,
+                    rx: rx2,
+                    skip: 0
+                }
+            )),
+            _ => None
+        }
+    }
+}
+
+unsafe impl<T: ToOwned + ?Sized> TransfersRef<T> for Blocking where T::Owned: Send + 'static {
+    unsafe fn send_ref(&mut self, obj: &T) {
+        Transfers::send(self, obj.to_owned())
+    }
+}
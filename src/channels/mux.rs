@@ -0,0 +1,786 @@
+//! Multiplexing many sessions over a single byte stream. Each session
+//! runs over its own `Stream`, which is an `IO` backend like any other,
+//! so the sessions on one connection may belong to different
+//! `Protocol`s.
+//!
+//! Every packet on the connection is a `Framed` payload naming the
+//! stream it belongs to. Flow control is per stream and counted in
+//! packets: a sender may have at most `WINDOW` packets in flight, and
+//! the receiver hands credit back as its handlers consume them. Packets
+//! beyond the window wait in the sending stream's outbox, so a stalled
+//! session never holds up the others. Packets are buffered on the
+//! connection until one of the streams is flushed.
+//!
+//! Credit only arrives while something reads the connection. Once the
+//! `Mux` and all of its streams are dropped, the connection goes on
+//! reading until every outbox has been sent, which blocks on a blocking
+//! stream. A non-blocking one can't wait there, so call `Mux::pump`
+//! until nothing is queued before letting go of it, or whatever is still
+//! queued, including the close of each stream, is lost.
+//!
+//! New streams are announced over a control session which is itself
+//! session-typed; see `Announce`. Each side runs one control session
+//! for the streams it opens, and ids are allocated so that the two
+//! sides never collide. Streams are also `Split` for `Par`; the side
+//! which opened a stream allocates the ids of its halves.
+//!
+//! Before anything is sent on a new stream, an open packet outside of
+//! flow control makes its id known to the peer. Ids are opened in
+//! increasing order and never reused, so a packet for an id which was
+//! never opened, or has since been closed by both sides, is a protocol
+//! violation and breaks the connection. So is a stream which sends more
+//! than its window allows.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::rc::Rc;
+use wire::{self, Encode, Decode};
+use super::framed::{Frame, Framed};
+use super::super::{Channel, Defer, Handler, Protocol, Split, Transfers, TransfersRef, IO, channel, channel_dual};
+use super::super::session_types::*;
+use super::super::peano::Z;
+
+/// The number of packets a stream may have in flight before the peer
+/// hands back credit.
+pub const WINDOW: u32 = 64;
+
+/// The control session, from the side opening streams: announce a
+/// stream id and the name of the service it is for, any number of
+/// times, then hang up.
+pub type Announce = Nest<Offer>;
+
+type Offer = Choose<Send<(u32, String), Escape<Z>>, Finally<End>>;
+type Listen = Accept<Recv<(u32, String), Escape<Z>>, Finally<End>>;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Item {
+    Discriminant(usize),
+    Payload(Vec<u8>),
+    Close
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Packet {
+    Data(u32, Item),
+    Credit(u32, u32),
+    Open(u32)
+}
+
+impl Encode for Packet {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            Packet::Data(id, Item::Discriminant(num)) => { out.push(0); id.encode(out); num.encode(out); },
+            Packet::Data(id, Item::Payload(ref bytes)) => { out.push(1); id.encode(out); bytes[..].encode(out); },
+            Packet::Data(id, Item::Close) => { out.push(2); id.encode(out); },
+            Packet::Credit(id, credit) => { out.push(3); id.encode(out); credit.encode(out); },
+            Packet::Open(id) => { out.push(4); id.encode(out); }
+        }
+    }
+}
+
+impl Decode for Packet {
+    fn decode(input: &mut &[u8]) -> Option<Packet> {
+        let tag = match u8::decode(input) {
+            Some(tag) => tag,
+            None => return None
+        };
+        let id = match u32::decode(input) {
+            Some(id) => id,
+            None => return None
+        };
+
+        match tag {
+            0 => usize::decode(input).map(|num| Packet::Data(id, Item::Discriminant(num))),
+            1 => Vec::decode(input).map(|bytes| Packet::Data(id, Item::Payload(bytes))),
+            2 => Some(Packet::Data(id, Item::Close)),
+            3 => u32::decode(input).map(|credit| Packet::Credit(id, credit)),
+            4 => Some(Packet::Open(id)),
+            _ => None
+        }
+    }
+}
+
+struct StreamState {
+    inbox: VecDeque<Item>,
+    outbox: VecDeque<Item>,
+    credit: u32,
+    consumed: u32,
+    // the peer sent something this stream didn't expect
+    broken: bool,
+    closed_local: bool,
+    closed_remote: bool
+}
+
+impl StreamState {
+    fn new() -> StreamState {
+        StreamState {
+            inbox: VecDeque::new(),
+            outbox: VecDeque::new(),
+            credit: WINDOW,
+            consumed: 0,
+            broken: false,
+            closed_local: false,
+            closed_remote: false
+        }
+    }
+}
+
+struct Inner<S: Read + Write> {
+    framed: Framed<S>,
+    streams: HashMap<u32, StreamState>,
+    next_id: u32,
+    // the lowest id the peer may still open
+    peer_next: u32,
+    // the connection failed or the peer broke the packet format
+    broken: bool,
+    // writing failed, so the peer is gone; what it sent before it went
+    // can still be read
+    write_failed: bool
+}
+
+impl<S: Read + Write> Inner<S> {
+    fn write(&mut self, packet: &Packet) {
+        if !self.write_failed && self.framed.queue_frame(&Frame::Payload(wire::to_bytes(packet))).is_err() {
+            self.write_failed = true;
+        }
+    }
+
+    fn flush(&mut self) {
+        if !self.write_failed && self.framed.flush().is_err() {
+            self.write_failed = true;
+        }
+    }
+
+    // Allocate the next `count` ids, two apart, and tell the peer about
+    // them. Returns the first.
+    fn open(&mut self, count: u32) -> u32 {
+        let first = self.next_id;
+        for n in 0..count {
+            let id = first + 2 * n;
+            self.streams.insert(id, StreamState::new());
+            self.write(&Packet::Open(id));
+        }
+        self.next_id += 2 * count;
+
+        first
+    }
+
+    fn enqueue(&mut self, id: u32, item: Item) {
+        match self.streams.get_mut(&id) {
+            Some(state) => state.outbox.push_back(item),
+            None => return
+        }
+        self.drain(id);
+    }
+
+    // Send whatever the stream's credit allows. Closing costs no credit,
+    // but still waits behind the packets queued before it.
+    fn drain(&mut self, id: u32) {
+        loop {
+            let item = match self.streams.get_mut(&id) {
+                Some(state) => match state.outbox.front() {
+                    Some(&Item::Close) => state.outbox.pop_front().unwrap(),
+                    Some(_) if state.credit > 0 => {
+                        state.credit -= 1;
+                        state.outbox.pop_front().unwrap()
+                    },
+                    _ => return
+                },
+                None => return
+            };
+
+            self.write(&Packet::Data(id, item));
+        }
+    }
+
+    // Read one packet from the connection. Returns false if nothing
+    // could be read.
+    fn read_one(&mut self) -> bool {
+        if self.broken {
+            return false;
+        }
+
+        let packet = match self.framed.read_frame() {
+            Ok(Some(Frame::Payload(bytes))) => wire::from_bytes(&bytes),
+            Ok(None) => return false,
+            _ => None
+        };
+
+        match packet {
+            Some(Packet::Data(id, item)) => {
+                if !self.streams.contains_key(&id) {
+                    return self.violation();
+                }
+
+                if item == Item::Close {
+                    self.streams.get_mut(&id).unwrap().closed_remote = true;
+                    self.forget(id);
+                } else {
+                    let state = self.streams.get_mut(&id).unwrap();
+                    // everything not yet credited back is either waiting
+                    // here or counted as consumed
+                    if state.inbox.len() + state.consumed as usize >= WINDOW as usize {
+                        state.broken = true;
+                    } else {
+                        state.inbox.push_back(item);
+                    }
+                }
+            },
+            Some(Packet::Credit(id, credit)) => {
+                // credit may still arrive for a stream we've forgotten
+                if let Some(state) = self.streams.get_mut(&id) {
+                    state.credit = state.credit.saturating_add(credit);
+                }
+                // the handler flushed these when it sent them
+                self.drain(id);
+                self.flush();
+                self.forget(id);
+            },
+            Some(Packet::Open(id)) => {
+                if id % 2 != self.peer_next % 2 || id < self.peer_next || id > ::std::u32::MAX - 2 {
+                    return self.violation();
+                }
+                self.peer_next = id + 2;
+                self.streams.insert(id, StreamState::new());
+            },
+            None => return self.violation()
+        }
+
+        true
+    }
+
+    // The peer broke the protocol, so nothing more is read.
+    fn violation(&mut self) -> bool {
+        self.broken = true;
+        false
+    }
+
+    // Take the next item for stream `id`, reading from the connection
+    // until one arrives or nothing more can be read.
+    fn take(&mut self, id: u32) -> Option<Item> {
+        self.flush();
+
+        loop {
+            let (item, credit) = {
+                let state = match self.streams.get_mut(&id) {
+                    Some(state) => state,
+                    None => return None
+                };
+                if state.broken {
+                    return None;
+                }
+                match state.inbox.pop_front() {
+                    Some(item) => {
+                        state.consumed += 1;
+                        if state.consumed >= WINDOW / 2 {
+                            let credit = state.consumed;
+                            state.consumed = 0;
+                            (Some(item), Some(credit))
+                        } else {
+                            (Some(item), None)
+                        }
+                    },
+                    None if state.closed_remote => return None,
+                    None => (None, None)
+                }
+            };
+
+            if let Some(credit) = credit {
+                self.write(&Packet::Credit(id, credit));
+            }
+            if item.is_some() {
+                return item;
+            }
+
+            if !self.read_one() {
+                return None;
+            }
+        }
+    }
+
+    // The number of packets waiting for credit, on all streams.
+    fn queued(&self) -> usize {
+        self.streams.values().map(|state| state.outbox.len()).sum()
+    }
+
+    // Read the connection for as long as packets wait for credit and
+    // something can be read. Returns how many still wait.
+    fn settle(&mut self) -> usize {
+        self.flush();
+        while self.queued() > 0 && self.read_one() { }
+        self.flush();
+
+        self.queued()
+    }
+
+    fn forget(&mut self, id: u32) {
+        let done = match self.streams.get(&id) {
+            Some(state) => state.closed_local && state.closed_remote && state.outbox.is_empty(),
+            None => false
+        };
+
+        if done {
+            self.streams.remove(&id);
+        }
+    }
+}
+
+impl<S: Read + Write> Drop for Inner<S> {
+    fn drop(&mut self) {
+        self.settle();
+    }
+}
+
+/// One logical session on a `Mux`.
+pub struct Stream<S: Read + Write> {
+    id: u32,
+    inner: Rc<RefCell<Inner<S>>>
+}
+
+impl<S: Read + Write> Stream<S> {
+    /// The id of this stream on the connection.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// The number of packets waiting for credit before they can be sent.
+    pub fn queued(&self) -> usize {
+        self.inner.borrow().streams.get(&self.id).map_or(0, |state| state.outbox.len())
+    }
+
+    fn recv_item(&mut self) -> Option<Item> {
+        self.inner.borrow_mut().take(self.id)
+    }
+
+    fn broken(&mut self) {
+        if let Some(state) = self.inner.borrow_mut().streams.get_mut(&self.id) {
+            state.broken = true;
+        }
+    }
+}
+
+impl<S: Read + Write> Drop for Stream<S> {
+    fn drop(&mut self) {
+        let mut inner = self.inner.borrow_mut();
+        let closed = inner.streams.get(&self.id).map_or(true, |state| state.closed_local);
+
+        if !closed {
+            inner.enqueue(self.id, Item::Close);
+            inner.flush();
+        }
+        if let Some(state) = inner.streams.get_mut(&self.id) {
+            state.closed_local = true;
+        }
+        inner.forget(self.id);
+    }
+}
+
+unsafe impl<S: Read + Write> IO for Stream<S> {
+    unsafe fn close(&mut self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.enqueue(self.id, Item::Close);
+        inner.flush();
+        if let Some(state) = inner.streams.get_mut(&self.id) {
+            state.closed_local = true;
+        }
+        inner.forget(self.id);
+    }
+
+    unsafe fn flush(&mut self) {
+        self.inner.borrow_mut().flush();
+    }
+
+    unsafe fn send_discriminant(&mut self, num: usize) {
+        self.inner.borrow_mut().enqueue(self.id, Item::Discriminant(num));
+    }
+
+    unsafe fn recv_discriminant(&mut self) -> Option<usize> {
+        match self.recv_item() {
+            Some(Item::Discriminant(num)) => Some(num),
+            Some(_) => {
+                self.broken();
+                None
+            },
+            None => None
+        }
+    }
+}
+
+// The side which opened a stream allocates the ids of both halves when
+// it is split, two apart like any pair of its ids, and announces the
+// first over the stream itself.
+unsafe impl<S: Read + Write> Split for Stream<S> {
+    type Half = Stream<S>;
+
+    unsafe fn split(&mut self) -> Option<(Stream<S>, Stream<S>)> {
+        let first = {
+            let mut inner = self.inner.borrow_mut();
+
+            if self.id % 2 == inner.next_id % 2 {
+                let id = inner.open(2);
+                inner.enqueue(self.id, Item::Discriminant(id as usize));
+                id
+            } else {
+                match inner.take(self.id) {
+                    // the halves were opened before they were announced
+                    Some(Item::Discriminant(id)) if id <= ::std::u32::MAX as usize - 2 &&
+                                                    inner.streams.contains_key(&(id as u32)) &&
+                                                    inner.streams.contains_key(&(id as u32 + 2)) => id as u32,
+                    Some(_) => {
+                        if let Some(state) = inner.streams.get_mut(&self.id) {
+                            state.broken = true;
+                        }
+                        return None;
+                    },
+                    None => return None
+                }
+            }
+        };
+
+        Some((
+            Stream { id: first, inner: self.inner.clone() },
+            Stream { id: first + 2, inner: self.inner.clone() }
+        ))
+    }
+}
+
+unsafe impl<S: Read + Write, T: Encode + Decode> Transfers<T> for Stream<S> {
+    unsafe fn send(&mut self, obj: T) {
+        self.inner.borrow_mut().enqueue(self.id, Item::Payload(wire::to_bytes(&obj)));
+    }
+
+    unsafe fn recv(&mut self) -> Option<T> {
+        match self.recv_item() {
+            Some(Item::Payload(bytes)) => match wire::from_bytes(&bytes) {
+                Some(obj) => Some(obj),
+                None => {
+                    self.broken();
+                    None
+                }
+            },
+            Some(_) => {
+                self.broken();
+                None
+            },
+            None => None
+        }
+    }
+
+    fn size_of(&self, obj: &T) -> Option<usize> {
+        Some(wire::to_bytes(obj).len())
+    }
+}
+
+unsafe impl<S: Read + Write, T: Encode + ?Sized> TransfersRef<T> for Stream<S> {
+    unsafe fn send_ref(&mut self, obj: &T) {
+        self.inner.borrow_mut().enqueue(self.id, Item::Payload(wire::to_bytes(obj)));
+    }
+
+    fn size_of_ref(&self, obj: &T) -> Option<usize> {
+        Some(wire::to_bytes(obj).len())
+    }
+}
+
+/// The control protocol. The accepting side collects announcements for
+/// `Mux::accept`.
+pub struct Control {
+    announced: Rc<RefCell<VecDeque<(u32, String)>>>
+}
+
+impl Protocol for Control {
+    type Initial = Announce;
+}
+
+impl<I: Transfers<(u32, String)>> Handler<I, (Listen, ()), Listen> for Control {
+    fn with(this: Channel<Self, I, (Listen, ()), Listen>) -> Defer<Self, I> {
+        match this.accept() {
+            Ok(d) => d,
+            Err(this) => this.defer()
+        }
+    }
+}
+
+impl<I: Transfers<(u32, String)>> Handler<I, (Listen, ()), Recv<(u32, String), Escape<Z>>> for Control {
+    fn with(this: Channel<Self, I, (Listen, ()), Recv<(u32, String), Escape<Z>>>) -> Defer<Self, I> {
+        match this.recv() {
+            Ok((announcement, this)) => {
+                this.proto.announced.borrow_mut().push_back(announcement);
+                this.pop().defer()
+            },
+            Err(this) => this.defer()
+        }
+    }
+}
+
+impl<I: IO> Handler<I, (Listen, ()), End> for Control {
+    fn with(this: Channel<Self, I, (Listen, ()), End>) -> Defer<Self, I> {
+        this.close()
+    }
+}
+
+/// Many sessions over one byte stream. One end of the connection must
+/// be created with `client` and the other with `server`; after that
+/// both sides may open and accept streams.
+///
+/// Streams share the connection through a reference count, so a `Mux`
+/// and its streams stay on one thread. Receiving on a stream reads the
+/// connection until a packet for that stream arrives, buffering packets
+/// for the others; over a non-blocking connection it fails if none is
+/// there yet, and the handler should `defer`. Dropping the last of them
+/// waits for any packets still queued to go out; see the module docs.
+pub struct Mux<S: Read + Write> {
+    inner: Rc<RefCell<Inner<S>>>,
+    outgoing: Option<Channel<Control, Stream<S>, (Offer, ()), Offer>>,
+    incoming: Defer<Control, Stream<S>>,
+    incoming_open: bool,
+    announced: Rc<RefCell<VecDeque<(u32, String)>>>
+}
+
+impl<S: Read + Write> Mux<S> {
+    /// Multiplex over the connecting end of `stream`.
+    pub fn client(stream: S) -> Mux<S> {
+        Mux::new(stream, 0)
+    }
+
+    /// Multiplex over the listening end of `stream`.
+    pub fn server(stream: S) -> Mux<S> {
+        Mux::new(stream, 1)
+    }
+
+    fn new(stream: S, side: u32) -> Mux<S> {
+        let inner = Rc::new(RefCell::new(Inner {
+            framed: Framed::new(stream),
+            streams: HashMap::new(),
+            // control sessions take ids 0 and 1; the low bit of every
+            // id says which side opened it
+            next_id: 2 + side,
+            peer_next: 3 - side,
+            broken: false,
+            write_failed: false
+        }));
+        let announced = Rc::new(RefCell::new(VecDeque::new()));
+
+        let ours = Stream { id: side, inner: inner.clone() };
+        let theirs = Stream { id: 1 - side, inner: inner.clone() };
+        inner.borrow_mut().streams.insert(side, StreamState::new());
+        inner.borrow_mut().streams.insert(1 - side, StreamState::new());
+
+        Mux {
+            inner: inner,
+            outgoing: Some(channel(ours, Control { announced: announced.clone() }).enter()),
+            incoming: channel_dual(theirs, Control { announced: announced.clone() }).enter().defer(),
+            incoming_open: true,
+            announced: announced
+        }
+    }
+
+    /// Open a new stream for the service `name`. The peer receives it
+    /// from `accept`.
+    pub fn open(&mut self, name: &str) -> Stream<S> {
+        let id = self.inner.borrow_mut().open(1);
+
+        let control = self.outgoing.take().unwrap();
+        self.outgoing = Some(control.choose::<Send<(u32, String), Escape<Z>>>()
+                                    .send((id, String::from(name)))
+                                    .pop()
+                                    .flush());
+
+        Stream { id: id, inner: self.inner.clone() }
+    }
+
+    /// Take the next stream the peer opened, with the name of the
+    /// service it asked for.
+    pub fn accept(&mut self) -> Option<(String, Stream<S>)> {
+        // one step to receive the branch, one to receive the announcement
+        for _ in 0..2 {
+            if !self.announced.borrow().is_empty() || !self.incoming_open {
+                break;
+            }
+            self.incoming_open = self.incoming.with();
+        }
+
+        let (id, name) = match self.announced.borrow_mut().pop_front() {
+            Some(announcement) => announcement,
+            None => return None
+        };
+
+        // the stream was opened before it was announced
+        let mut inner = self.inner.borrow_mut();
+        if id % 2 == inner.next_id % 2 || !inner.streams.contains_key(&id) {
+            inner.violation();
+            return None;
+        }
+
+        Some((name, Stream { id: id, inner: self.inner.clone() }))
+    }
+
+    /// Write out any packets queued on the connection.
+    pub fn flush(&mut self) {
+        self.inner.borrow_mut().flush();
+    }
+
+    /// Read whatever has arrived on the connection, so that streams get
+    /// the credit they wait for, and send what it frees. Returns the
+    /// number of packets still waiting for credit; over a non-blocking
+    /// connection, keep pumping until it is zero before dropping the
+    /// `Mux`.
+    pub fn pump(&mut self) -> usize {
+        self.inner.borrow_mut().settle()
+    }
+}
+
+impl<S: Read + Write> Drop for Mux<S> {
+    fn drop(&mut self) {
+        if let Some(control) = self.outgoing.take() {
+            control.choose::<End>().close();
+        }
+    }
+}
+
+#[test]
+fn check_flow_control() {
+    use super::PipeStream;
+
+    let (a, b) = PipeStream::pair();
+    let mut client = Mux::client(a);
+    let mut server = Mux::server(b);
+
+    let mut tx = client.open("numbers");
+    for i in 0..(WINDOW as usize + 10) {
+        unsafe { Transfers::send(&mut tx, i) };
+    }
+    unsafe { tx.flush() };
+
+    // the rest waits until the server has read some
+    assert_eq!(tx.queued(), 10);
+
+    let (name, mut rx) = server.accept().unwrap();
+    assert_eq!(name, "numbers");
+    assert_eq!(rx.id(), tx.id());
+
+    for i in 0..(WINDOW as usize / 2) {
+        assert_eq!(unsafe { Transfers::<usize>::recv(&mut rx) }, Some(i));
+    }
+
+    // reading the client's side delivers the credit
+    unsafe { rx.flush() };
+    assert_eq!(unsafe { tx.recv_discriminant() }, None);
+    assert_eq!(tx.queued(), 0);
+
+    for i in (WINDOW as usize / 2)..(WINDOW as usize + 10) {
+        assert_eq!(unsafe { Transfers::<usize>::recv(&mut rx) }, Some(i));
+    }
+    assert_eq!(unsafe { Transfers::<usize>::recv(&mut rx) }, None);
+}
+
+#[test]
+fn check_close_past_window() {
+    use std::io;
+    use std::thread;
+    use std::time::Duration;
+    use super::PipeStream;
+
+    // blocks instead of reporting `WouldBlock`
+    struct Patient(PipeStream);
+
+    impl Read for Patient {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            loop {
+                match self.0.read(buf) {
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(1)),
+                    result => return result
+                }
+            }
+        }
+    }
+
+    impl Write for Patient {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    let (a, b) = PipeStream::pair();
+    let count = WINDOW as usize + 10;
+
+    // the client sends past its window, closes and goes away; dropping
+    // the `Mux` waits for the credit the rest needs
+    let client = thread::spawn(move || {
+        let mut client = Mux::client(Patient(a));
+        let mut tx = client.open("numbers");
+        for i in 0..count {
+            unsafe { Transfers::send(&mut tx, i) };
+        }
+        unsafe { tx.close() };
+    });
+
+    let mut server = Mux::server(b);
+    let (_, mut rx) = loop {
+        match server.accept() {
+            Some(stream) => break stream,
+            None => thread::sleep(Duration::from_millis(1))
+        }
+    };
+
+    fn next(rx: &mut Stream<PipeStream>) -> Option<usize> {
+        for _ in 0..5000 {
+            match unsafe { Transfers::recv(rx) } {
+                Some(num) => return Some(num),
+                None => thread::sleep(Duration::from_millis(1))
+            }
+        }
+        None
+    }
+
+    // enough to hand back credit for the rest
+    for i in 0..(WINDOW as usize / 2) {
+        assert_eq!(next(&mut rx), Some(i));
+    }
+    unsafe { rx.flush() };
+    client.join().unwrap();
+
+    // the client is gone by now, so the credit for these can't be sent,
+    // but they still arrived
+    for i in (WINDOW as usize / 2)..count {
+        assert_eq!(next(&mut rx), Some(i));
+    }
+    assert_eq!(unsafe { Transfers::<usize>::recv(&mut rx) }, None);
+    assert!(rx.inner.borrow().streams[&rx.id()].closed_remote);
+}
+
+#[test]
+fn check_stream_ids() {
+    use super::PipeStream;
+
+    let (a, b) = PipeStream::pair();
+    let mut client = Mux::client(a);
+    let mut peer = Framed::new(b);
+    let mut send = |packet: Packet| peer.write_frame(&Frame::Payload(wire::to_bytes(&packet))).unwrap();
+
+    let mut ours = client.open("numbers");
+    let mut theirs = Stream { id: 3, inner: client.inner.clone() };
+    unsafe { ours.close() };
+    send(Packet::Open(3));
+    for i in 0..(WINDOW + 1) {
+        send(Packet::Data(3, Item::Discriminant(i as usize)));
+    }
+    send(Packet::Data(ours.id(), Item::Close));
+
+    // reading the rest breaks the stream which went over its window,
+    // and forgets the one closed by both sides
+    assert_eq!(unsafe { ours.recv_discriminant() }, None);
+    assert!(!client.inner.borrow().streams.contains_key(&ours.id()));
+    assert_eq!(unsafe { theirs.recv_discriminant() }, None);
+    assert!(!client.inner.borrow().broken);
+
+    // a packet for a forgotten stream breaks the connection
+    send(Packet::Open(5));
+    send(Packet::Data(ours.id(), Item::Discriminant(0)));
+    assert!(client.accept().is_none());
+    assert!(client.inner.borrow().broken);
+}
@@ -0,0 +1,187 @@
+//! An in-memory duplex byte stream. Unlike `Blocking`, which moves
+//! values between threads as they are, `BytePipe` encodes every payload
+//! and discriminant through `Framed`, so tests exercise the same bytes a
+//! socket would carry.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use wire::{Encode, Decode};
+use super::Framed;
+use super::super::{Channel, Protocol, Transfers, TransfersRef, IO};
+use super::super::session_types::{SessionType, WellFormed};
+
+struct Buffer {
+    data: VecDeque<u8>,
+    reader_gone: bool,
+    writer_gone: bool
+}
+
+impl Buffer {
+    fn new() -> Arc<Mutex<Buffer>> {
+        Arc::new(Mutex::new(Buffer {
+            data: VecDeque::new(),
+            reader_gone: false,
+            writer_gone: false
+        }))
+    }
+}
+
+/// One end of an in-memory duplex byte stream. Reads never block: an
+/// empty stream reports `WouldBlock`, and one whose other end was
+/// dropped reports end of file once drained.
+pub struct PipeStream {
+    incoming: Arc<Mutex<Buffer>>,
+    outgoing: Arc<Mutex<Buffer>>
+}
+
+impl PipeStream {
+    /// Create both ends of a stream.
+    pub fn pair() -> (PipeStream, PipeStream) {
+        let a = Buffer::new();
+        let b = Buffer::new();
+
+        (
+            PipeStream {
+                incoming: a.clone(),
+                outgoing: b.clone()
+            },
+            PipeStream {
+                incoming: b,
+                outgoing: a
+            }
+        )
+    }
+
+    /// The number of bytes written by the other end but not yet read.
+    pub fn available(&self) -> usize {
+        self.incoming.lock().unwrap().data.len()
+    }
+}
+
+impl Read for PipeStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = self.incoming.lock().unwrap();
+
+        if incoming.data.is_empty() {
+            return if incoming.writer_gone {
+                Ok(0)
+            } else {
+                Err(io::Error::new(io::ErrorKind::WouldBlock, "pipe is empty"))
+            };
+        }
+
+        let mut n = 0;
+        while n < buf.len() {
+            match incoming.data.pop_front() {
+                Some(byte) => buf[n] = byte,
+                None => break
+            }
+            n += 1;
+        }
+
+        Ok(n)
+    }
+}
+
+impl Write for PipeStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut outgoing = self.outgoing.lock().unwrap();
+
+        if outgoing.reader_gone {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "other end of the pipe was dropped"));
+        }
+
+        outgoing.data.extend(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for PipeStream {
+    fn drop(&mut self) {
+        self.incoming.lock().unwrap().reader_gone = true;
+        self.outgoing.lock().unwrap().writer_gone = true;
+    }
+}
+
+/// A hermetic `IO` backend which behaves like a non-blocking socket.
+/// Receiving before the peer has sent anything fails, so handlers
+/// should `defer` and be polled again.
+pub struct BytePipe {
+    framed: Framed<PipeStream>
+}
+
+impl BytePipe {
+    /// Create a new bi-directional channel for protocols.
+    pub fn new<P: Protocol>(a: P, b: P) -> (Channel<P, BytePipe, (), P::Initial>, Channel<P, BytePipe, (), <P::Initial as SessionType>::Dual>)
+        where P::Initial: WellFormed, <P::Initial as SessionType>::Dual: WellFormed
+    {
+        let (io1, io2) = BytePipe::pair();
+
+        (
+            super::super::channel(io1, a),
+            super::super::channel_dual(io2, b)
+        )
+    }
+
+    /// Create the two ends of a pipe without starting a session on them.
+    pub fn pair() -> (BytePipe, BytePipe) {
+        let (a, b) = PipeStream::pair();
+
+        (
+            BytePipe { framed: Framed::new(a) },
+            BytePipe { framed: Framed::new(b) }
+        )
+    }
+
+    /// The framed stream underneath, for inspecting or injecting bytes.
+    pub fn get_mut(&mut self) -> &mut Framed<PipeStream> {
+        &mut self.framed
+    }
+}
+
+unsafe impl IO for BytePipe {
+    unsafe fn close(&mut self) {
+        self.framed.close()
+    }
+
+    unsafe fn send_discriminant(&mut self, num: usize) {
+        self.framed.send_discriminant(num)
+    }
+
+    unsafe fn recv_discriminant(&mut self) -> Option<usize> {
+        self.framed.recv_discriminant()
+    }
+
+    unsafe fn flush(&mut self) {
+        IO::flush(&mut self.framed)
+    }
+}
+
+unsafe impl<T: Encode + Decode> Transfers<T> for BytePipe {
+    unsafe fn send(&mut self, obj: T) {
+        Transfers::send(&mut self.framed, obj)
+    }
+
+    unsafe fn recv(&mut self) -> Option<T> {
+        Transfers::recv(&mut self.framed)
+    }
+
+    fn size_of(&self, obj: &T) -> Option<usize> {
+        self.framed.size_of(obj)
+    }
+}
+
+unsafe impl<T: Encode + ?Sized> TransfersRef<T> for BytePipe {
+    unsafe fn send_ref(&mut self, obj: &T) {
+        self.framed.send_ref(obj)
+    }
+
+    fn size_of_ref(&self, obj: &T) -> Option<usize> {
+        self.framed.size_of_ref(obj)
+    }
+}
@@ -0,0 +1,340 @@
+//! Recording and replaying the operations a `Channel` performs on its
+//! `IO`. A `Record` writes a trace of everything that crossed an inner
+//! backend, and a `Replay` plays that trace back to the same handlers
+//! so that a session can be reproduced deterministically.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::collections::VecDeque;
+use wire::{self, Encode, Decode};
+use super::super::{Transfers, TransfersRef, IO};
+
+/// A single operation performed on an `IO`. Payloads are stored in
+/// their wire encoding.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    Send(Vec<u8>),
+    Recv(Option<Vec<u8>>),
+    SendDiscriminant(usize),
+    RecvDiscriminant(Option<usize>),
+    Close
+}
+
+impl Encode for Op {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            Op::Send(ref bytes) => { out.push(0); bytes[..].encode(out); },
+            Op::Recv(ref bytes) => { out.push(1); bytes.encode(out); },
+            Op::SendDiscriminant(num) => { out.push(2); num.encode(out); },
+            Op::RecvDiscriminant(num) => { out.push(3); num.encode(out); },
+            Op::Close => out.push(4)
+        }
+    }
+}
+
+impl Decode for Op {
+    fn decode(input: &mut &[u8]) -> Option<Op> {
+        match u8::decode(input) {
+            Some(0) => Vec::decode(input).map(Op::Send),
+            Some(1) => Option::decode(input).map(Op::Recv),
+            Some(2) => usize::decode(input).map(Op::SendDiscriminant),
+            Some(3) => Option::decode(input).map(Op::RecvDiscriminant),
+            Some(4) => Some(Op::Close),
+            _ => None
+        }
+    }
+}
+
+/// The sequence of operations captured by a `Record`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Trace {
+    pub ops: Vec<Op>
+}
+
+impl Trace {
+    /// Read a trace which was written by `Record`.
+    pub fn read_from<R: Read>(mut reader: R) -> io::Result<Trace> {
+        let mut buf = vec![];
+        try!(reader.read_to_end(&mut buf));
+
+        let mut input = &buf[..];
+        let mut ops = vec![];
+        while !input.is_empty() {
+            match Op::decode(&mut input) {
+                Some(op) => ops.push(op),
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed trace"))
+            }
+        }
+
+        Ok(Trace { ops: ops })
+    }
+
+    /// Open and read a trace file.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Trace> {
+        Trace::read_from(try!(File::open(path)))
+    }
+
+    /// Write the trace in the same format `Record` uses.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut buf = vec![];
+        for op in &self.ops {
+            op.encode(&mut buf);
+        }
+
+        writer.write_all(&buf)
+    }
+}
+
+/// Wraps an `IO`, forwarding every operation to it and appending the
+/// operation to a trace. Each operation is written out as soon as it
+/// happens, so the trace survives a crash of the process.
+pub struct Record<I, W: Write = File> {
+    inner: I,
+    out: W
+}
+
+impl<I: IO> Record<I> {
+    /// Record `inner` to a newly created trace file at `path`.
+    pub fn create<P: AsRef<Path>>(inner: I, path: P) -> io::Result<Record<I>> {
+        Ok(Record::new(inner, try!(File::create(path))))
+    }
+}
+
+impl<I: IO, W: Write> Record<I, W> {
+    /// Record `inner` to an arbitrary writer.
+    pub fn new(inner: I, out: W) -> Record<I, W> {
+        Record {
+            inner: inner,
+            out: out
+        }
+    }
+
+    fn log(&mut self, op: Op) {
+        let buf = wire::to_bytes(&op);
+        self.out.write_all(&buf).and_then(|_| self.out.flush()).unwrap();
+    }
+}
+
+unsafe impl<I: IO, W: Write> IO for Record<I, W> {
+    unsafe fn close(&mut self) {
+        self.inner.close();
+        self.log(Op::Close);
+    }
+
+    unsafe fn send_discriminant(&mut self, num: usize) {
+        self.inner.send_discriminant(num);
+        self.log(Op::SendDiscriminant(num));
+    }
+
+    unsafe fn recv_discriminant(&mut self) -> Option<usize> {
+        let num = self.inner.recv_discriminant();
+        self.log(Op::RecvDiscriminant(num));
+
+        num
+    }
+
+    unsafe fn flush(&mut self) {
+        self.inner.flush();
+    }
+}
+
+unsafe impl<T: Encode, I: Transfers<T>, W: Write> Transfers<T> for Record<I, W> {
+    unsafe fn send(&mut self, obj: T) {
+        self.log(Op::Send(wire::to_bytes(&obj)));
+        self.inner.send(obj);
+    }
+
+    unsafe fn recv(&mut self) -> Option<T> {
+        let obj = self.inner.recv();
+        self.log(Op::Recv(obj.as_ref().map(wire::to_bytes)));
+
+        obj
+    }
+
+    fn size_of(&self, obj: &T) -> Option<usize> {
+        self.inner.size_of(obj)
+    }
+}
+
+unsafe impl<T: Encode + ?Sized, I: TransfersRef<T>, W: Write> TransfersRef<T> for Record<I, W> {
+    unsafe fn send_ref(&mut self, obj: &T) {
+        self.log(Op::Send(wire::to_bytes(obj)));
+        self.inner.send_ref(obj);
+    }
+
+    fn size_of_ref(&self, obj: &T) -> Option<usize> {
+        self.inner.size_of_ref(obj)
+    }
+}
+
+/// An `IO` backend which plays a `Trace` back to a set of handlers. The
+/// handlers must perform exactly the operations that were recorded:
+/// received payloads and discriminants are served from the trace, and
+/// anything the handlers send is compared against it. Any divergence,
+/// including running past the end of the trace, panics with the
+/// position and both operations.
+///
+/// A lenient replay instead treats the trace as untrusted input from a
+/// peer: outbound operations are ignored, and inbound operations which
+/// don't match what the handlers expect, don't decode, or run past the
+/// end of the trace are reported as nothing received. Like a byte
+/// backend, it reads a discriminant from a payload as a variable length
+/// integer.
+pub struct Replay {
+    ops: VecDeque<Op>,
+    position: usize,
+    lenient: bool
+}
+
+impl Replay {
+    pub fn new(trace: Trace) -> Replay {
+        Replay {
+            ops: trace.ops.into_iter().collect(),
+            position: 0,
+            lenient: false
+        }
+    }
+
+    /// Play only the inbound operations of `trace`, never panicking.
+    pub fn lenient(trace: Trace) -> Replay {
+        let mut replay = Replay::new(trace);
+        replay.lenient = true;
+        replay
+    }
+
+    /// Replay the trace file at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Replay> {
+        Trace::open(path).map(Replay::new)
+    }
+
+    /// The number of recorded operations which have not been replayed.
+    pub fn remaining(&self) -> usize {
+        self.ops.len()
+    }
+
+    fn next(&mut self, performed: &Op) -> Op {
+        match self.ops.pop_front() {
+            Some(op) => {
+                self.position += 1;
+                op
+            },
+            None => panic!("replay diverged at operation {}: handler performed {:?} \
+                            but the trace has ended", self.position, performed)
+        }
+    }
+
+    fn next_inbound(&mut self) -> Option<Op> {
+        while let Some(op) = self.ops.pop_front() {
+            self.position += 1;
+            match op {
+                Op::Recv(_) | Op::RecvDiscriminant(_) => return Some(op),
+                _ => {}
+            }
+        }
+
+        None
+    }
+
+    fn diverged(&self, performed: &Op, recorded: &Op) -> ! {
+        panic!("replay diverged at operation {}: handler performed {:?} \
+                but the trace has {:?}", self.position - 1, performed, recorded)
+    }
+}
+
+unsafe impl IO for Replay {
+    unsafe fn close(&mut self) {
+        if self.lenient {
+            return;
+        }
+
+        let performed = Op::Close;
+        let recorded = self.next(&performed);
+        if recorded != performed {
+            self.diverged(&performed, &recorded);
+        }
+    }
+
+    unsafe fn send_discriminant(&mut self, num: usize) {
+        if self.lenient {
+            return;
+        }
+
+        let performed = Op::SendDiscriminant(num);
+        let recorded = self.next(&performed);
+        if recorded != performed {
+            self.diverged(&performed, &recorded);
+        }
+    }
+
+    unsafe fn recv_discriminant(&mut self) -> Option<usize> {
+        if self.lenient {
+            return match self.next_inbound() {
+                Some(Op::RecvDiscriminant(num)) => num,
+                Some(Op::Recv(Some(bytes))) => wire::from_bytes(&bytes),
+                _ => None
+            };
+        }
+
+        let performed = Op::RecvDiscriminant(None);
+        match self.next(&performed) {
+            Op::RecvDiscriminant(num) => num,
+            recorded => self.diverged(&performed, &recorded)
+        }
+    }
+}
+
+unsafe impl<T: Encode + Decode> Transfers<T> for Replay {
+    unsafe fn send(&mut self, obj: T) {
+        if self.lenient {
+            return;
+        }
+
+        let performed = Op::Send(wire::to_bytes(&obj));
+        let recorded = self.next(&performed);
+        if recorded != performed {
+            self.diverged(&performed, &recorded);
+        }
+    }
+
+    unsafe fn recv(&mut self) -> Option<T> {
+        if self.lenient {
+            return match self.next_inbound() {
+                Some(Op::Recv(Some(bytes))) => wire::from_bytes(&bytes),
+                _ => None
+            };
+        }
+
+        let performed = Op::Recv(None);
+        match self.next(&performed) {
+            Op::Recv(None) => None,
+            Op::Recv(Some(bytes)) => match wire::from_bytes(&bytes) {
+                Some(obj) => Some(obj),
+                None => self.diverged(&performed, &Op::Recv(Some(bytes)))
+            },
+            recorded => self.diverged(&performed, &recorded)
+        }
+    }
+
+    fn size_of(&self, obj: &T) -> Option<usize> {
+        Some(wire::to_bytes(obj).len())
+    }
+}
+
+unsafe impl<T: Encode + ?Sized> TransfersRef<T> for Replay {
+    unsafe fn send_ref(&mut self, obj: &T) {
+        if self.lenient {
+            return;
+        }
+
+        let performed = Op::Send(wire::to_bytes(obj));
+        let recorded = self.next(&performed);
+        if recorded != performed {
+            self.diverged(&performed, &recorded);
+        }
+    }
+
+    fn size_of_ref(&self, obj: &T) -> Option<usize> {
+        Some(wire::to_bytes(obj).len())
+    }
+}
@@ -0,0 +1,326 @@
+//! A deterministic, simulated network for testing how handlers cope with
+//! slow and failing peers. Time is virtual: nothing is delivered until
+//! the simulation's clock reaches the message's delivery time, and all
+//! delays and failures are drawn from a seeded generator so that every
+//! run with the same seed behaves identically.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::Duration;
+use rng::Rng;
+use super::super::{Channel, Defer, Protocol, Timer, Transfers, TransfersRef, Wait, IO};
+use super::super::session_types::{SessionType, WellFormed};
+
+enum Message {
+    Discriminant(usize),
+    Payload(Box<Any>)
+}
+
+struct Direction {
+    queue: VecDeque<(u64, Message)>,
+    last_delivery: u64,
+    // the sending side closed or was half-closed; nothing more is sent
+    shut: bool,
+    // when the receiving side stops waiting for a `Timed` answer
+    deadline: Option<u64>,
+    // late answers which the receiving side gave up on
+    skip: usize
+}
+
+impl Direction {
+    fn new() -> Direction {
+        Direction {
+            queue: VecDeque::new(),
+            last_delivery: 0,
+            shut: false,
+            deadline: None,
+            skip: 0
+        }
+    }
+}
+
+struct Network {
+    now: u64,
+    rng: Rng,
+    latency: (u64, u64),
+    drop_rate: f64,
+    half_close_rate: f64,
+    // two directions per connection; endpoint `side` sends on
+    // `links[link][side]` and receives on the other
+    links: Vec<[Direction; 2]>,
+    // bumped whenever a message is sent or delivered
+    progress: u64
+}
+
+impl Network {
+    fn send(&mut self, link: usize, side: usize, msg: Message) {
+        if self.links[link][side].shut {
+            return;
+        }
+
+        self.progress += 1;
+
+        if self.rng.chance(self.drop_rate) {
+            // the connection is reset; anything in flight is lost
+            for dir in self.links[link].iter_mut() {
+                dir.queue.clear();
+                dir.shut = true;
+            }
+            return;
+        }
+
+        if self.rng.chance(self.half_close_rate) {
+            self.links[link][side].shut = true;
+            return;
+        }
+
+        let delay = self.rng.range(self.latency.0, self.latency.1);
+        let dir = &mut self.links[link][side];
+
+        // messages are delayed but never reordered
+        let at = cmp::max(self.now + delay, dir.last_delivery);
+        dir.last_delivery = at;
+        dir.queue.push_back((at, msg));
+    }
+
+    fn recv(&mut self, link: usize, side: usize) -> Option<Message> {
+        let now = self.now;
+
+        loop {
+            let dir = &mut self.links[link][1 - side];
+
+            let ready = match dir.queue.front() {
+                Some(&(at, _)) => at <= now,
+                None => false
+            };
+
+            if !ready {
+                return None;
+            }
+
+            self.progress += 1;
+            match dir.queue.pop_front() {
+                Some((_, Message::Discriminant(_))) if dir.skip > 0 => dir.skip -= 1,
+                Some((_, msg)) => return Some(msg),
+                None => return None
+            }
+        }
+    }
+
+    // The next time a message is delivered or a deadline passes.
+    fn next_event(&self) -> Option<u64> {
+        self.links.iter()
+                  .flat_map(|dirs| dirs.iter())
+                  .filter_map(|dir| {
+                      let delivery = dir.queue.front().map(|&(at, _)| at);
+                      match (delivery, dir.deadline) {
+                          (Some(a), Some(b)) => Some(cmp::min(a, b)),
+                          (a, b) => a.or(b)
+                      }
+                  })
+                  .min()
+    }
+}
+
+/// A simulated network. Connections created with `connect` or `pair`
+/// deliver messages after a random delay drawn from `latency`, and may
+/// be reset or half-closed at random. Receiving never blocks: until a
+/// message has arrived, `recv` and `accept` fail and the handler is
+/// expected to `defer`. Deadlines of `Timed` sessions are measured on
+/// the virtual clock, one tick to the millisecond.
+pub struct Sim {
+    net: Rc<RefCell<Network>>
+}
+
+impl Sim {
+    /// Create a network whose behavior is entirely determined by `seed`.
+    /// By default messages arrive instantly and are never lost.
+    pub fn new(seed: u64) -> Sim {
+        Sim {
+            net: Rc::new(RefCell::new(Network {
+                now: 0,
+                rng: Rng::new(seed),
+                latency: (0, 0),
+                drop_rate: 0.0,
+                half_close_rate: 0.0,
+                links: vec![],
+                progress: 0
+            }))
+        }
+    }
+
+    /// Delay each message by between `min` and `max` ticks.
+    pub fn latency(self, min: u64, max: u64) -> Sim {
+        self.net.borrow_mut().latency = (min, cmp::max(min, max));
+        self
+    }
+
+    /// The probability that sending a message resets its connection,
+    /// losing the message and everything in flight in both directions.
+    pub fn drops(self, p: f64) -> Sim {
+        self.net.borrow_mut().drop_rate = p;
+        self
+    }
+
+    /// The probability that sending a message half-closes the sender's
+    /// direction of the connection: the message and all later ones are
+    /// lost, but messages already in flight still arrive.
+    pub fn half_closes(self, p: f64) -> Sim {
+        self.net.borrow_mut().half_close_rate = p;
+        self
+    }
+
+    /// Create the two ends of a new connection.
+    pub fn pair(&self) -> (SimEndpoint, SimEndpoint) {
+        let mut net = self.net.borrow_mut();
+        let link = net.links.len();
+        net.links.push([Direction::new(), Direction::new()]);
+
+        (
+            SimEndpoint { net: self.net.clone(), link: link, side: 0 },
+            SimEndpoint { net: self.net.clone(), link: link, side: 1 }
+        )
+    }
+
+    /// Create a new connection and start a session of `P` on it.
+    pub fn connect<P: Protocol>(&self, a: P, b: P) -> (Channel<P, SimEndpoint, (), P::Initial>, Channel<P, SimEndpoint, (), <P::Initial as SessionType>::Dual>)
+        where P::Initial: WellFormed, <P::Initial as SessionType>::Dual: WellFormed
+    {
+        let (io1, io2) = self.pair();
+
+        (
+            super::super::channel(io1, a),
+            super::super::channel_dual(io2, b)
+        )
+    }
+
+    /// The current virtual time.
+    pub fn now(&self) -> u64 {
+        self.net.borrow().now
+    }
+
+    /// Advance the clock to the next pending delivery or deadline.
+    /// Returns false if nothing is in flight and nobody is waiting.
+    pub fn step(&self) -> bool {
+        let mut net = self.net.borrow_mut();
+        match net.next_event() {
+            Some(at) => {
+                net.now = cmp::max(net.now, at);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Drive deferred sessions until they all close, until they stall
+    /// with nothing left in flight, or until the clock passes `until`.
+    /// Each round resumes every open session once; if that moved any
+    /// data the clock ticks forward by one, otherwise it skips ahead to
+    /// the next delivery. Returns the number of sessions left open.
+    pub fn run<P: Protocol>(&self, sessions: &mut [Defer<P, SimEndpoint>], until: u64) -> usize {
+        let mut open = vec![true; sessions.len()];
+
+        loop {
+            let before = self.net.borrow().progress;
+
+            for (session, open) in sessions.iter_mut().zip(open.iter_mut()) {
+                if *open {
+                    *open = session.with();
+                }
+            }
+
+            let remaining = open.iter().filter(|&&open| open).count();
+            if remaining == 0 || self.now() >= until {
+                return remaining;
+            }
+
+            if self.net.borrow().progress != before {
+                self.net.borrow_mut().now += 1;
+            } else if !self.step() {
+                return remaining;
+            }
+        }
+    }
+}
+
+/// One end of a connection on a `Sim` network.
+pub struct SimEndpoint {
+    net: Rc<RefCell<Network>>,
+    link: usize,
+    side: usize
+}
+
+unsafe impl IO for SimEndpoint {
+    unsafe fn close(&mut self) {
+        self.net.borrow_mut().links[self.link][self.side].shut = true;
+    }
+
+    unsafe fn send_discriminant(&mut self, num: usize) {
+        self.net.borrow_mut().send(self.link, self.side, Message::Discriminant(num));
+    }
+
+    unsafe fn recv_discriminant(&mut self) -> Option<usize> {
+        match self.net.borrow_mut().recv(self.link, self.side) {
+            Some(Message::Discriminant(num)) => Some(num),
+            Some(Message::Payload(_)) => panic!("protocol violation: expected a discriminant"),
+            None => None
+        }
+    }
+}
+
+// One tick of the virtual clock stands for a millisecond.
+unsafe impl Timer for SimEndpoint {
+    unsafe fn recv_discriminant_within(&mut self, limit: Duration) -> Wait<usize> {
+        let mut net = self.net.borrow_mut();
+        let now = net.now;
+        let ticks = limit.as_secs() * 1000 + (limit.subsec_nanos() / 1_000_000) as u64;
+
+        let deadline = match net.links[self.link][1 - self.side].deadline {
+            Some(deadline) => deadline,
+            None => {
+                net.links[self.link][1 - self.side].deadline = Some(now + ticks);
+                now + ticks
+            }
+        };
+
+        let wait = match net.recv(self.link, self.side) {
+            Some(Message::Discriminant(num)) => Wait::Arrived(num),
+            Some(Message::Payload(_)) => panic!("protocol violation: expected a discriminant"),
+            None if now >= deadline => Wait::Expired,
+            None => return Wait::Pending
+        };
+
+        net.links[self.link][1 - self.side].deadline = None;
+        wait
+    }
+
+    unsafe fn skip_discriminant(&mut self) {
+        self.net.borrow_mut().links[self.link][1 - self.side].skip += 1;
+    }
+}
+
+unsafe impl<T: 'static> Transfers<T> for SimEndpoint {
+    unsafe fn send(&mut self, obj: T) {
+        self.net.borrow_mut().send(self.link, self.side, Message::Payload(Box::new(obj)));
+    }
+
+    unsafe fn recv(&mut self) -> Option<T> {
+        match self.net.borrow_mut().recv(self.link, self.side) {
+            Some(Message::Payload(obj)) => match obj.downcast() {
+                Ok(obj) => Some(*obj),
+                Err(_) => panic!("protocol violation: unexpected payload type")
+            },
+            Some(Message::Discriminant(_)) => panic!("protocol violation: expected a payload"),
+            None => None
+        }
+    }
+}
+
+unsafe impl<T: ToOwned + ?Sized> TransfersRef<T> for SimEndpoint where T::Owned: 'static {
+    unsafe fn send_ref(&mut self, obj: &T) {
+        Transfers::send(self, obj.to_owned())
+    }
+}
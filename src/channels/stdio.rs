@@ -0,0 +1,113 @@
+//! Running sessions over the standard streams of a process. A parent
+//! talks to a child through the child's stdin and stdout, and the child
+//! talks back through its own; both sides use the `Framed` format, so
+//! the two can run dual sessions of the same `Protocol`.
+
+use std::io::{self, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout};
+use wire::{Encode, Decode};
+use super::Framed;
+use super::super::{Transfers, TransfersRef, IO};
+
+/// A reading half and a writing half used together as one stream.
+pub struct Duplex {
+    input: Box<Read + Send>,
+    output: Box<Write + Send>
+}
+
+impl Read for Duplex {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Duplex {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// An `IO` backend over a pair of pipes. Receiving blocks until the
+/// other process writes, and fails once it has closed its end.
+pub struct Stdio {
+    framed: Framed<Duplex>
+}
+
+impl Stdio {
+    /// Talk to a child process which was spawned with piped stdin and
+    /// stdout. The pipes are taken from `child`; returns `None` if
+    /// either of them isn't there.
+    pub fn child(child: &mut Child) -> Option<Stdio> {
+        match (child.stdin.take(), child.stdout.take()) {
+            (Some(stdin), Some(stdout)) => Some(Stdio::pipes(stdin, stdout)),
+            _ => None
+        }
+    }
+
+    /// Talk to a child process through its stdin and stdout.
+    pub fn pipes(stdin: ChildStdin, stdout: ChildStdout) -> Stdio {
+        Stdio::new(stdout, stdin)
+    }
+
+    /// Talk to the parent process through our own stdin and stdout.
+    /// Nothing else may use stdout while the session runs.
+    pub fn current() -> Stdio {
+        Stdio::new(io::stdin(), io::stdout())
+    }
+
+    /// Run over any reader and writer pair.
+    pub fn new<R: Read + Send + 'static, W: Write + Send + 'static>(input: R, output: W) -> Stdio {
+        Stdio {
+            framed: Framed::new(Duplex {
+                input: Box::new(input),
+                output: Box::new(output)
+            })
+        }
+    }
+}
+
+unsafe impl IO for Stdio {
+    unsafe fn close(&mut self) {
+        self.framed.close()
+    }
+
+    unsafe fn send_discriminant(&mut self, num: usize) {
+        self.framed.send_discriminant(num)
+    }
+
+    unsafe fn recv_discriminant(&mut self) -> Option<usize> {
+        self.framed.recv_discriminant()
+    }
+
+    unsafe fn flush(&mut self) {
+        IO::flush(&mut self.framed)
+    }
+}
+
+unsafe impl<T: Encode + Decode> Transfers<T> for Stdio {
+    unsafe fn send(&mut self, obj: T) {
+        Transfers::send(&mut self.framed, obj)
+    }
+
+    unsafe fn recv(&mut self) -> Option<T> {
+        Transfers::recv(&mut self.framed)
+    }
+
+    fn size_of(&self, obj: &T) -> Option<usize> {
+        self.framed.size_of(obj)
+    }
+}
+
+unsafe impl<T: Encode + ?Sized> TransfersRef<T> for Stdio {
+    unsafe fn send_ref(&mut self, obj: &T) {
+        self.framed.send_ref(obj)
+    }
+
+    fn size_of_ref(&self, obj: &T) -> Option<usize> {
+        self.framed.size_of_ref(obj)
+    }
+}
@@ -0,0 +1,199 @@
+//! An `IO` wrapper which reports every operation on the inner backend
+//! to a `Sink`, for auditing sessions without touching their handlers.
+
+use type_name;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use super::super::{Transfers, TransfersRef, IO};
+
+/// Whether an operation moved data towards the peer or away from it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Outbound,
+    Inbound
+}
+
+/// The kind of operation performed on the backend.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Payload,
+    Discriminant,
+    Close
+}
+
+/// A single operation on a traced backend. Inbound operations which
+/// found nothing to receive are reported with `received` set to false.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub session: u64,
+    pub direction: Direction,
+    pub operation: Operation,
+    pub received: bool,
+    /// The discriminant sent or received, for `Operation::Discriminant`.
+    pub discriminant: Option<usize>,
+    /// The payload's type, for `Operation::Payload`.
+    pub type_name: Option<&'static str>,
+    /// The payload's size on the channel, if the inner backend knows it.
+    pub size: Option<usize>
+}
+
+impl Event {
+    fn new(session: u64, direction: Direction, operation: Operation) -> Event {
+        Event {
+            session: session,
+            direction: direction,
+            operation: operation,
+            received: direction == Direction::Inbound,
+            discriminant: None,
+            type_name: None,
+            size: None
+        }
+    }
+}
+
+/// Receives the events produced by `Traced`.
+pub trait Sink {
+    fn event(&mut self, event: &Event);
+}
+
+impl<F: FnMut(&Event)> Sink for F {
+    fn event(&mut self, event: &Event) {
+        self(event)
+    }
+}
+
+/// Lets many sessions, possibly on other threads, report to one sink.
+impl<K: Sink> Sink for Arc<Mutex<K>> {
+    fn event(&mut self, event: &Event) {
+        self.lock().unwrap().event(event)
+    }
+}
+
+/// A sink which writes one line of `key=value` pairs per event.
+pub struct Log<W: Write> {
+    out: W
+}
+
+impl<W: Write> Log<W> {
+    pub fn new(out: W) -> Log<W> {
+        Log {
+            out: out
+        }
+    }
+}
+
+impl<W: Write> Sink for Log<W> {
+    fn event(&mut self, event: &Event) {
+        let mut line = format!("session={} direction={:?} operation={:?}",
+                               event.session, event.direction, event.operation);
+        if event.direction == Direction::Inbound {
+            line.push_str(&format!(" received={}", event.received));
+        }
+        if let Some(num) = event.discriminant {
+            line.push_str(&format!(" discriminant={}", num));
+        }
+        if let Some(name) = event.type_name {
+            line.push_str(&format!(" type={}", name));
+        }
+        if let Some(size) = event.size {
+            line.push_str(&format!(" size={}", size));
+        }
+
+        // an audit log that can't be written shouldn't take the session down
+        let _ = writeln!(self.out, "{}", line);
+    }
+}
+
+/// Wraps an `IO`, forwarding every operation to it and reporting the
+/// operation to `K`. Implements `Transfers<T>` whenever the inner
+/// backend does.
+pub struct Traced<I, K: Sink> {
+    inner: I,
+    session: u64,
+    sink: K
+}
+
+impl<I: IO, K: Sink> Traced<I, K> {
+    /// Trace `inner`, tagging its events with `session`.
+    pub fn new(inner: I, session: u64, sink: K) -> Traced<I, K> {
+        Traced {
+            inner: inner,
+            session: session,
+            sink: sink
+        }
+    }
+}
+
+unsafe impl<I: IO, K: Sink> IO for Traced<I, K> {
+    unsafe fn close(&mut self) {
+        self.inner.close();
+
+        let event = Event::new(self.session, Direction::Outbound, Operation::Close);
+        self.sink.event(&event);
+    }
+
+    unsafe fn send_discriminant(&mut self, num: usize) {
+        self.inner.send_discriminant(num);
+
+        let mut event = Event::new(self.session, Direction::Outbound, Operation::Discriminant);
+        event.discriminant = Some(num);
+        self.sink.event(&event);
+    }
+
+    unsafe fn recv_discriminant(&mut self) -> Option<usize> {
+        let num = self.inner.recv_discriminant();
+
+        let mut event = Event::new(self.session, Direction::Inbound, Operation::Discriminant);
+        event.received = num.is_some();
+        event.discriminant = num;
+        self.sink.event(&event);
+
+        num
+    }
+
+    unsafe fn flush(&mut self) {
+        self.inner.flush();
+    }
+}
+
+unsafe impl<T, I: Transfers<T>, K: Sink> Transfers<T> for Traced<I, K> {
+    unsafe fn send(&mut self, obj: T) {
+        let mut event = Event::new(self.session, Direction::Outbound, Operation::Payload);
+        event.type_name = Some(type_name::<T>());
+        event.size = self.inner.size_of(&obj);
+
+        self.inner.send(obj);
+        self.sink.event(&event);
+    }
+
+    unsafe fn recv(&mut self) -> Option<T> {
+        let obj = self.inner.recv();
+
+        let mut event = Event::new(self.session, Direction::Inbound, Operation::Payload);
+        event.received = obj.is_some();
+        event.type_name = Some(type_name::<T>());
+        event.size = obj.as_ref().and_then(|obj| self.inner.size_of(obj));
+        self.sink.event(&event);
+
+        obj
+    }
+
+    fn size_of(&self, obj: &T) -> Option<usize> {
+        self.inner.size_of(obj)
+    }
+}
+
+unsafe impl<T: ?Sized, I: TransfersRef<T>, K: Sink> TransfersRef<T> for Traced<I, K> {
+    unsafe fn send_ref(&mut self, obj: &T) {
+        let mut event = Event::new(self.session, Direction::Outbound, Operation::Payload);
+        event.type_name = Some(type_name::<T>());
+        event.size = self.inner.size_of_ref(obj);
+
+        self.inner.send_ref(obj);
+        self.sink.event(&event);
+    }
+
+    fn size_of_ref(&self, obj: &T) -> Option<usize> {
+        self.inner.size_of_ref(obj)
+    }
+}
@@ -0,0 +1,167 @@
+//! Checkpointing paused sessions, so that they survive a restart. A
+//! `Defer` waiting to be resumed can be saved as a `Snapshot` of the
+//! state it is paused in and its encoded `Protocol` value. After the
+//! restart, a `Registry` of the states the session may have been paused
+//! in rebuilds the `Defer` over a fresh IO.
+//!
+//! States are identified by the structure of their session types and
+//! environments, so the identifiers are the same in every run of a
+//! program, and in every build of it which keeps the protocol. Payloads,
+//! loop labels and deadlines are named by their full paths, as the
+//! compiler prints them, so that types of the same name in different
+//! modules are told apart; moving one to another module changes the
+//! identifiers of the states it appears in. Snapshots also carry a
+//! fingerprint of the whole protocol, and are rejected once its
+//! definition changes.
+//!
+//! The compiler doesn't promise to print a type the same way in each of
+//! its versions, so a build from another compiler may reject snapshots
+//! of a protocol which hasn't changed.
+
+use std::collections::HashMap;
+use wire::{self, Encode, Decode};
+use protocol::{Defer, Handler, Protocol};
+use session_types::SessionType;
+use super::{IO, type_name};
+
+/// A paused session.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    /// The fingerprint of the protocol.
+    pub protocol: u64,
+    /// The state the session was paused in.
+    pub state: u64,
+    /// The encoded `Protocol` value.
+    pub proto: Vec<u8>,
+    /// Anything received ahead of the state, such as the number of items
+    /// a paused `RecvMany` has left.
+    pub ahead: Option<usize>
+}
+
+impl Encode for Snapshot {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.protocol.encode(out);
+        self.state.encode(out);
+        self.proto.encode(out);
+        self.ahead.encode(out);
+    }
+}
+
+impl Decode for Snapshot {
+    fn decode(input: &mut &[u8]) -> Option<Snapshot> {
+        <(u64, u64, Vec<u8>, Option<usize>)>::decode(input).map(|(protocol, state, proto, ahead)| {
+            Snapshot {
+                protocol: protocol,
+                state: state,
+                proto: proto,
+                ahead: ahead
+            }
+        })
+    }
+}
+
+/// Why a snapshot could not be resumed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The snapshot is of another protocol, or of another definition of
+    /// this one.
+    ProtocolChanged,
+    /// No state with the snapshot's identifier was registered.
+    UnknownState,
+    /// The `Protocol` value could not be decoded.
+    Malformed
+}
+
+// FNV-1a. Unlike the standard library's hashers it is fixed, so the
+// identifiers stay the same between builds.
+fn hash(name: &str) -> u64 {
+    name.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// The identifier of the state `S` in the environment `E`.
+pub fn state_id<E: SessionType, S: SessionType>() -> u64 {
+    let mut shape = String::new();
+    E::shape(&mut shape);
+    shape.push(';');
+    S::shape(&mut shape);
+
+    hash(&shape)
+}
+
+/// The fingerprint of `P`'s session type, which changes along with it.
+pub fn fingerprint<P: Protocol>() -> u64 {
+    let mut shape = String::new();
+    P::Initial::shape(&mut shape);
+
+    hash(&shape)
+}
+
+/// The states in which sessions of `P` over `I` can be resumed.
+pub struct Registry<P: Protocol, I> {
+    states: HashMap<u64, unsafe fn(I, P, Option<usize>) -> Defer<P, I>>
+}
+
+impl<P: Protocol + Decode, I: IO> Registry<P, I> {
+    pub fn new() -> Registry<P, I> {
+        Registry {
+            states: HashMap::new()
+        }
+    }
+
+    /// Allow sessions paused in the state `S`, in the environment `E`, to
+    /// be resumed. Panics if a state with the same identifier was
+    /// registered already.
+    pub fn register<E: SessionType, S: SessionType>(&mut self) where P: Handler<I, E, S> {
+        if self.states.insert(state_id::<E, S>(), Defer::<P, I>::restore::<E, S>).is_some() {
+            panic!("state `{}` in environment `{}` was registered twice, or shares its identifier with another",
+                   type_name::<S>(), type_name::<E>());
+        }
+    }
+
+    /// Rebuild the session saved in `snapshot` over `io`.
+    ///
+    /// This is unsafe because `io` must reach a peer that is resuming the
+    /// dual of the same state, and `snapshot` must have been taken by
+    /// `Defer::checkpoint`.
+    pub unsafe fn resume(&self, snapshot: &Snapshot, io: I) -> Result<Defer<P, I>, Error> {
+        if snapshot.protocol != fingerprint::<P>() {
+            return Err(Error::ProtocolChanged);
+        }
+
+        let restore = match self.states.get(&snapshot.state) {
+            Some(&restore) => restore,
+            None => return Err(Error::UnknownState)
+        };
+
+        match wire::from_bytes(&snapshot.proto) {
+            Some(proto) => Ok(restore(io, proto, snapshot.ahead)),
+            None => Err(Error::Malformed)
+        }
+    }
+}
+
+#[test]
+fn check_state_ids_are_structural() {
+    use session_types::*;
+
+    mod old {
+        pub struct Job;
+    }
+
+    mod new {
+        pub struct Job;
+    }
+
+    type Worker = Recv<old::Job, Send<u64, End>>;
+
+    let mut shape = String::new();
+    <(Worker, ())>::shape(&mut shape);
+    assert!(shape.starts_with("(Recv(") && shape.ends_with("::old::Job,Send(u64,End)),())"), "{}", shape);
+
+    // payloads of the same name in different modules are told apart
+    assert!(state_id::<(), Recv<old::Job, End>>() != state_id::<(), Recv<new::Job, End>>());
+    assert!(state_id::<(), Recv<old::Job, End>>() != state_id::<(), Send<old::Job, End>>());
+    assert!(state_id::<(Worker, ()), Worker>() != state_id::<(), Worker>());
+}
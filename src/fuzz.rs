@@ -0,0 +1,854 @@
+//! Stress testing for handlers. A `Driver` walks the dual of a protocol's
+//! session type and plays a random peer which is nevertheless always
+//! protocol-legal: it picks a random branch at every `Choose`, generates
+//! every value it sends through `Arbitrary`, and follows `Nest` and
+//! `Escape` the way the types dictate. Everything is derived from a seed,
+//! so any failure can be reproduced.
+//!
+//! `hostile` goes further and replays a recorded session whose inbound
+//! traffic has been corrupted, to check that malformed input only ever
+//! reaches handlers through the `recv` and `accept` error paths.
+//! `hostile_stream` does the same one level down, corrupting the raw
+//! bytes a `Framed` backend parses its frames from.
+
+use std::cmp;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::any::Any;
+use type_name;
+use rng::Rng;
+use peano::{Peano, Pop};
+use session_types::*;
+use channels::{Blocking, Framed, Replay, Trace, Op};
+use channels::framed;
+use wire;
+use super::{Channel, Defer, Handler, Protocol, Transfers, TransfersRef, IO, channel};
+
+/// A seeded source of randomness for `Arbitrary`.
+pub struct Gen {
+    rng: Rng
+}
+
+impl Gen {
+    pub fn new(seed: u64) -> Gen {
+        Gen {
+            rng: Rng::new(seed)
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.rng.next_u64()
+    }
+
+    /// A number in `[0, n)`; `n` must not be zero.
+    pub fn below(&mut self, n: usize) -> usize {
+        self.rng.range(0, n as u64 - 1) as usize
+    }
+
+    /// Returns true with probability `p`.
+    pub fn chance(&mut self, p: f64) -> bool {
+        self.rng.chance(p)
+    }
+}
+
+/// Types whose values a random peer can make up.
+pub trait Arbitrary: Sized {
+    fn arbitrary(g: &mut Gen) -> Self;
+}
+
+macro_rules! arbitrary_int {
+    ($($t:ty),*) => ($(
+        impl Arbitrary for $t {
+            fn arbitrary(g: &mut Gen) -> $t {
+                // favor the edges, where bugs tend to live
+                match g.below(8) {
+                    0 => 0,
+                    1 => <$t>::max_value(),
+                    2 => <$t>::min_value(),
+                    3 => g.below(16) as $t,
+                    _ => g.next_u64() as $t
+                }
+            }
+        }
+    )*)
+}
+
+arbitrary_int!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+impl Arbitrary for () {
+    fn arbitrary(_: &mut Gen) -> () { () }
+}
+
+impl Arbitrary for bool {
+    fn arbitrary(g: &mut Gen) -> bool {
+        g.chance(0.5)
+    }
+}
+
+impl Arbitrary for char {
+    fn arbitrary(g: &mut Gen) -> char {
+        if g.chance(0.9) {
+            (b' ' + g.below(95) as u8) as char
+        } else {
+            ::std::char::from_u32(g.below(0x110000) as u32).unwrap_or('\u{fffd}')
+        }
+    }
+}
+
+impl Arbitrary for String {
+    fn arbitrary(g: &mut Gen) -> String {
+        let len = g.below(16);
+        (0..len).map(|_| char::arbitrary(g)).collect()
+    }
+}
+
+impl<T: Arbitrary> Arbitrary for Vec<T> {
+    fn arbitrary(g: &mut Gen) -> Vec<T> {
+        let len = g.below(8);
+        (0..len).map(|_| T::arbitrary(g)).collect()
+    }
+}
+
+impl<T: Arbitrary> Arbitrary for Option<T> {
+    fn arbitrary(g: &mut Gen) -> Option<T> {
+        if g.chance(0.5) { Some(T::arbitrary(g)) } else { None }
+    }
+}
+
+impl<A: Arbitrary, B: Arbitrary> Arbitrary for (A, B) {
+    fn arbitrary(g: &mut Gen) -> (A, B) {
+        (A::arbitrary(g), B::arbitrary(g))
+    }
+}
+
+impl<A: Arbitrary, B: Arbitrary, C: Arbitrary> Arbitrary for (A, B, C) {
+    fn arbitrary(g: &mut Gen) -> (A, B, C) {
+        (A::arbitrary(g), B::arbitrary(g), C::arbitrary(g))
+    }
+}
+
+/// One thing the random peer did.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Step {
+    Sent(&'static str, String),
+    Received(&'static str, String),
+    Chose(usize),
+    Accepted(usize),
+    Entered,
+    Escaped(usize),
+    Closed
+}
+
+/// Why the random peer stopped before the session ended.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Stop {
+    /// Receiving failed; the other side hung up.
+    Hangup,
+    /// The step budget ran out, so the peer hung up.
+    Exhausted,
+    /// The other side sent a discriminant for a branch that doesn't exist.
+    Violation
+}
+
+type Frame<P, I> = fn(Channel<P, I, (), ()>, &mut Driver<P, I>) -> Result<(), Stop>;
+
+/// Plays the random peer. The driver keeps its own stack of the nested
+/// scopes it has entered so that `Escape` can jump back into them.
+pub struct Driver<P: Protocol, I> {
+    gen: Gen,
+    steps: usize,
+    trace: Arc<Mutex<Vec<Step>>>,
+    stack: Vec<Frame<P, I>>,
+    // the scope an `Escape` or `Var` jumps back into, once the calls which
+    // led to it have returned
+    resume: Option<(Frame<P, I>, Channel<P, I, (), ()>)>
+}
+
+impl<P: Protocol, I: IO> Driver<P, I> {
+    /// A driver which performs at most `steps` operations before it
+    /// hangs up.
+    pub fn new(seed: u64, steps: usize) -> Driver<P, I> {
+        Driver {
+            gen: Gen::new(seed),
+            steps: steps,
+            trace: Arc::new(Mutex::new(vec![])),
+            stack: vec![],
+            resume: None
+        }
+    }
+
+    /// Everything the peer has done so far.
+    pub fn trace(&self) -> Vec<Step> {
+        self.trace.lock().unwrap().clone()
+    }
+
+    /// Play the peer on `chan` until the session ends or the peer stops.
+    pub fn drive<S: Peer<P, I, ()>>(&mut self, chan: Channel<P, I, (), S>) -> Result<(), Stop> {
+        try!(S::drive(chan, self));
+
+        // every time round a loop starts from here, so that the stack
+        // doesn't grow with the number of steps
+        while let Some((frame, chan)) = self.resume.take() {
+            try!(frame(chan, self));
+        }
+
+        Ok(())
+    }
+
+    fn step(&mut self, step: Step) -> Result<(), Stop> {
+        if self.steps == 0 {
+            return Err(Stop::Exhausted);
+        }
+
+        self.steps -= 1;
+        self.trace.lock().unwrap().push(step);
+
+        Ok(())
+    }
+}
+
+/// Session types a random peer knows how to follow. `drive` returns
+/// early when it reaches an `Escape` or `Var`, leaving `Driver::drive` to
+/// carry on from the start of the loop.
+pub trait Peer<P: Protocol, I: IO, E: SessionType>: SessionType + Sized {
+    fn drive(chan: Channel<P, I, E, Self>, driver: &mut Driver<P, I>) -> Result<(), Stop>;
+}
+
+/// The branches of a `Choose` or `Accept` tree, for a random peer.
+pub trait Branches<P: Protocol, I: IO, E: SessionType>: SessionType {
+    fn count() -> usize;
+
+    /// Choose branch `num`, `depth` branches from the root `R`.
+    fn choose<R: SessionType>(chan: Channel<P, I, E, R>, num: usize, depth: usize, driver: &mut Driver<P, I>) -> Result<(), Stop>;
+
+    /// Follow branch `num`, which the other side chose, `depth` branches
+    /// from the root `R`.
+    fn accept<R: SessionType>(chan: Channel<P, I, E, R>, num: usize, depth: usize, driver: &mut Driver<P, I>) -> Result<(), Stop>;
+}
+
+impl<P: Protocol, I: IO, E: SessionType> Peer<P, I, E> for End {
+    fn drive(chan: Channel<P, I, E, End>, driver: &mut Driver<P, I>) -> Result<(), Stop> {
+        try!(driver.step(Step::Closed));
+        chan.close();
+
+        Ok(())
+    }
+}
+
+impl<P: Protocol, I: Transfers<T>, T: Arbitrary + fmt::Debug, E: SessionType, S: Peer<P, I, E>> Peer<P, I, E> for Send<T, S> {
+    fn drive(chan: Channel<P, I, E, Send<T, S>>, driver: &mut Driver<P, I>) -> Result<(), Stop> {
+        let val = T::arbitrary(&mut driver.gen);
+        try!(driver.step(Step::Sent(type_name::<T>(), format!("{:?}", val))));
+
+        S::drive(chan.send(val), driver)
+    }
+}
+
+impl<P: Protocol, I: Transfers<T>, T: fmt::Debug, E: SessionType, S: Peer<P, I, E>> Peer<P, I, E> for Recv<T, S> {
+    fn drive(chan: Channel<P, I, E, Recv<T, S>>, driver: &mut Driver<P, I>) -> Result<(), Stop> {
+        match chan.recv() {
+            Ok((val, chan)) => {
+                try!(driver.step(Step::Received(type_name::<T>(), format!("{:?}", val))));
+                S::drive(chan, driver)
+            },
+            Err(_) => Err(Stop::Hangup)
+        }
+    }
+}
+
+// Unsized payloads are generated and received in their owned form.
+macro_rules! unsized_payload {
+    ($($t:ty, $owned:ty);*) => ($(
+        impl<P: Protocol, I: TransfersRef<$t>, E: SessionType, S: Peer<P, I, E>> Peer<P, I, E> for Send<$t, S> {
+            fn drive(chan: Channel<P, I, E, Send<$t, S>>, driver: &mut Driver<P, I>) -> Result<(), Stop> {
+                let val = <$owned>::arbitrary(&mut driver.gen);
+                try!(driver.step(Step::Sent(type_name::<$t>(), format!("{:?}", val))));
+
+                S::drive(chan.send_ref(&val), driver)
+            }
+        }
+
+        impl<P: Protocol, I: Transfers<$owned>, E: SessionType, S: Peer<P, I, E>> Peer<P, I, E> for Recv<$t, S> {
+            fn drive(chan: Channel<P, I, E, Recv<$t, S>>, driver: &mut Driver<P, I>) -> Result<(), Stop> {
+                match chan.recv() {
+                    Ok((val, chan)) => {
+                        try!(driver.step(Step::Received(type_name::<$t>(), format!("{:?}", val))));
+                        S::drive(chan, driver)
+                    },
+                    Err(_) => Err(Stop::Hangup)
+                }
+            }
+        }
+    )*)
+}
+
+unsized_payload!([u8], Vec<u8>; str, String);
+
+impl<P: Protocol, I: Transfers<T>, T: Arbitrary + fmt::Debug, E: SessionType, S: Peer<P, I, E>> Peer<P, I, E> for SendMany<T, S> {
+    fn drive(chan: Channel<P, I, E, SendMany<T, S>>, driver: &mut Driver<P, I>) -> Result<(), Stop> {
+        let len = driver.gen.below(5);
+        let mut items = Vec::with_capacity(len);
+        for _ in 0..len {
+            let val = T::arbitrary(&mut driver.gen);
+            try!(driver.step(Step::Sent(type_name::<T>(), format!("{:?}", val))));
+            items.push(val);
+        }
+
+        S::drive(chan.send_iter(items), driver)
+    }
+}
+
+impl<P: Protocol, I: Transfers<T>, T: fmt::Debug, E: SessionType, S: Peer<P, I, E>> Peer<P, I, E> for RecvMany<T, S> {
+    fn drive(chan: Channel<P, I, E, RecvMany<T, S>>, driver: &mut Driver<P, I>) -> Result<(), Stop> {
+        let mut items = match chan.recv_iter() {
+            Ok(items) => items,
+            Err(_) => return Err(Stop::Hangup)
+        };
+
+        while let Some(val) = items.next() {
+            try!(driver.step(Step::Received(type_name::<T>(), format!("{:?}", val))));
+        }
+
+        match items.finish() {
+            Ok(chan) => S::drive(chan, driver),
+            Err(_) => Err(Stop::Hangup)
+        }
+    }
+}
+
+impl<P: Protocol, I: IO, E: SessionType, S: Peer<P, I, (S, E)>> Peer<P, I, E> for Nest<S> {
+    fn drive(chan: Channel<P, I, E, Nest<S>>, driver: &mut Driver<P, I>) -> Result<(), Stop> {
+        try!(driver.step(Step::Entered));
+
+        // Escaping back into this scope can't be expressed as a trait
+        // bound (it would be cyclic), so the body is kept on the stack.
+        let frame: fn(Channel<P, I, (S, E), S>, &mut Driver<P, I>) -> Result<(), Stop> = S::drive;
+        driver.stack.push(unsafe { mem::transmute(frame) });
+
+        S::drive(chan.enter(), driver)
+    }
+}
+
+impl<P: Protocol, I: IO, N: Peano, E: SessionType + Pop<N>> Peer<P, I, E> for Escape<N> {
+    fn drive(chan: Channel<P, I, E, Escape<N>>, driver: &mut Driver<P, I>) -> Result<(), Stop> {
+        try!(driver.step(Step::Escaped(N::to_usize())));
+
+        // `E: Pop<N>` guarantees we entered at least N + 1 scopes
+        let depth = driver.stack.len() - N::to_usize();
+        driver.stack.truncate(depth);
+        let frame = driver.stack[depth - 1];
+        driver.resume = Some((frame, unsafe { chan.into_state() }));
+
+        Ok(())
+    }
+}
+
+impl<P: Protocol, I: IO, L, E: SessionType, S: Peer<P, I, Bind<L, S, E>>> Peer<P, I, E> for Rec<L, S> {
+    fn drive(chan: Channel<P, I, E, Rec<L, S>>, driver: &mut Driver<P, I>) -> Result<(), Stop> {
+        try!(driver.step(Step::Entered));
+
+        let frame: fn(Channel<P, I, Bind<L, S, E>, S>, &mut Driver<P, I>) -> Result<(), Stop> = S::drive;
+        driver.stack.push(unsafe { mem::transmute(frame) });
+
+        S::drive(chan.recurse(), driver)
+    }
+}
+
+impl<P: Protocol, I: IO, L, E: SessionType + Lookup<L>> Peer<P, I, E> for Var<L> {
+    fn drive(chan: Channel<P, I, E, Var<L>>, driver: &mut Driver<P, I>) -> Result<(), Stop> {
+        try!(driver.step(Step::Escaped(E::depth())));
+
+        // every frame entered since the `Rec` is on the stack too
+        let depth = driver.stack.len() - E::depth();
+        driver.stack.truncate(depth);
+        let frame = driver.stack[depth - 1];
+        driver.resume = Some((frame, unsafe { chan.into_state() }));
+
+        Ok(())
+    }
+}
+
+impl<P: Protocol, I: IO, E: SessionType, S: SessionType, Q: SessionType> Peer<P, I, E> for Choose<S, Q>
+    where Choose<S, Q>: Branches<P, I, E>
+{
+    fn drive(chan: Channel<P, I, E, Choose<S, Q>>, driver: &mut Driver<P, I>) -> Result<(), Stop> {
+        let num = driver.gen.below(Self::count());
+        <Self as Branches<P, I, E>>::choose(chan, num, 0, driver)
+    }
+}
+
+impl<P: Protocol, I: IO, E: SessionType, S: SessionType, Q: SessionType> Peer<P, I, E> for Accept<S, Q>
+    where Accept<S, Q>: Branches<P, I, E>
+{
+    fn drive(mut chan: Channel<P, I, E, Accept<S, Q>>, driver: &mut Driver<P, I>) -> Result<(), Stop> {
+        unsafe { chan.io_mut().flush() };
+
+        match unsafe { chan.io_mut().recv_discriminant() } {
+            Some(num) => <Self as Branches<P, I, E>>::accept(chan, num, 0, driver),
+            None => Err(Stop::Hangup)
+        }
+    }
+}
+
+impl<P: Protocol, I: IO, E: SessionType, S: Peer<P, I, E>> Peer<P, I, E> for Finally<S> {
+    fn drive(chan: Channel<P, I, E, Finally<S>>, driver: &mut Driver<P, I>) -> Result<(), Stop> {
+        // on its own, `Finally` can only be chosen
+        <Self as Branches<P, I, E>>::choose(chan, 0, 0, driver)
+    }
+}
+
+impl<P: Protocol, I: IO, E: SessionType, S: Peer<P, I, E>, Q: Branches<P, I, E>> Branches<P, I, E> for Choose<S, Q> {
+    fn count() -> usize {
+        Q::count() + 1
+    }
+
+    fn choose<R: SessionType>(mut chan: Channel<P, I, E, R>, num: usize, depth: usize, driver: &mut Driver<P, I>) -> Result<(), Stop> {
+        if num == 0 {
+            try!(driver.step(Step::Chose(depth)));
+            unsafe { chan.io_mut().send_discriminant(depth); }
+            S::drive(unsafe { chan.into_session() }, driver)
+        } else {
+            Q::choose(chan, num - 1, depth + 1, driver)
+        }
+    }
+
+    fn accept<R: SessionType>(chan: Channel<P, I, E, R>, num: usize, depth: usize, driver: &mut Driver<P, I>) -> Result<(), Stop> {
+        Q::accept(chan, num, depth, driver)
+    }
+}
+
+impl<P: Protocol, I: IO, E: SessionType, S: Peer<P, I, E>, Q: Branches<P, I, E>> Branches<P, I, E> for Accept<S, Q> {
+    fn count() -> usize {
+        Q::count() + 1
+    }
+
+    fn choose<R: SessionType>(chan: Channel<P, I, E, R>, num: usize, depth: usize, driver: &mut Driver<P, I>) -> Result<(), Stop> {
+        Q::choose(chan, num, depth, driver)
+    }
+
+    fn accept<R: SessionType>(chan: Channel<P, I, E, R>, num: usize, depth: usize, driver: &mut Driver<P, I>) -> Result<(), Stop> {
+        if num == 0 {
+            try!(driver.step(Step::Accepted(depth)));
+            S::drive(unsafe { chan.into_session() }, driver)
+        } else {
+            Q::accept(chan, num - 1, depth + 1, driver)
+        }
+    }
+}
+
+impl<P: Protocol, I: IO, E: SessionType, S: Peer<P, I, E>> Branches<P, I, E> for Finally<S> {
+    fn count() -> usize {
+        1
+    }
+
+    fn choose<R: SessionType>(mut chan: Channel<P, I, E, R>, _: usize, depth: usize, driver: &mut Driver<P, I>) -> Result<(), Stop> {
+        try!(driver.step(Step::Chose(depth)));
+        unsafe { chan.io_mut().send_discriminant(depth); }
+        S::drive(unsafe { chan.into_session() }, driver)
+    }
+
+    fn accept<R: SessionType>(chan: Channel<P, I, E, R>, num: usize, depth: usize, driver: &mut Driver<P, I>) -> Result<(), Stop> {
+        if num == 0 {
+            try!(driver.step(Step::Accepted(depth)));
+            S::drive(unsafe { chan.into_session() }, driver)
+        } else {
+            Err(Stop::Violation)
+        }
+    }
+}
+
+/// The protocol a random peer runs: it has no state and no handlers.
+pub struct Conforming<S>(PhantomData<fn() -> S>);
+
+impl<S: SessionType> Protocol for Conforming<S> {
+    type Initial = S;
+}
+
+/// A handler panicked while talking to a random peer.
+#[derive(Clone, Debug)]
+pub struct Failure {
+    pub seed: u64,
+    pub message: String,
+    /// Everything the peer did, up to the panic.
+    pub trace: Vec<Step>
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "handler panicked with seed {}: {}", self.seed, self.message));
+        try!(writeln!(f, "peer trace:"));
+        for (i, step) in self.trace.iter().enumerate() {
+            try!(writeln!(f, "{:>6}: {:?}", i, step));
+        }
+
+        Ok(())
+    }
+}
+
+fn panic_message(err: &Box<Any + ::std::marker::Send>) -> String {
+    match err.downcast_ref::<&'static str>() {
+        Some(msg) => msg.to_string(),
+        None => match err.downcast_ref::<String>() {
+            Some(msg) => msg.clone(),
+            None => "unknown panic".to_string()
+        }
+    }
+}
+
+/// Run the handlers of `proto` against a random peer over a `Blocking`
+/// channel. The peer performs at most `steps` operations; if it runs out
+/// it hangs up, and anything the handlers do after that is not counted
+/// as a failure. Returns the peer's trace, or the trace and the panic
+/// message if a handler panicked.
+pub fn run<P>(proto: P, seed: u64, steps: usize) -> Result<Vec<Step>, Failure>
+    where P: Protocol + Handler<Blocking, (), <P as Protocol>::Initial>,
+          P::Initial: WellFormed,
+          <P::Initial as SessionType>::Dual: Peer<Conforming<<P::Initial as SessionType>::Dual>, Blocking, ()> + WellFormed + 'static
+{
+    let (io1, io2) = Blocking::pair();
+
+    let mut driver = Driver::new(seed, steps);
+    let trace = driver.trace.clone();
+    let finished = Arc::new(AtomicBool::new(false));
+
+    let peer = {
+        let finished = finished.clone();
+
+        thread::spawn(move || {
+            let chan = channel(io2, Conforming::<<P::Initial as SessionType>::Dual>(PhantomData));
+            let result = driver.drive(chan);
+            finished.store(true, Ordering::SeqCst);
+            result
+        })
+    };
+
+    let mut session = channel(io1, proto).defer();
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        // once the peer is gone, a handler which keeps deferring would
+        // spin forever; give it a bounded number of chances to finish
+        let mut idle = 0;
+        while session.with() {
+            if finished.load(Ordering::SeqCst) {
+                idle += 1;
+                if idle > steps + 16 {
+                    break;
+                }
+            }
+        }
+    }));
+    drop(session);
+
+    // a peer which panicked could only have lost its connection
+    let stopped = peer.join().unwrap_or(Err(Stop::Hangup));
+    let trace = trace.lock().unwrap().clone();
+
+    match result {
+        Err(ref err) if stopped != Err(Stop::Exhausted) => {
+            Err(Failure {
+                seed: seed,
+                message: panic_message(err),
+                trace: trace
+            })
+        },
+        _ => Ok(trace)
+    }
+}
+
+fn arbitrary_discriminant(g: &mut Gen) -> usize {
+    match g.below(3) {
+        0 => g.below(4),
+        1 => usize::max_value() - g.below(4),
+        _ => g.next_u64() as usize
+    }
+}
+
+fn arbitrary_op(g: &mut Gen) -> Op {
+    if g.chance(0.5) {
+        Op::RecvDiscriminant(Some(arbitrary_discriminant(g)))
+    } else {
+        let len = g.below(16);
+        Op::Recv(Some((0..len).map(|_| g.next_u64() as u8).collect()))
+    }
+}
+
+// Corrupt the encoding of an inbound value: truncate it, flip a bit,
+// append junk, drop it, or replace it with something else entirely.
+fn corrupt(mut bytes: Vec<u8>, g: &mut Gen) -> Option<Op> {
+    match g.below(5) {
+        0 if !bytes.is_empty() => {
+            let len = g.below(bytes.len());
+            bytes.truncate(len);
+            Some(Op::Recv(Some(bytes)))
+        },
+        1 if !bytes.is_empty() => {
+            let at = g.below(bytes.len());
+            bytes[at] ^= 1 << g.below(8);
+            Some(Op::Recv(Some(bytes)))
+        },
+        2 => {
+            for _ in 0..g.below(8) + 1 {
+                bytes.push(g.next_u64() as u8);
+            }
+            Some(Op::Recv(Some(bytes)))
+        },
+        3 => None,
+        _ => Some(arbitrary_op(g))
+    }
+}
+
+/// Corrupt the inbound operations of `trace`. Payloads and discriminants
+/// are corrupted in their wire encoding, so a lenient `Replay` has to
+/// decode them again: they are truncated, extended or have bits flipped,
+/// are dropped or change kind. Extra data is also appended after the end
+/// of the session.
+pub fn mutate(trace: &Trace, g: &mut Gen) -> Trace {
+    let mut ops = trace.ops.clone();
+
+    for _ in 0..g.below(3) + 1 {
+        let inbound: Vec<usize> = ops.iter().enumerate().filter_map(|(i, op)| match *op {
+            Op::Recv(_) | Op::RecvDiscriminant(_) => Some(i),
+            _ => None
+        }).collect();
+
+        if inbound.is_empty() || g.below(6) == 0 {
+            // extra data after the session is over
+            for _ in 0..g.below(4) + 1 {
+                let op = arbitrary_op(g);
+                ops.push(op);
+            }
+            continue;
+        }
+
+        let i = inbound[g.below(inbound.len())];
+        let op = match ops[i].clone() {
+            Op::RecvDiscriminant(Some(num)) => corrupt(wire::to_bytes(&num), g),
+            Op::Recv(Some(bytes)) => corrupt(bytes, g),
+            _ => Some(arbitrary_op(g))
+        };
+
+        match op {
+            Some(op) => ops[i] = op,
+            None => { ops.remove(i); }
+        }
+    }
+
+    Trace { ops: ops }
+}
+
+/// The inbound traffic of `trace` as a `Framed` peer would have sent it:
+/// a frame for every payload and discriminant received, and then a close
+/// frame.
+pub fn framed_bytes(trace: &Trace) -> Vec<u8> {
+    let mut out = Framed::new(io::Cursor::new(vec![]));
+
+    for op in &trace.ops {
+        let _ = match *op {
+            Op::Recv(Some(ref bytes)) => out.queue_frame(&framed::Frame::Payload(bytes.clone())),
+            Op::RecvDiscriminant(Some(num)) => out.queue_frame(&framed::Frame::Discriminant(num)),
+            _ => Ok(())
+        };
+    }
+
+    let _ = out.write_frame(&framed::Frame::Close);
+    out.into_inner().into_inner()
+}
+
+/// Corrupt a byte stream without regard for where its frames begin and
+/// end: truncate it, flip bits, insert junk, or delete or repeat runs of
+/// bytes. Frame headers, lengths and discriminants all get hit.
+pub fn mutate_bytes(bytes: &[u8], g: &mut Gen) -> Vec<u8> {
+    let mut bytes = bytes.to_vec();
+
+    for _ in 0..g.below(3) + 1 {
+        if bytes.is_empty() {
+            bytes.push(g.next_u64() as u8);
+            continue;
+        }
+
+        let at = g.below(bytes.len());
+        match g.below(5) {
+            0 => bytes.truncate(at),
+            1 => bytes[at] ^= 1 << g.below(8),
+            2 => {
+                for _ in 0..g.below(8) + 1 {
+                    bytes.insert(at, g.next_u64() as u8);
+                }
+            },
+            3 => {
+                let end = at + g.below(bytes.len() - at) + 1;
+                bytes.drain(at..end);
+            },
+            _ => {
+                let end = at + g.below(bytes.len() - at) + 1;
+                let run = bytes[at..end].to_vec();
+                let tail = bytes.split_off(end);
+                bytes.extend(run);
+                bytes.extend(tail);
+            }
+        }
+    }
+
+    bytes
+}
+
+/// A stream which hands out `input` in chunks of random size, sometimes
+/// blocking in between so that frames arrive in pieces, and discards
+/// everything written to it.
+pub struct HostileStream {
+    input: Vec<u8>,
+    at: usize,
+    gen: Gen
+}
+
+impl HostileStream {
+    pub fn new(input: Vec<u8>, seed: u64) -> HostileStream {
+        HostileStream {
+            input: input,
+            at: 0,
+            gen: Gen::new(seed)
+        }
+    }
+}
+
+impl Read for HostileStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.at == self.input.len() {
+            return Ok(0);
+        }
+
+        if self.gen.chance(0.25) {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "nothing yet"));
+        }
+
+        let len = cmp::min(cmp::min(buf.len(), self.input.len() - self.at), self.gen.below(16) + 1);
+        buf[..len].copy_from_slice(&self.input[self.at..self.at + len]);
+        self.at += len;
+
+        Ok(len)
+    }
+}
+
+impl Write for HostileStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A handler panicked on corrupted input.
+#[derive(Clone, Debug)]
+pub struct HostileFailure {
+    pub seed: u64,
+    pub iteration: usize,
+    pub message: String,
+    /// The corrupted trace which provoked the panic. For `hostile_stream`
+    /// this is the trace as it was recorded.
+    pub trace: Trace,
+    /// For `hostile_stream`, the corrupted bytes which provoked the panic.
+    pub stream: Option<Vec<u8>>
+}
+
+impl fmt::Display for HostileFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "handler panicked with seed {} on iteration {}: {}",
+                      self.seed, self.iteration, self.message));
+        if let Some(ref stream) = self.stream {
+            return writeln!(f, "corrupted stream: {}", wire::to_hex(stream));
+        }
+
+        try!(writeln!(f, "corrupted trace:"));
+        for (i, op) in self.trace.ops.iter().enumerate() {
+            match *op {
+                Op::Recv(Some(ref bytes)) => try!(writeln!(f, "{:>6}: Recv({})", i, wire::to_hex(bytes))),
+                ref op => try!(writeln!(f, "{:>6}: {:?}", i, op))
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Replay `iterations` corrupted copies of a recorded `trace` to the
+/// handlers of a session started by `start`, which should begin the
+/// session on the same side that was recorded. Handlers must survive
+/// every one of them without panicking; a handler which keeps deferring
+/// is resumed a bounded number of times and then abandoned.
+pub fn hostile<P, F>(trace: &Trace, seed: u64, iterations: usize, start: F) -> Result<(), HostileFailure>
+    where P: Protocol, F: Fn(Replay) -> Defer<P, Replay>
+{
+    let mut g = Gen::new(seed);
+
+    for iteration in 0..iterations {
+        let corrupted = mutate(trace, &mut g);
+        let limit = corrupted.ops.len() * 2 + 16;
+        let replay = Replay::lenient(corrupted.clone());
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut session = start(replay);
+            for _ in 0..limit {
+                if !session.with() {
+                    break;
+                }
+            }
+        }));
+
+        if let Err(err) = result {
+            return Err(HostileFailure {
+                seed: seed,
+                iteration: iteration,
+                message: panic_message(&err),
+                trace: corrupted,
+                stream: None
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Like `hostile`, but the handlers run over a `Framed` backend, which
+/// parses the inbound traffic of `trace` from `iterations` corrupted
+/// copies of its bytes. The bytes arrive in pieces, with the stream
+/// blocking in between, so partial frames are exercised too.
+pub fn hostile_stream<P, F>(trace: &Trace, seed: u64, iterations: usize, start: F) -> Result<(), HostileFailure>
+    where P: Protocol, F: Fn(Framed<HostileStream>) -> Defer<P, Framed<HostileStream>>
+{
+    let mut g = Gen::new(seed);
+    let bytes = framed_bytes(trace);
+
+    for iteration in 0..iterations {
+        let corrupted = mutate_bytes(&bytes, &mut g);
+        let limit = corrupted.len() * 4 + 16;
+        let stream = HostileStream::new(corrupted.clone(), g.next_u64());
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut session = start(Framed::new(stream));
+            for _ in 0..limit {
+                if !session.with() {
+                    break;
+                }
+            }
+        }));
+
+        if let Err(err) = result {
+            return Err(HostileFailure {
+                seed: seed,
+                iteration: iteration,
+                message: panic_message(&err),
+                trace: trace.clone(),
+                stream: Some(corrupted)
+            });
+        }
+    }
+
+    Ok(())
+}
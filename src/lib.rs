@@ -12,16 +12,189 @@
 //! otherwise.
 
 #![feature(optin_builtin_traits)]
+#![feature(on_unimplemented)]
+#![feature(core_intrinsics)]
 
 pub mod peano;
 pub mod session_types;
 pub mod channels;
+pub mod wire;
+pub mod fuzz;
+pub mod spec;
+pub mod checkpoint;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 mod protocol;
+mod rng;
 
-pub use protocol::{Channel, Defer, Protocol, Handler, channel, channel_dual};
+pub use protocol::{Channel, Defer, Protocol, Handler, RecvIter, Fork, Suspended, channel, channel_dual};
 
+/// The name of `T` as the compiler prints it, for traces and reports.
+fn type_name<T: ?Sized>() -> &'static str {
+    unsafe { ::std::intrinsics::type_name::<T>() }
+}
+
+/// Strip the module paths from a type and write it without spaces around
+/// punctuation, so that `std::vec::Vec< u8 >` and `Vec<u8>` compare equal.
+fn normalize(ty: &str) -> String {
+    let mut out = String::new();
+    let mut word = String::new();
+    let mut space = false;
+
+    let chars: Vec<char> = ty.chars().collect();
+    let mut i = 0;
+    while i <= chars.len() {
+        let c = chars.get(i).cloned();
+        match c {
+            Some(c) if c == '_' || c.is_alphanumeric() => {
+                if word.is_empty() && space && out.chars().last().map_or(false, |c| c == '_' || c.is_alphanumeric()) {
+                    out.push(' ');
+                }
+                space = false;
+                word.push(c);
+            },
+            _ => {
+                if !word.is_empty() {
+                    if chars.get(i) == Some(&':') && chars.get(i + 1) == Some(&':') {
+                        // a path segment; skip it and the separator
+                        i += 2;
+                        word.clear();
+                        continue;
+                    }
+                    out.push_str(&word);
+                    word.clear();
+                }
+                match c {
+                    Some(c) if c.is_whitespace() => space = true,
+                    Some(c) => {
+                        space = false;
+                        out.push(c);
+                    },
+                    None => {}
+                }
+            }
+        }
+        i += 1;
+    }
+
+    out
+}
+
+/// Builds a session type from a description of the protocol, e.g.
+/// `proto!(Recv String, loop { Send u64, continue })`. A loop may be
+/// labelled with a type, as in `loop Outer { .. continue Outer }`, which
+/// makes it a `Rec` rather than a `Nest`. The label has to be a type, not
+/// a lifetime such as `'outer`: it is what tells the loops of a session
+/// apart, and lifetimes can't do that inside a type.
+/// `Par { {..}, {..} }, ..` runs two sessions side by side before
+/// continuing, and `try { .. } catch { .. }` is a `Try`.
+/// `Timed D { .. } else { .. }` waits at most `D` for the first block,
+/// and `Due` is its dual.
+///
+/// Used as an item, `proto! { mod atm { ... } }` declares both sides of a
+/// protocol at once. Every state in the block is named, and the first is
+/// where the protocol starts; states declared inside
+/// `loop Name = ... { ... }` run in the environment of that loop:
+///
+/// ```ignore
+/// proto! {
+///     mod atm {
+///         Start = {Recv String, loop { goto Menu }};
+///
+///         loop Menu = {Accept { {goto Deposit}, {goto Quit} }} {
+///             Deposit = {Recv u64, Send u64, continue};
+///             Quit = {End};
+///         }
+///     }
+/// }
+/// ```
+///
+/// The module contains `Server` and `Client`, the two endpoints;
+/// `server::Menu`, `client::Menu` and so on, the states as each side sees
+/// them; and the traits `ServerHandlers` and `ClientHandlers`, which
+/// require a `Handler` for every named state in its environment. Writing
+/// `impl<I, E: SessionType> atm::ServerHandlers<I, E> for Atm { }` checks
+/// that none are missing. A `goto` may only name states of the same
+/// block, as the client's states are written out as the duals of the
+/// server's.
 #[macro_export]
 macro_rules! proto {
+	(@dual [$($out:tt)*] []) => (proto!($($out)*));
+	(@dual [$($out:tt)*] [[$($pout:tt)*] [$($prest:tt)*] $($stack:tt)*]) => (proto!(@dual [$($pout)* {$($out)*}] [$($stack)*] $($prest)*));
+	(@dual [$($out:tt)*] [$($stack:tt)*] { $($inner:tt)* } $($rest:tt)*) => (proto!(@dual [] [[$($out)*] [$($rest)*] $($stack)*] $($inner)*));
+	(@dual [$($out:tt)*] [$($stack:tt)*] Recv $($rest:tt)*) => (proto!(@dual [$($out)* Send] [$($stack)*] $($rest)*));
+	(@dual [$($out:tt)*] [$($stack:tt)*] Send $($rest:tt)*) => (proto!(@dual [$($out)* Recv] [$($stack)*] $($rest)*));
+	(@dual [$($out:tt)*] [$($stack:tt)*] RecvMany $($rest:tt)*) => (proto!(@dual [$($out)* SendMany] [$($stack)*] $($rest)*));
+	(@dual [$($out:tt)*] [$($stack:tt)*] SendMany $($rest:tt)*) => (proto!(@dual [$($out)* RecvMany] [$($stack)*] $($rest)*));
+	(@dual [$($out:tt)*] [$($stack:tt)*] Accept $($rest:tt)*) => (proto!(@dual [$($out)* Choose] [$($stack)*] $($rest)*));
+	(@dual [$($out:tt)*] [$($stack:tt)*] Choose $($rest:tt)*) => (proto!(@dual [$($out)* Accept] [$($stack)*] $($rest)*));
+	(@dual [$($out:tt)*] [$($stack:tt)*] Offer $($rest:tt)*) => (proto!(@dual [$($out)* Select] [$($stack)*] $($rest)*));
+	(@dual [$($out:tt)*] [$($stack:tt)*] Select $($rest:tt)*) => (proto!(@dual [$($out)* Offer] [$($stack)*] $($rest)*));
+	(@dual [$($out:tt)*] [$($stack:tt)*] Timed $($rest:tt)*) => (proto!(@dual [$($out)* Due] [$($stack)*] $($rest)*));
+	(@dual [$($out:tt)*] [$($stack:tt)*] Due $($rest:tt)*) => (proto!(@dual [$($out)* Timed] [$($stack)*] $($rest)*));
+	(@dual [$($out:tt)*] [$($stack:tt)*] $t:tt $($rest:tt)*) => (proto!(@dual [$($out)* $t] [$($stack)*] $($rest)*));
+	(@env $side:ident $base:ty;) => ($base);
+	(@env $side:ident $base:ty; $name:ident $($rest:ident)*) => (($side::$name, proto!(@env $side $base; $($rest)*)));
+	(@states $module:tt [$($out:tt)*] [$($env:ident)*] [$($stack:tt)*] loop $name:ident = $s:tt { $($inner:tt)* } $($rest:tt)*) => (
+		proto!(@states $module [$($out)* ($name [$name $($env)*] $s)] [$name $($env)*] [([$($env)*] [$($rest)*]) $($stack)*] $($inner)*);
+	);
+	(@states $module:tt [$($out:tt)*] [$($env:ident)*] [$($stack:tt)*] $name:ident = $s:tt; $($rest:tt)*) => (
+		proto!(@states $module [$($out)* ($name [$($env)*] $s)] [$($env)*] [$($stack)*] $($rest)*);
+	);
+	(@states $module:tt [$($out:tt)*] [$($env:ident)*] [([$($penv:ident)*] [$($prest:tt)*]) $($stack:tt)*]) => (
+		proto!(@states $module [$($out)*] [$($penv)*] [$($stack)*] $($prest)*);
+	);
+	(@states [$($module:tt)*] [$($out:tt)*] [$($env:ident)*] []) => (
+		proto!(@module [$($module)*] $($out)*);
+	);
+	(@module [[$($vis:tt)*] $m:ident] ($first:ident [$($fenv:ident)*] $fs:tt) $(($name:ident [$($env:ident)*] $s:tt))*) => (
+		$($vis)* mod $m {
+			#![allow(dead_code, unused_imports)]
+			use super::*;
+			use $crate::Handler;
+			use $crate::session_types::*;
+			use $crate::peano::*;
+
+			/// The side which starts in the first state declared.
+			pub type Server = server::$first;
+
+			/// The other side.
+			pub type Client = client::$first;
+
+			pub mod server {
+				use super::*;
+
+				pub type $first = proto!($fs);
+				$(pub type $name = proto!($s);)*
+			}
+
+			pub mod client {
+				use super::*;
+
+				pub type $first = proto!(@dual [] [] $fs);
+				$(pub type $name = proto!(@dual [] [] $s);)*
+			}
+
+			/// The handlers a server must implement.
+			pub trait ServerHandlers<I, E: SessionType>: Handler<I, proto!(@env server E; $($fenv)*), server::$first>
+				$(+ Handler<I, proto!(@env server E; $($env)*), server::$name>)* { }
+
+			/// The handlers a client must implement.
+			pub trait ClientHandlers<I, E: SessionType>: Handler<I, proto!(@env client E; $($fenv)*), client::$first>
+				$(+ Handler<I, proto!(@env client E; $($env)*), client::$name>)* { }
+
+			// the client's states are written out rather than projected, so
+			// that handlers for them never overlap; make sure they agree
+			fn check_duality() {
+				use std::marker::PhantomData;
+
+				let _: PhantomData<client::$first> = PhantomData::<<server::$first as SessionType>::Dual>;
+				$(let _: PhantomData<client::$name> = PhantomData::<<server::$name as SessionType>::Dual>;)*
+			}
+		}
+	);
+	(mod $m:ident { $($decls:tt)* }) => (proto!(@states [[] $m] [] [] [] $($decls)*););
+	(pub mod $m:ident { $($decls:tt)* }) => (proto!(@states [[pub] $m] [] [] [] $($decls)*););
 	(@peano 0) => (Z);
 	(@peano 1) => (S<Z>);
 	(@peano 2) => (S<proto!(@peano 1)>);
@@ -39,9 +212,20 @@ macro_rules! proto {
 	(@peano 14) => (S<proto!(@peano 13)>);
 	(@peano 15) => (S<proto!(@peano 14)>);
 	(@peano 16) => (S<proto!(@peano 15)>);
+	(Offer { $($rest:tt)* }) => (Offer<proto!(Accept { $($rest)* })>);
+	(Select { $($rest:tt)* }) => (Select<proto!(Choose { $($rest)* })>);
+	(try { $($body:tt)* } catch { $($rest:tt)* }) => (Try<proto!($($body)*), proto!($($rest)*)>);
+	(Timed $d:ty { $($s:tt)* } else { $($rest:tt)* }) => (Timed<$d, proto!($($s)*), proto!($($rest)*)>);
+	(Due $d:ty { $($s:tt)* } else { $($rest:tt)* }) => (Due<$d, proto!($($s)*), proto!($($rest)*)>);
+	(Par { $a:tt, $b:tt }, $($rest:tt)*) => (Par<proto!($a), proto!($b), proto!($($rest)*)>);
 	(Recv $t:ty, $($rest:tt)*) => (Recv<$t, proto!($($rest)*)>);
 	(Send $t:ty, $($rest:tt)*) => (Send<$t, proto!($($rest)*)>);
+	(RecvMany $t:ty, $($rest:tt)*) => (RecvMany<$t, proto!($($rest)*)>);
+	(SendMany $t:ty, $($rest:tt)*) => (SendMany<$t, proto!($($rest)*)>);
 	(loop { $($rest:tt)* }) => (Nest<proto!($($rest)*)>);
+	(loop $l:ident { $($rest:tt)* }) => (Rec<$l, proto!($($rest)*)>);
+	(loop $l:tt { $($rest:tt)* }) => (compile_error!("loops are labelled with a type, as in `loop Outer { .. continue Outer }`"));
+	(continue $l:ident) => (Var<$l>);
 	(continue $p:tt) => (Escape<proto!(@peano $p)>);
 	(continue) => (Escape<Z>);
 	(goto $p:ty) => ($p);
@@ -89,6 +273,280 @@ macro_rules! handlers {
     );
 }
 
+/// Declares a protocol and its handlers in one place. The first line names
+/// the protocol, the types its IO must transfer, and its initial state.
+/// Each state after that is either given a name, which becomes a type
+/// alias built with `proto!`, or written out in place; it is followed by
+/// the body of its handler. States inside `loop Name = ... { ... }` are
+/// handled in the environment of that loop, so handlers never spell out
+/// the `(Name, E)` environments `Nest` requires.
+///
+/// ```ignore
+/// session! {
+///     Doubler(u64) = Start;
+///
+///     Start = {loop { goto Menu }} => |this| { this.enter().defer() }
+///
+///     loop Menu = {Accept { {goto Double}, End }} {
+///         Menu => |this| {
+///             match this.accept() {
+///                 Ok(d) => d,
+///                 Err(this) => this.defer()
+///             }
+///         }
+///
+///         Double = {Recv u64, Send u64, continue} => |this| {
+///             match this.recv() {
+///                 Ok((num, this)) => this.send(num * 2).pop().defer(),
+///                 Err(this) => this.defer()
+///             }
+///         }
+///
+///         End => |this| { this.close() }
+///     }
+/// }
+/// ```
+///
+/// The protocol can also be written as straight-line code, from which its
+/// states and handlers are derived. `let x = recv::<T>();` and
+/// `send::<T>(value);` transfer a `T`, `offer { {..}, .. }` accepts one
+/// of its blocks, `choose (value) { pattern => {..} .. }` chooses the
+/// block whose pattern matches, `loop { .. }` enters a nested protocol
+/// and `continue;` or `continue N;` escapes from it. The protocol ends
+/// where a block runs out of statements. Any other statement is run as it
+/// is. Handlers are split wherever the channel may have to wait on the
+/// peer, before `recv` and `offer`, and at the start of every `loop` and
+/// `offer` block. Variables received with `recv`, or declared with a type
+/// as in `let x: T = value;`, are carried over to the handlers after such
+/// a split, up to the next `loop` or `continue`, so their types must be
+/// `Send` and `'static`, and their names distinct. Other variables only
+/// live until the next split; keep anything longer lived in the protocol.
+/// The blocks of an `offer` or `choose` are handled apart even where
+/// their states are the same.
+///
+/// ```ignore
+/// session! {
+///     Doubler(u64) = |this| {
+///         loop {
+///             offer {
+///                 {
+///                     let num = recv::<u64>();
+///                     send::<u64>(num * 2);
+///                     continue;
+///                 },
+///                 {}
+///             }
+///         }
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! session {
+    (@type) => (End);
+    (@type let $x:pat = recv::<$t:ty>(); $($rest:tt)*) => (Recv<$t, session!(@type $($rest)*)>);
+    (@type send::<$t:ty>($e:expr); $($rest:tt)*) => (Send<$t, session!(@type $($rest)*)>);
+    (@type offer { $({ $($arm:tt)* }),* }) => (session!(@accept $({ $($arm)* })*));
+    (@type choose ($e:expr) { $($p:pat => { $($arm:tt)* })* }) => (session!(@choose $({ $($arm)* })*));
+    (@type loop { $($body:tt)* }) => (Nest<session!(@type $($body)*)>);
+    (@type continue;) => (Escape<Z>);
+    (@type continue $n:tt;) => (Escape<proto!(@peano $n)>);
+    (@type $s:stmt; $($rest:tt)*) => (session!(@type $($rest)*));
+    (@accept { $($arm:tt)* }) => (Finally<session!(@type $($arm)*)>);
+    (@accept { $($arm:tt)* } $($rest:tt)+) => (Accept<session!(@type $($arm)*), session!(@accept $($rest)+)>);
+    (@choose { $($arm:tt)* }) => (Finally<session!(@type $($arm)*)>);
+    (@choose { $($arm:tt)* } $($rest:tt)+) => (Choose<session!(@type $($arm)*), session!(@choose $($rest)+)>);
+    // An `offer` whose blocks are marked with their positions, so that
+    // each gets a handler of its own.
+    (@marked $n:ty; { $($arm:tt)* }) => (Finally<Mark<$n, session!(@type $($arm)*)>>);
+    (@marked $n:ty; { $($arm:tt)* } $($rest:tt)+) => (Accept<Mark<$n, session!(@type $($arm)*)>, session!(@marked S<$n>; $($rest)+)>);
+    // The variables in scope, which are carried over to the next handler.
+    (@stash $chan:ident []) => (());
+    (@stash $chan:ident [$($v:ident: $vt:ty),+]) => ($chan.stash(($($v,)+)));
+    (@unstash $chan:ident []) => ();
+    (@unstash $chan:ident [$($v:ident: $vt:ty),+]) => (
+        #[allow(unused_variables)]
+        let ($($v,)+): ($($vt,)+) = $chan.unstash();
+    );
+    // The body of a handler. Only the first statement of a handler may
+    // wait on the peer; later ones defer to a handler of their own.
+    (@code $chan:ident $mode:ident $vars:tt;) => ($chan.close());
+    (@code $chan:ident start [$($v:ident: $vt:ty),*]; let $x:ident = recv::<$t:ty>(); $($rest:tt)*) => (
+        match $chan.recv() {
+            Ok(($x, mut $chan)) => session!(@code $chan next [$($v: $vt,)* $x: $t]; $($rest)*),
+            Err(mut $chan) => {
+                session!(@stash $chan [$($v: $vt),*]);
+                $chan.defer()
+            }
+        }
+    );
+    (@code $chan:ident start $vars:tt; let $x:pat = recv::<$t:ty>(); $($rest:tt)*) => (
+        match $chan.recv() {
+            Ok(($x, mut $chan)) => session!(@code $chan next $vars; $($rest)*),
+            Err(mut $chan) => {
+                session!(@stash $chan $vars);
+                $chan.defer()
+            }
+        }
+    );
+    (@code $chan:ident next $vars:tt; let $x:pat = recv::<$t:ty>(); $($rest:tt)*) => ({
+        session!(@stash $chan $vars);
+        $chan.defer()
+    });
+    (@code $chan:ident $mode:ident [$($v:ident: $vt:ty),*]; let $x:ident: $t:ty = $e:expr; $($rest:tt)*) => ({
+        let $x: $t = $e;
+        session!(@code $chan next [$($v: $vt,)* $x: $t]; $($rest)*)
+    });
+    (@code $chan:ident $mode:ident $vars:tt; send::<$t:ty>($e:expr); $($rest:tt)*) => ({
+        let value = $e;
+        let mut $chan = $chan.send(value);
+        session!(@code $chan next $vars; $($rest)*)
+    });
+    (@code $chan:ident start $vars:tt; offer { $({ $($arm:tt)* }),* }) => ({
+        session!(@stash $chan $vars);
+        match unsafe { $chan.into_session::<session!(@marked Z; $({ $($arm)* })*)>() }.accept() {
+            Ok(d) => d,
+            Err($chan) => unsafe { $chan.into_session::<session!(@accept $({ $($arm)* })*)>() }.defer()
+        }
+    });
+    (@code $chan:ident next $vars:tt; offer { $($arms:tt)* }) => ({
+        session!(@stash $chan $vars);
+        $chan.defer()
+    });
+    (@code $chan:ident $mode:ident $vars:tt; choose ($e:expr) { $($p:pat => { $($arm:tt)* })* }) => (
+        match $e {
+            $($p => {
+                let mut $chan = $chan.choose::<session!(@type $($arm)*)>().mark::<session!(@type $($arm)*)>();
+                session!(@code $chan next $vars; $($arm)*)
+            })*
+        }
+    );
+    (@code $chan:ident $mode:ident $vars:tt; loop { $($body:tt)* }) => ($chan.enter().defer());
+    (@code $chan:ident $mode:ident $vars:tt; continue;) => ($chan.pop().defer());
+    (@code $chan:ident $mode:ident $vars:tt; continue $n:tt;) => ($chan.pop().defer());
+    (@code $chan:ident $mode:ident $vars:tt; $s:stmt; $($rest:tt)*) => ({
+        $s;
+        session!(@code $chan next $vars; $($rest)*)
+    });
+    // The handler for a block of statements, and those for any states
+    // its handler defers to.
+    (@block $protocol:ident $bounds:tt $env:ty; $chan:ident $vars:tt; $($stmts:tt)*) => (
+        session!(@line_handler $protocol $bounds $env; session!(@type $($stmts)*); $chan $vars {
+            session!(@code $chan start $vars; $($stmts)*)
+        });
+        session!(@scan $protocol $bounds $env; $chan start $vars; $($stmts)*);
+    );
+    (@scan $protocol:ident $bounds:tt $env:ty; $chan:ident $mode:ident $vars:tt;) => ();
+    (@scan $protocol:ident $bounds:tt $env:ty; $chan:ident start [$($v:ident: $vt:ty),*]; let $x:ident = recv::<$t:ty>(); $($rest:tt)*) => (
+        session!(@scan $protocol $bounds $env; $chan next [$($v: $vt,)* $x: $t]; $($rest)*);
+    );
+    (@scan $protocol:ident $bounds:tt $env:ty; $chan:ident start $vars:tt; let $x:pat = recv::<$t:ty>(); $($rest:tt)*) => (
+        session!(@scan $protocol $bounds $env; $chan next $vars; $($rest)*);
+    );
+    (@scan $protocol:ident $bounds:tt $env:ty; $chan:ident next $vars:tt; let $x:ident = recv::<$t:ty>(); $($rest:tt)*) => (
+        session!(@block $protocol $bounds $env; $chan $vars; let $x = recv::<$t>(); $($rest)*);
+    );
+    (@scan $protocol:ident $bounds:tt $env:ty; $chan:ident next $vars:tt; let $x:pat = recv::<$t:ty>(); $($rest:tt)*) => (
+        session!(@block $protocol $bounds $env; $chan $vars; let $x = recv::<$t>(); $($rest)*);
+    );
+    (@scan $protocol:ident $bounds:tt $env:ty; $chan:ident $mode:ident [$($v:ident: $vt:ty),*]; let $x:ident: $t:ty = $e:expr; $($rest:tt)*) => (
+        session!(@scan $protocol $bounds $env; $chan next [$($v: $vt,)* $x: $t]; $($rest)*);
+    );
+    (@scan $protocol:ident $bounds:tt $env:ty; $chan:ident $mode:ident $vars:tt; send::<$t:ty>($e:expr); $($rest:tt)*) => (
+        session!(@scan $protocol $bounds $env; $chan next $vars; $($rest)*);
+    );
+    (@scan $protocol:ident $bounds:tt $env:ty; $chan:ident start $vars:tt; offer { $({ $($arm:tt)* }),* }) => (
+        session!(@arms $protocol $bounds $env; $chan $vars; Z; $({ $($arm)* })*);
+    );
+    (@scan $protocol:ident $bounds:tt $env:ty; $chan:ident next $vars:tt; offer { $($arms:tt)* }) => (
+        session!(@block $protocol $bounds $env; $chan $vars; offer { $($arms)* });
+    );
+    (@scan $protocol:ident $bounds:tt $env:ty; $chan:ident $mode:ident $vars:tt; choose ($e:expr) { $($p:pat => { $($arm:tt)* })* }) => (
+        $(session!(@scan $protocol $bounds Mark<session!(@type $($arm)*), $env>; $chan next $vars; $($arm)*);)*
+    );
+    (@scan $protocol:ident $bounds:tt $env:ty; $chan:ident $mode:ident $vars:tt; loop { $($body:tt)* }) => (
+        session!(@block $protocol $bounds (session!(@type $($body)*), $env); $chan []; $($body)*);
+    );
+    (@scan $protocol:ident $bounds:tt $env:ty; $chan:ident $mode:ident $vars:tt; continue;) => ();
+    (@scan $protocol:ident $bounds:tt $env:ty; $chan:ident $mode:ident $vars:tt; continue $n:tt;) => ();
+    (@scan $protocol:ident $bounds:tt $env:ty; $chan:ident $mode:ident $vars:tt; $s:stmt; $($rest:tt)*) => (
+        session!(@scan $protocol $bounds $env; $chan next $vars; $($rest)*);
+    );
+    // The blocks of an `offer`, each handled in the environment marked
+    // with its position, which `accept` reaches through its marked state.
+    (@arms $protocol:ident $bounds:tt $env:ty; $chan:ident $vars:tt; $n:ty;) => ();
+    (@arms $protocol:ident $bounds:tt $env:ty; $chan:ident $vars:tt; $n:ty; { $($arm:tt)* } $($rest:tt)*) => (
+        session!(@arm_handler $protocol $bounds $env; $n; session!(@type $($arm)*));
+        session!(@block $protocol $bounds Mark<$n, $env>; $chan $vars; $($arm)*);
+        session!(@arms $protocol $bounds $env; $chan $vars; S<$n>; $($rest)*);
+    );
+    (@arm_handler $protocol:ident ($($impl_bound:ty),*) $env:ty; $n:ty; $state:ty) => (
+        impl<I: IO, E> Handler<I, $env, Mark<$n, $state>> for $protocol
+            where $(I: Transfers<$impl_bound>, )* E: SessionType
+        {
+            fn with(this: Channel<Self, I, $env, Mark<$n, $state>>) -> Defer<Self, I> {
+                <Self as Handler<I, Mark<$n, $env>, $state>>::with(this.unmark())
+            }
+        }
+    );
+    (@line_handler $protocol:ident ($($impl_bound:ty),*) $env:ty; $state:ty; $chan:ident $vars:tt $b:block) => (
+        impl<I: IO, E> Handler<I, $env, $state> for $protocol
+            where $(I: Transfers<$impl_bound>, )* E: SessionType
+        {
+            #[allow(unused_mut)]
+            fn with(mut $chan: Channel<Self, I, $env, $state>) -> Defer<Self, I> {
+                session!(@unstash $chan $vars);
+                $b
+            }
+        }
+    );
+    (@handler $protocol:ident ($($impl_bound:ty),*) $env:ty; $state:ty; $chan:ident $b:block) => (
+        impl<I: IO, E> Handler<I, $env, $state> for $protocol
+            where $(I: Transfers<$impl_bound>, )* E: SessionType
+        {
+            fn with($chan: Channel<Self, I, $env, $state>) -> Defer<Self, I> {
+                $b
+            }
+        }
+    );
+    (@states $protocol:ident $bounds:tt $env:ty;) => ();
+    (@states $protocol:ident $bounds:tt $env:ty; loop $name:ident = $s:tt { $($inner:tt)* } $($rest:tt)*) => (
+        type $name = proto!($s);
+        session!(@states $protocol $bounds ($name, $env); $($inner)*);
+        session!(@states $protocol $bounds $env; $($rest)*);
+    );
+    (@states $protocol:ident $bounds:tt $env:ty; $name:ident = $s:tt => |$chan:ident| $b:block $($rest:tt)*) => (
+        type $name = proto!($s);
+        session!(@handler $protocol $bounds $env; $name; $chan $b);
+        session!(@states $protocol $bounds $env; $($rest)*);
+    );
+    (@states $protocol:ident $bounds:tt $env:ty; $name:ident = $s:tt; $($rest:tt)*) => (
+        type $name = proto!($s);
+        session!(@states $protocol $bounds $env; $($rest)*);
+    );
+    (@states $protocol:ident $bounds:tt $env:ty; $state:ident => |$chan:ident| $b:block $($rest:tt)*) => (
+        session!(@handler $protocol $bounds $env; $state; $chan $b);
+        session!(@states $protocol $bounds $env; $($rest)*);
+    );
+    (@states $protocol:ident $bounds:tt $env:ty; $state:tt => |$chan:ident| $b:block $($rest:tt)*) => (
+        session!(@handler $protocol $bounds $env; proto!($state); $chan $b);
+        session!(@states $protocol $bounds $env; $($rest)*);
+    );
+    ($protocol:ident ($($impl_bound:ty),*) = |$chan:ident| { $($stmts:tt)* }) => (
+        impl Protocol for $protocol {
+            type Initial = session!(@type $($stmts)*);
+        }
+
+        session!(@block $protocol ($($impl_bound),*) E; $chan []; $($stmts)*);
+    );
+    ($protocol:ident ($($impl_bound:ty),*) = $initial:ty; $($rest:tt)*) => (
+        impl Protocol for $protocol {
+            type Initial = $initial;
+        }
+
+        session!(@states $protocol ($($impl_bound),*) E; $($rest)*);
+    );
+}
+
 /// This trait is implemented by backing IO structures to offer an
 /// interface for bi-directional channels. Discriminants are sent
 /// and received by `Channel` to indicate protocol changes; they
@@ -108,12 +566,19 @@ pub unsafe trait IO {
     unsafe fn close(&mut self);
 
     /// Send a discriminant over the channel. Over a network a
-    /// variable length integer would be ideal.
+    /// variable length integer would be ideal. `usize::MAX` is reserved
+    /// for raising an exception in a `Try`, so it must get through too.
     unsafe fn send_discriminant(&mut self, usize);
 
     /// Receives a discriminant from the channel. Over a network a
     /// variable length integer would be ideal.
     unsafe fn recv_discriminant(&mut self) -> Option<usize>;
+
+    /// Writes out anything buffered by earlier operations. Backends may
+    /// hold outgoing data until this is called; `Channel` flushes before
+    /// it waits on the peer, and when the handler defers or closes. By
+    /// default nothing is buffered.
+    unsafe fn flush(&mut self) { }
 }
 
 /// An implementation of this trait provides sending and receiving
@@ -123,6 +588,7 @@ pub unsafe trait IO {
 /// accessed.
 ///
 /// See the explanation on `IO` for more details.
+#[rustc_on_unimplemented(message="`{Self}` cannot transfer a `{T}`", label="cannot transfer this type")]
 pub unsafe trait Transfers<T>: IO {
     /// Sends an object from the handler to the outside channel.
     unsafe fn send(&mut self, T);
@@ -130,4 +596,67 @@ pub unsafe trait Transfers<T>: IO {
     /// Attempts to retrieve an object from the outside channel. This *can* block
     /// but it also might not, depending on the impl.
     unsafe fn recv(&mut self) -> Option<T>;
-}
\ No newline at end of file
+
+    /// The number of bytes `obj` occupies on the channel, if the backend
+    /// knows. Backends which move values without encoding them return
+    /// `None`.
+    fn size_of(&self, _: &T) -> Option<usize> {
+        None
+    }
+}
+
+/// Like `Transfers`, but sends a borrowed `T`, which may be unsized.
+/// Byte backends serialize straight from the caller's memory, and
+/// backends which move values send an owned copy. The peer receives
+/// `T`'s owned form, so a `Recv<[u8], S>` yields a `Vec<u8>` and a
+/// `Recv<str, S>` yields a `String`.
+#[rustc_on_unimplemented(message="`{Self}` cannot transfer a borrowed `{T}`", label="cannot transfer this type")]
+pub unsafe trait TransfersRef<T: ?Sized>: IO {
+    /// Sends a borrowed object from the handler to the outside channel.
+    unsafe fn send_ref(&mut self, &T);
+
+    /// The number of bytes `obj` occupies on the channel, if the backend
+    /// knows.
+    fn size_of_ref(&self, _: &T) -> Option<usize> {
+        None
+    }
+}
+
+/// The outcome of waiting for the peer against a deadline.
+pub enum Wait<T> {
+    /// It arrived in time.
+    Arrived(T),
+    /// Nothing yet, but there is still time.
+    Pending,
+    /// The deadline passed.
+    Expired,
+    /// The peer hung up, so it will never arrive.
+    Closed
+}
+
+/// Backends which can tell whether the peer kept a deadline, for `Timed`.
+pub unsafe trait Timer: IO {
+    /// Receive a discriminant which must arrive within `limit` of the
+    /// first attempt to receive it. Backends which block wait at most
+    /// until the deadline.
+    unsafe fn recv_discriminant_within(&mut self, limit: ::std::time::Duration) -> Wait<usize>;
+
+    /// The peer's next discriminant missed its deadline and will arrive
+    /// anyway; drop it when it does.
+    unsafe fn skip_discriminant(&mut self);
+}
+
+/// Backends which can carry two further sessions alongside the one they
+/// already carry, for `Par`. Both sides split at the same point of the
+/// session, and the halves they return correspond in order.
+#[rustc_on_unimplemented(message="`{Self}` cannot be split into parallel sessions", label="cannot be split")]
+pub unsafe trait Split: IO {
+    /// The backend of each half.
+    type Half: IO;
+
+    /// Open the two sub-streams. Returns `None` if the peer hung up, or
+    /// if its side of the split hasn't arrived yet on a backend which
+    /// doesn't block. Nothing is consumed in the latter case, so the
+    /// split can be tried again.
+    unsafe fn split(&mut self) -> Option<(Self::Half, Self::Half)>;
+}
@@ -0,0 +1,203 @@
+//! Optional session metrics, enabled with the `metrics` feature. Every
+//! `Defer`, `choose`, `accept` and `close` is counted against its
+//! protocol and session type, and a `Snapshot` of the counters can be
+//! exported in the Prometheus text format.
+//!
+//! * `nemo_sessions_deferred` is the number of sessions currently
+//!   deferred in each state.
+//! * `nemo_deferred_seconds` is a histogram of how long sessions sat
+//!   deferred in each state before being resumed.
+//! * `nemo_branches_total` counts how often each branch of a `Choose`
+//!   or `Accept` was taken.
+//! * `nemo_sessions_closed_total` counts closed sessions.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::path::Path;
+use std::sync::{Mutex, Once, ONCE_INIT};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use type_name;
+
+/// Upper bounds, in seconds, of the `nemo_deferred_seconds` buckets.
+pub const BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 10.0];
+
+/// The distribution of time spent deferred in one state.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Histogram {
+    /// Cumulative counts for each of `BUCKETS`.
+    pub buckets: [u64; 8],
+    pub count: u64,
+    pub sum: f64
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        for (bound, bucket) in BUCKETS.iter().zip(self.buckets.iter_mut()) {
+            if secs <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+}
+
+/// Which side of a branch point a branch was taken from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Side {
+    Choose,
+    Accept
+}
+
+/// A copy of all counters at one point in time. Protocols and states
+/// are identified by their type names.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    pub deferred: BTreeMap<(&'static str, &'static str), i64>,
+    pub deferred_seconds: BTreeMap<(&'static str, &'static str), Histogram>,
+    pub branches: BTreeMap<(&'static str, &'static str, Side, usize), u64>,
+    pub closed: BTreeMap<&'static str, u64>
+}
+
+static INIT: Once = ONCE_INIT;
+static mut REGISTRY: *const Mutex<Snapshot> = 0 as *const Mutex<Snapshot>;
+
+fn with_registry<F: FnOnce(&mut Snapshot)>(f: F) {
+    // the registry is allocated on first use and never freed
+    let registry = unsafe {
+        INIT.call_once(|| REGISTRY = Box::into_raw(Box::new(Mutex::new(Snapshot::default()))));
+        &*REGISTRY
+    };
+
+    // a panicking handler must not disable metrics for everyone else
+    let mut registry = match registry.lock() {
+        Ok(registry) => registry,
+        Err(poisoned) => poisoned.into_inner()
+    };
+
+    f(&mut registry)
+}
+
+#[doc(hidden)]
+pub fn deferred(protocol: &'static str, state: &'static str) {
+    with_registry(|r| *r.deferred.entry((protocol, state)).or_insert(0) += 1);
+}
+
+#[doc(hidden)]
+pub fn resumed(protocol: &'static str, state: &'static str, waited: Duration) {
+    let secs = waited.as_secs() as f64 + waited.subsec_nanos() as f64 / 1e9;
+
+    with_registry(|r| {
+        *r.deferred.entry((protocol, state)).or_insert(0) -= 1;
+        r.deferred_seconds.entry((protocol, state)).or_insert_with(Histogram::default).observe(secs);
+    });
+}
+
+#[doc(hidden)]
+pub fn dropped(protocol: &'static str, state: &'static str) {
+    with_registry(|r| *r.deferred.entry((protocol, state)).or_insert(0) -= 1);
+}
+
+#[doc(hidden)]
+pub fn branch(protocol: &'static str, state: &'static str, side: Side, num: usize) {
+    with_registry(|r| *r.branches.entry((protocol, state, side, num)).or_insert(0) += 1);
+}
+
+#[doc(hidden)]
+pub fn closed(protocol: &'static str) {
+    with_registry(|r| *r.closed.entry(protocol).or_insert(0) += 1);
+}
+
+/// The name under which the protocol or state `T` is counted.
+pub fn name<T: ?Sized>() -> &'static str {
+    type_name::<T>()
+}
+
+/// Take a copy of the current counters.
+pub fn snapshot() -> Snapshot {
+    let mut snapshot = Snapshot::default();
+    with_registry(|r| snapshot = r.clone());
+    snapshot
+}
+
+/// Clear all counters.
+pub fn reset() {
+    with_registry(|r| *r = Snapshot::default());
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+impl Snapshot {
+    /// Render the snapshot in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# TYPE nemo_sessions_deferred gauge\n");
+        for (&(protocol, state), value) in &self.deferred {
+            out.push_str(&format!("nemo_sessions_deferred{{protocol=\"{}\",state=\"{}\"}} {}\n",
+                                  escape(protocol), escape(state), value));
+        }
+
+        out.push_str("# TYPE nemo_deferred_seconds histogram\n");
+        for (&(protocol, state), hist) in &self.deferred_seconds {
+            let labels = format!("protocol=\"{}\",state=\"{}\"", escape(protocol), escape(state));
+            for (bound, count) in BUCKETS.iter().zip(hist.buckets.iter()) {
+                out.push_str(&format!("nemo_deferred_seconds_bucket{{{},le=\"{}\"}} {}\n", labels, bound, count));
+            }
+            out.push_str(&format!("nemo_deferred_seconds_bucket{{{},le=\"+Inf\"}} {}\n", labels, hist.count));
+            out.push_str(&format!("nemo_deferred_seconds_sum{{{}}} {}\n", labels, hist.sum));
+            out.push_str(&format!("nemo_deferred_seconds_count{{{}}} {}\n", labels, hist.count));
+        }
+
+        out.push_str("# TYPE nemo_branches_total counter\n");
+        for (&(protocol, state, side, num), value) in &self.branches {
+            out.push_str(&format!("nemo_branches_total{{protocol=\"{}\",state=\"{}\",side=\"{}\",branch=\"{}\"}} {}\n",
+                                  escape(protocol), escape(state),
+                                  match side { Side::Choose => "choose", Side::Accept => "accept" },
+                                  num, value));
+        }
+
+        out.push_str("# TYPE nemo_sessions_closed_total counter\n");
+        for (&protocol, value) in &self.closed {
+            out.push_str(&format!("nemo_sessions_closed_total{{protocol=\"{}\"}} {}\n", escape(protocol), value));
+        }
+
+        out
+    }
+
+    /// Write the snapshot to `path` in the Prometheus text format, e.g.
+    /// for the node exporter's textfile collector.
+    pub fn write_to<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = try!(File::create(path));
+        file.write_all(self.to_prometheus().as_bytes())
+    }
+}
+
+/// Serve the current metrics over HTTP on `addr` from a background
+/// thread. Every request is answered with a fresh snapshot.
+pub fn serve<A: ToSocketAddrs>(addr: A) -> io::Result<JoinHandle<()>> {
+    let listener = try!(TcpListener::bind(addr));
+
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue
+            };
+
+            // we answer every request the same way, so only drain it
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf);
+
+            let body = snapshot().to_prometheus();
+            let _ = write!(stream, "HTTP/1.0 200 OK\r\n\
+                                    Content-Type: text/plain; version=0.0.4\r\n\
+                                    Content-Length: {}\r\n\r\n{}", body.len(), body);
+        }
+    }))
+}
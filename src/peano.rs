@@ -3,21 +3,29 @@
 //! you must indicate the number of layers you wish to escape from.
 
 use std::marker::PhantomData;
-use session_types::SessionType;
+use session_types::{SessionType, Mark};
 
 /// Represents a peano number.
-pub unsafe trait Peano {}
+pub unsafe trait Peano {
+    /// The number this type represents.
+    fn to_usize() -> usize;
+}
 
 /// Peano numbers: Zero
 pub struct Z;
-unsafe impl Peano for Z {}
+unsafe impl Peano for Z {
+    fn to_usize() -> usize { 0 }
+}
 
 /// Peano numbers: Increment
 pub struct S<N> ( PhantomData<N> );
-unsafe impl<N> Peano for S<N> {}
+unsafe impl<N: Peano> Peano for S<N> {
+    fn to_usize() -> usize { N::to_usize() + 1 }
+}
 
 /// This represents the types obtained by popping N layers from
 /// a stack.
+#[rustc_on_unimplemented(message="cannot escape `{N}` levels out of environment `{Self}`", label="escapes too far")]
 pub trait Pop<N: Peano> {
     type Head: SessionType;
     type Tail: SessionType;
@@ -31,4 +39,9 @@ impl<A: SessionType, B: SessionType> Pop<Z> for (A, B) {
 impl<N: Peano, A, B: Pop<N>> Pop<S<N>> for (A, B) {
     type Head = B::Head;
     type Tail = B::Tail;
-}
\ No newline at end of file
+}
+
+impl<N: Peano, T, E: Pop<N>> Pop<N> for Mark<T, E> {
+    type Head = E::Head;
+    type Tail = E::Tail;
+}
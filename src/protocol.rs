@@ -1,8 +1,16 @@
+use std::any::Any;
 use std::marker::PhantomData;
 use std::mem;
+use type_name;
+#[cfg(feature = "metrics")]
+use std::time::Instant;
+#[cfg(feature = "metrics")]
+use metrics;
+use checkpoint::{self, Snapshot};
 use session_types::*;
 use peano::{Peano,Pop};
-use super::{IO, Transfers};
+use wire::{self, Encode};
+use super::{IO, Split, Timer, Transfers, TransfersRef, Wait};
 
 /// A `Protocol` describes the underlying protocol, including the "initial" session
 /// type. `Handler`s are defined over concrete `Protocol`s to implement the behavior
@@ -11,6 +19,17 @@ pub trait Protocol {
     type Initial: SessionType;
 }
 
+// The discriminant sent to raise an exception, in place of whatever
+// the peer expected to receive.
+const RAISED: usize = ::std::usize::MAX;
+
+// The discriminant which announces a payload inside a `Try`.
+const CARRY_ON: usize = 0;
+
+// The verdicts sent back for a `Due`.
+const IN_TIME: usize = 0;
+const TIMED_OUT: usize = 1;
+
 /// Handlers must return `Defer` to indicate to the `Session` how to proceed in
 /// the future. `Defer` can be obtained by calling `.defer()` on the channel, or
 /// by calling `.close()` when the session is `End`.
@@ -19,6 +38,14 @@ pub struct Defer<P: Protocol, I> {
     proto: Option<P>,
     func: DeferFunc<P, I, (), ()>,
     open: bool,
+    ahead: Option<usize>,
+    locals: Option<Box<Any + ::std::marker::Send>>,
+    // identifies the state `func` handles, for checkpoints
+    state_id: fn() -> u64,
+    #[cfg(feature = "metrics")]
+    state: &'static str,
+    #[cfg(feature = "metrics")]
+    since: Instant,
     _marker: PhantomData<P>
 }
 
@@ -27,11 +54,27 @@ impl<P: Protocol, I> Defer<P, I> {
     pub fn new<X: SessionType, Y: SessionType>(chan: Channel<P, I, X, Y>, next: DeferFunc<P, I, (), ()>, open: bool)
                -> Defer<P, I>
     {
+        #[cfg(feature = "metrics")]
+        {
+            if open {
+                metrics::deferred(type_name::<P>(), type_name::<Y>());
+            } else {
+                metrics::closed(type_name::<P>());
+            }
+        }
+
         Defer {
             io: Some(chan.io),
             proto: Some(chan.proto),
             func: next,
             open: open,
+            ahead: chan.ahead,
+            locals: chan.locals,
+            state_id: checkpoint::state_id::<X, Y>,
+            #[cfg(feature = "metrics")]
+            state: type_name::<Y>(),
+            #[cfg(feature = "metrics")]
+            since: Instant::now(),
             _marker: PhantomData
         }
     }
@@ -39,18 +82,79 @@ impl<P: Protocol, I> Defer<P, I> {
 
 impl<P: Protocol, I> Defer<P, I> {
     pub fn with(&mut self) -> bool {
-        let p: Channel<P, I, (), ()> = Channel::new(self.io.take().unwrap(), self.proto.take().unwrap());
+        #[cfg(feature = "metrics")]
+        metrics::resumed(type_name::<P>(), self.state, self.since.elapsed());
+
+        let mut p: Channel<P, I, (), ()> = Channel::new(self.io.take().unwrap(), self.proto.take().unwrap());
+        p.ahead = self.ahead.take();
+        p.locals = self.locals.take();
 
         let mut new = (self.func)(p);
         self.func = new.func;
         self.open = new.open;
+        self.ahead = new.ahead.take();
+        self.locals = new.locals.take();
+        self.state_id = new.state_id;
         self.io = Some(new.io.take().unwrap());
         self.proto = Some(new.proto.take().unwrap());
 
+        #[cfg(feature = "metrics")]
+        {
+            self.state = new.state;
+            self.since = new.since;
+            // we now account for the new state; `new` must not on drop
+            new.open = false;
+        }
+
         self.open
     }
 }
 
+impl<P: Protocol + Encode, I> Defer<P, I> {
+    /// Save the state the session is paused in along with the protocol's
+    /// value, so that a `Registry` can resume it over a new IO after a
+    /// restart. Returns `None` once the session has closed, and while it
+    /// holds the variables of a `session!` handler, which can't be saved.
+    pub fn checkpoint(&self) -> Option<Snapshot> {
+        if !self.open || self.locals.is_some() {
+            return None;
+        }
+
+        self.proto.as_ref().map(|proto| {
+            Snapshot {
+                protocol: checkpoint::fingerprint::<P>(),
+                state: (self.state_id)(),
+                proto: wire::to_bytes(proto),
+                ahead: self.ahead
+            }
+        })
+    }
+}
+
+impl<P: Protocol, I: IO> Defer<P, I> {
+    #[doc(hidden)]
+    /// Pause a session over `io` in the state `S`, in the environment `E`,
+    /// holding `ahead` as received ahead of it. This is unsafe because the
+    /// peer must be paused in the dual state.
+    pub unsafe fn restore<E: SessionType, S: SessionType>(io: I, proto: P, ahead: Option<usize>) -> Defer<P, I>
+        where P: Handler<I, E, S>
+    {
+        let mut chan = Channel::<P, I, E, S>::new(io, proto);
+        chan.ahead = ahead;
+
+        chan.defer()
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl<P: Protocol, I> Drop for Defer<P, I> {
+    fn drop(&mut self) {
+        if self.open {
+            metrics::dropped(type_name::<P>(), self.state);
+        }
+    }
+}
+
 #[doc(hidden)]
 pub type DeferFunc<P, I, E, S> = fn(Channel<P, I, E, S>) -> Defer<P, I>;
 
@@ -59,6 +163,10 @@ pub type DeferFunc<P, I, E, S> = fn(Channel<P, I, E, S>) -> Defer<P, I>;
 pub struct Channel<P: Protocol, I, E: SessionType, S: SessionType> {
     io: I,
     pub proto: P,
+    // a discriminant `catch` received ahead of the operation which needs it
+    ahead: Option<usize>,
+    // the variables `session!` carries over to the next handler
+    locals: Option<Box<Any + ::std::marker::Send>>,
     _marker: PhantomData<(P, E, S)>
 }
 
@@ -70,9 +178,66 @@ impl<P: Protocol, I, E: SessionType, S: SessionType> Channel<P, I, E, S> {
         Channel {
             io: self.io,
             proto: self.proto,
+            ahead: self.ahead,
+            locals: self.locals,
+            _marker: PhantomData
+        }
+    }
+
+    #[doc(hidden)]
+    /// Reinterpret both the environment and the session type. This is
+    /// unsafe for the same reasons as `into_session`.
+    pub unsafe fn into_state<F: SessionType, N: SessionType>(self) -> Channel<P, I, F, N> {
+        Channel {
+            io: self.io,
+            proto: self.proto,
+            ahead: self.ahead,
+            locals: self.locals,
             _marker: PhantomData
         }
     }
+
+    #[doc(hidden)]
+    /// Direct access to the IO backend, for drivers which walk a session
+    /// type themselves. Using it to perform operations the session type
+    /// doesn't allow desynchronizes the channel.
+    pub unsafe fn io_mut(&mut self) -> &mut I {
+        &mut self.io
+    }
+
+    #[doc(hidden)]
+    /// Set `values` aside for the handler this channel is deferred to.
+    /// `session!` carries its variables from one handler to the next
+    /// with it.
+    pub fn stash<T: Any + ::std::marker::Send>(&mut self, values: T) {
+        self.locals = Some(Box::new(values));
+    }
+
+    #[doc(hidden)]
+    /// Take back what the previous handler set aside.
+    pub fn unstash<T: Any + ::std::marker::Send>(&mut self) -> T {
+        match self.locals.take().map(|locals| locals.downcast::<T>()) {
+            Some(Ok(values)) => *values,
+            _ => panic!("the previous handler didn't set aside a `{}`", type_name::<T>())
+        }
+    }
+
+    #[doc(hidden)]
+    /// Continue in the environment `E` marked with `T`.
+    pub fn mark<T>(self) -> Channel<P, I, Mark<T, E>, S> {
+        self.advance()
+    }
+}
+
+impl<P: Protocol, I, E: SessionType, S: SessionType> Channel<P, I, E, S> {
+    /// Continue as a session of type `T`, which must be a supertype of
+    /// the current one. A newer handler can use this to serve an older
+    /// peer: accepting more branches than the peer knows of, or choosing
+    /// among fewer than the protocol allows. Branches keep their
+    /// discriminants, so nothing is sent.
+    pub fn upcast<T: SessionType>(self) -> Channel<P, I, E, T> where S: Subtype<T> {
+        self.advance()
+    }
 }
 
 impl<P: Protocol, I, E: SessionType, S: SessionType> Channel<P, I, E, S> {
@@ -80,6 +245,19 @@ impl<P: Protocol, I, E: SessionType, S: SessionType> Channel<P, I, E, S> {
         Channel {
             io: io,
             proto: proto,
+            ahead: None,
+            locals: None,
+            _marker: PhantomData
+        }
+    }
+
+    // Move on to the next state, keeping anything received ahead of it.
+    fn advance<F: SessionType, N: SessionType>(self) -> Channel<P, I, F, N> {
+        Channel {
+            io: self.io,
+            proto: self.proto,
+            ahead: self.ahead,
+            locals: self.locals,
             _marker: PhantomData
         }
     }
@@ -87,6 +265,7 @@ impl<P: Protocol, I, E: SessionType, S: SessionType> Channel<P, I, E, S> {
 
 /// `Handler` is implemented on `Protocol` for every session type you expect to defer,
 /// including the initial state.
+#[rustc_on_unimplemented(message="no Handler for state `{S}` in environment `{E}`", label="no Handler for this state")]
 pub trait Handler<I, E: SessionType, S: SessionType>: Protocol + Sized {
     /// Given a channel in a particular state, with a particular environment,
     /// do whatever you'd like with the channel and return `Defer`, which you
@@ -95,29 +274,98 @@ pub trait Handler<I, E: SessionType, S: SessionType>: Protocol + Sized {
     fn with(Channel<Self, I, E, S>) -> Defer<Self, I>;
 }
 
-pub fn channel<P: Protocol, I: IO>(io: I, proto: P) -> Channel<P, I, (), P::Initial> {
+pub fn channel<P: Protocol, I: IO>(io: I, proto: P) -> Channel<P, I, (), P::Initial>
+    where P::Initial: WellFormed
+{
     Channel::new(io, proto)
 }
 
-pub fn channel_dual<P: Protocol, I: IO>(io: I, proto: P) -> Channel<P, I, (), <P::Initial as SessionType>::Dual> {
+pub fn channel_dual<P: Protocol, I: IO>(io: I, proto: P) -> Channel<P, I, (), <P::Initial as SessionType>::Dual>
+    where <P::Initial as SessionType>::Dual: WellFormed
+{
     Channel::new(io, proto)
 }
 
-impl<I, E: SessionType, S: SessionType, P: Handler<I, E, S>> Channel<P, I, E, S> {
+// The bounds of these methods are on the methods themselves rather than
+// their impls, so that a missing `Handler` or `Transfers` is reported with
+// that trait's own message instead of as an unknown method.
+impl<I: IO, E: SessionType, S: SessionType, P: Protocol> Channel<P, I, E, S> {
     /// Defer the rest of the protocol execution. Useful for returning early.
+    /// Anything the backend buffered is flushed first.
     /// 
     /// There must be a [`Handler`](trait.Handler.html) implemented for the protocol state you're deferring.
-    pub fn defer(self) -> Defer<P, I> {
+    pub fn defer(mut self) -> Defer<P, I> where P: Handler<I, E, S> {
+        unsafe { self.io.flush() };
+
         let next_func: DeferFunc<P, I, E, S> = Handler::<I, E, S>::with;
 
         Defer::new(self, unsafe { mem::transmute(next_func) }, true)
     }
 }
 
+impl<I: IO, E: SessionType, S: SessionType, P: Protocol> Channel<P, I, E, S> {
+    // There is no state to continue in, because the peer sent something
+    // the session type doesn't allow or hung up; close the channel.
+    fn hang_up(mut self) -> Defer<P, I> {
+        unsafe {
+            self.io.flush();
+            self.io.close();
+        }
+
+        let next_func: DeferFunc<P, I, E, S> = Dummy::<P, I, E, S>::with;
+
+        Defer::new(self, unsafe { mem::transmute(next_func) }, false)
+    }
+
+    // Receive a discriminant, or take the one `catch` already received.
+    unsafe fn next_discriminant(&mut self) -> Option<usize> {
+        match self.ahead.take() {
+            Some(num) => Some(num),
+            None => self.io.recv_discriminant()
+        }
+    }
+
+    // Inside a `Try`, tell the peer that a payload follows rather than an
+    // exception.
+    unsafe fn announce(&mut self) {
+        if E::in_try() {
+            self.io.send_discriminant(CARRY_ON);
+        }
+    }
+
+    // Inside a `Try`, receive the announcement of a payload. Returns false
+    // if it hasn't arrived; anything else, such as an exception which
+    // hasn't been caught, is kept, so that the channel doesn't fall out
+    // of step with the peer.
+    unsafe fn announced(&mut self) -> bool {
+        if !E::in_try() {
+            return true;
+        }
+
+        match self.next_discriminant() {
+            Some(CARRY_ON) => true,
+            other => {
+                self.ahead = other;
+                false
+            }
+        }
+    }
+
+    // The payload hasn't arrived, so keep its announcement for next time.
+    unsafe fn unannounce(&mut self) {
+        if E::in_try() {
+            self.ahead = Some(CARRY_ON);
+        }
+    }
+}
+
 impl<I: IO, E: SessionType, P: Protocol> Channel<P, I, E, End> {
     /// Close the channel. Only possible if it's in the `End` state.
     pub fn close(mut self) -> Defer<P, I> {
-        unsafe { self.io.close() };
+        unsafe {
+            self.io.flush();
+            self.io.close();
+        }
 
         let next_func: DeferFunc<P, I, E, End> = Dummy::<P, I, E, End>::with;
 
@@ -125,47 +373,317 @@ impl<I: IO, E: SessionType, P: Protocol> Channel<P, I, E, End> {
     }
 }
 
-impl<I: Transfers<T>, T, E: SessionType, S: SessionType, P: Protocol> Channel<P, I, E, Send<T, S>> {
+impl<I: IO, T, E: SessionType, S: SessionType, P: Protocol> Channel<P, I, E, Send<T, S>> {
     /// Send a `T` to IO.
-    pub fn send(mut self, a: T) -> Channel<P, I, E, S> {
-        unsafe { self.io.send(a) };
+    pub fn send(mut self, a: T) -> Channel<P, I, E, S> where I: Transfers<T> {
+        unsafe {
+            self.announce();
+            self.io.send(a);
+        }
 
-        Channel::new(self.io, self.proto)
+        self.advance()
     }
 }
 
-impl<I: Transfers<T>, T, E: SessionType, S: SessionType, P: Protocol> Channel<P, I, E, Recv<T, S>> {
+impl<I: IO, T, E: SessionType, S: SessionType, P: Protocol> Channel<P, I, E, Recv<T, S>> {
     /// Receive a `T` from IO.
-    pub fn recv(mut self) -> Result<(T, Channel<P, I, E, S>), Self> {
+    pub fn recv(mut self) -> Result<(T, Channel<P, I, E, S>), Self> where I: Transfers<T> {
+        unsafe { self.io.flush() };
+
+        if !unsafe { self.announced() } {
+            return Err(self);
+        }
+
+        match unsafe { self.io.recv() } {
+            Some(res) => Ok((res, self.advance())),
+            None => {
+                unsafe { self.unannounce() };
+                Err(self)
+            }
+        }
+    }
+}
+
+impl<I: IO, T: ?Sized, E: SessionType, S: SessionType, P: Protocol> Channel<P, I, E, Send<T, S>> {
+    /// Send a borrowed `T` to IO, without giving up ownership of it.
+    pub fn send_ref(mut self, a: &T) -> Channel<P, I, E, S> where I: TransfersRef<T> {
+        unsafe {
+            self.announce();
+            self.io.send_ref(a);
+        }
+
+        self.advance()
+    }
+}
+
+impl<I: Transfers<Vec<u8>>, E: SessionType, S: SessionType, P: Protocol> Channel<P, I, E, Recv<[u8], S>> {
+    /// Receive bytes from IO.
+    pub fn recv(mut self) -> Result<(Vec<u8>, Channel<P, I, E, S>), Self> {
+        unsafe { self.io.flush() };
+
+        if !unsafe { self.announced() } {
+            return Err(self);
+        }
+
+        match unsafe { self.io.recv() } {
+            Some(res) => Ok((res, self.advance())),
+            None => {
+                unsafe { self.unannounce() };
+                Err(self)
+            }
+        }
+    }
+}
+
+impl<I: Transfers<String>, E: SessionType, S: SessionType, P: Protocol> Channel<P, I, E, Recv<str, S>> {
+    /// Receive a string from IO.
+    pub fn recv(mut self) -> Result<(String, Channel<P, I, E, S>), Self> {
+        unsafe { self.io.flush() };
+
+        if !unsafe { self.announced() } {
+            return Err(self);
+        }
+
         match unsafe { self.io.recv() } {
-            Some(res) => Ok((res, Channel::new(self.io, self.proto))),
+            Some(res) => Ok((res, self.advance())),
             None => {
+                unsafe { self.unannounce() };
                 Err(self)
             }
         }
     }
 }
 
+impl<I: Transfers<T>, T, E: SessionType, S: SessionType, P: Protocol> Channel<P, I, E, SendMany<T, S>> {
+    /// Send the number of items in `items`, and then each of them.
+    /// Panics if the iterator doesn't yield as many items as it reported.
+    pub fn send_iter<J>(mut self, items: J) -> Channel<P, I, E, S>
+        where J: IntoIterator<Item = T>, J::IntoIter: ExactSizeIterator
+    {
+        let mut items = items.into_iter();
+        let len = items.len();
+
+        unsafe { self.io.send_discriminant(len) };
+
+        for _ in 0..len {
+            match items.next() {
+                Some(item) => unsafe { self.io.send(item) },
+                None => panic!("iterator yielded fewer items than its length")
+            }
+        }
+
+        if items.next().is_some() {
+            panic!("iterator yielded more items than its length");
+        }
+
+        self.advance()
+    }
+}
+
+impl<I: Transfers<T>, T, E: SessionType, S: SessionType, P: Protocol> Channel<P, I, E, RecvMany<T, S>> {
+    /// Receive the number of items the peer is sending, and iterate over
+    /// them. Fails if the count hasn't arrived.
+    pub fn recv_iter(mut self) -> Result<RecvIter<P, I, E, T, S>, Self> {
+        unsafe { self.io.flush() };
+
+        match unsafe { self.next_discriminant() } {
+            Some(RAISED) if E::in_try() => {
+                self.ahead = Some(RAISED);
+                Err(self)
+            },
+            Some(len) => Ok(RecvIter {
+                chan: self.advance(),
+                remaining: len,
+                _marker: PhantomData
+            }),
+            None => Err(self)
+        }
+    }
+}
+
+impl<I: Transfers<T>, T, E: SessionType, S: SessionType, P: Protocol> Channel<P, I, E, Remaining<T, S>> {
+    /// Iterate over the items of a paused `RecvMany` which are left.
+    pub fn recv_iter(mut self) -> RecvIter<P, I, E, T, S> {
+        let remaining = self.ahead.take().expect("a paused RecvMany always holds its count");
+
+        RecvIter {
+            chan: self.advance(),
+            remaining: remaining,
+            _marker: PhantomData
+        }
+    }
+}
+
+/// Yields the items of a `RecvMany`. The iterator ends early if an item
+/// can't be received; iterating again later resumes where it stopped,
+/// and `pause` lets a handler defer in between.
+pub struct RecvIter<P: Protocol, I, E: SessionType, T, S: SessionType> {
+    chan: Channel<P, I, E, S>,
+    remaining: usize,
+    _marker: PhantomData<T>
+}
+
+impl<P: Protocol, I: Transfers<T>, E: SessionType, T, S: SessionType> RecvIter<P, I, E, T, S> {
+    /// The number of items which haven't been received yet.
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    /// Advance to the continuation. Fails if there are items left.
+    pub fn finish(self) -> Result<Channel<P, I, E, S>, Self> {
+        if self.remaining == 0 {
+            Ok(self.chan)
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Set the iterator aside as a channel which holds the number of
+    /// items left, and can be deferred until they arrive.
+    pub fn pause(self) -> Channel<P, I, E, Remaining<T, S>> {
+        let mut chan = self.chan.advance();
+        chan.ahead = Some(self.remaining);
+
+        chan
+    }
+}
+
+impl<P: Protocol, I: Transfers<T>, E: SessionType, T, S: SessionType> Iterator for RecvIter<P, I, E, T, S> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let item = unsafe { self.chan.io.recv() };
+        if item.is_some() {
+            self.remaining -= 1;
+        }
+
+        item
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.remaining))
+    }
+}
+
 impl<I, E: SessionType, S: SessionType, P: Protocol> Channel<P, I, E, Nest<S>> {
     /// Enter into a nested protocol.
     pub fn enter(self) -> Channel<P, I, (S, E), S> {
-        Channel::new(self.io, self.proto)
+        self.advance()
+    }
+}
+
+impl<I, L, S: SessionType, E: SessionType, P: Protocol> Channel<P, I, E, Rec<L, S>> {
+    /// Enter a recursive protocol.
+    pub fn recurse(self) -> Channel<P, I, Bind<L, S, E>, S> {
+        self.advance()
+    }
+}
+
+impl<I, L, E: SessionType + Lookup<L>, P: Protocol> Channel<P, I, E, Var<L>> {
+    /// Return to the start of the recursive protocol labelled `L`.
+    pub fn recurse(self) -> Channel<P, I, E::Env, E::Body> {
+        self.advance()
+    }
+}
+
+/// The protocol of the halves of a split channel. Halves are driven
+/// directly instead of being deferred, so it has no handlers. `'id`
+/// brands the halves with the split they came from.
+pub struct Fork<'id> {
+    _brand: PhantomData<fn(&'id ()) -> &'id ()>
+}
+
+impl<'id> Protocol for Fork<'id> {
+    type Initial = End;
+}
+
+/// A channel set aside by `split` until both of its halves have ended.
+pub struct Suspended<'id, P: Protocol, I, E: SessionType, S: SessionType> {
+    chan: Channel<P, I, E, S>,
+    _brand: PhantomData<fn(&'id ()) -> &'id ()>
+}
+
+impl<I: Split, S1: SessionType, S2: SessionType, N: SessionType, E: SessionType, P: Protocol> Channel<P, I, E, Par<S1, S2, N>> {
+    /// Split into two channels, which run `S1` and `S2` independently of
+    /// each other over sub-streams of this one, and the rest of this
+    /// session, which resumes once both have been joined. All three are
+    /// passed to `f`, whose result is returned.
+    ///
+    /// The halves are branded with this split, so that `join` takes back
+    /// only its own, and they can't outlive `f`. Neither can they be
+    /// deferred, as `Fork` has no handlers: `f` drives them directly, and
+    /// on a backend which doesn't block, retries a `recv` or `accept` on
+    /// a half until it succeeds.
+    pub fn split<F, R>(mut self, f: F) -> Result<R, Self>
+        where F: for<'id> FnOnce(Channel<Fork<'id>, I::Half, (), S1>, Channel<Fork<'id>, I::Half, (), S2>, Suspended<'id, P, I, E, N>) -> R
+    {
+        unsafe { self.io.flush() };
+
+        match unsafe { self.io.split() } {
+            Some((a, b)) => {
+                let rest = Suspended { chan: self.advance(), _brand: PhantomData };
+
+                Ok(f(Channel::new(a, Fork { _brand: PhantomData }), Channel::new(b, Fork { _brand: PhantomData }), rest))
+            },
+            None => {
+                Err(self)
+            }
+        }
+    }
+}
+
+impl<'id, I: Split, E: SessionType, S: SessionType, P: Protocol> Suspended<'id, P, I, E, S> {
+    /// Close both halves, which must have ended, and resume the session.
+    pub fn join<F: SessionType, G: SessionType>(self, mut a: Channel<Fork<'id>, I::Half, F, End>, mut b: Channel<Fork<'id>, I::Half, G, End>) -> Channel<P, I, E, S> {
+        unsafe {
+            a.io.flush();
+            a.io.close();
+            b.io.flush();
+            b.io.close();
+        }
+
+        self.chan
+    }
+}
+
+impl<I, T, S: SessionType, E: SessionType, P: Protocol> Channel<P, I, E, Mark<T, S>> {
+    #[doc(hidden)]
+    /// Move the mark from the session to the environment.
+    pub fn unmark(self) -> Channel<P, I, Mark<T, E>, S> {
+        self.advance()
     }
 }
 
 impl<I, N: Peano, E: SessionType + Pop<N>, P: Protocol> Channel<P, I, E, Escape<N>> {
     /// Escape from a nested protocol.
     pub fn pop(self) -> Channel<P, I, E::Tail, E::Head> {
-        Channel::new(self.io, self.proto)
+        self.advance()
     }
 }
 
 impl<I: IO, E: SessionType, R: SessionType, P: Protocol> Channel<P, I, E, R> {
+    /// Write out anything the backend has buffered. Sends and choices
+    /// may be held by the backend until the channel is flushed, which
+    /// happens anyway before receiving or accepting and on `defer` and
+    /// `close`.
+    pub fn flush(mut self) -> Channel<P, I, E, R> {
+        unsafe { self.io.flush() };
+
+        self
+    }
+
     /// Select a protocol to advance to.
     pub fn choose<S: SessionType>(mut self) -> Channel<P, I, E, S> where R: Chooser<S> {
+        #[cfg(feature = "metrics")]
+        metrics::branch(type_name::<P>(), type_name::<R>(), metrics::Side::Choose, R::num());
+
         unsafe { self.io.send_discriminant(R::num()); }
 
-        Channel::new(self.io, self.proto)
+        self.advance()
     }
 }
 
@@ -173,21 +691,173 @@ impl<I: IO, // Our IO
          E: SessionType, // Our current environment
          S: SessionType, // The first branch of our accepting session
          Q: SessionType, // The second branch of our accepting session
-         P: Acceptor<I, E, Accept<S, Q>> // We must be able to "accept" with our current state
+         P: Protocol
     > Channel<P, I, E, Accept<S, Q>> {
-    /// Accept one of many protocols and advance to its handler.
-    pub fn accept(mut self) -> Result<Defer<P, I>, Channel<P, I, E, Accept<S, Q>>> {
-        match unsafe { self.io.recv_discriminant() } {
-            Some(num) => {
+    /// Accept one of many protocols and advance to its handler. Fails if
+    /// nothing was received, or if the peer raised an exception which
+    /// hasn't been caught. If the peer sent a discriminant for a branch
+    /// which doesn't exist, the channel is closed.
+    pub fn accept(mut self) -> Result<Defer<P, I>, Channel<P, I, E, Accept<S, Q>>>
+        where P: Acceptor<I, E, Accept<S, Q>> // We must be able to "accept" with our current state
+    {
+        unsafe { self.io.flush() };
+
+        match unsafe { self.next_discriminant() } {
+            Some(RAISED) if E::in_try() => {
+                self.ahead = Some(RAISED);
+                Err(self)
+            },
+            Some(num) if num < <P as Acceptor<I, E, Accept<S, Q>>>::branches() => {
+                #[cfg(feature = "metrics")]
+                metrics::branch(type_name::<P>(), type_name::<Accept<S, Q>>(), metrics::Side::Accept, num);
+
                 Ok(<P as Acceptor<I, E, Accept<S, Q>>>::with(self, num))
             },
-            None => {
+            Some(_) => Ok(self.hang_up()),
+            None => Err(self)
+        }
+    }
+}
+
+impl<I, B: SessionType, H: SessionType, E: SessionType, P: Protocol> Channel<P, I, E, Try<B, H>> {
+    /// Enter a block which either side may abandon with `raise`.
+    pub fn attempt(self) -> Channel<P, I, Catch<H, E>, B> {
+        self.advance()
+    }
+}
+
+impl<I: IO, E: SessionType + Raise, S: Sending, P: Protocol> Channel<P, I, E, S> {
+    /// Raise an exception instead of sending, leaving the innermost `Try`
+    /// for its `OnError`.
+    pub fn raise(mut self) -> Channel<P, I, E::Env, E::OnError> {
+        unsafe { self.io.send_discriminant(RAISED) };
+
+        self.advance()
+    }
+}
+
+impl<I: IO, E: SessionType + Raise, S: Receiving, P: Protocol> Channel<P, I, E, S> {
+    /// Check whether the peer raised an exception instead of sending, and
+    /// if so advance to the handler of the innermost `Try`'s `OnError`.
+    /// Otherwise, or if nothing has arrived yet, the channel is returned
+    /// to receive as usual.
+    pub fn catch(mut self) -> Result<Defer<P, I>, Self>
+        where P: Handler<I, E::Env, E::OnError>
+    {
+        unsafe { self.io.flush() };
+
+        match unsafe { self.next_discriminant() } {
+            Some(RAISED) => {
+                let next_func: DeferFunc<P, I, E::Env, E::OnError> = Handler::<I, E::Env, E::OnError>::with;
+
+                let this = unsafe { self.into_state::<E::Env, E::OnError>() };
+
+                Ok(Defer::new(this, unsafe { mem::transmute(next_func) }, true))
+            },
+            other => {
+                self.ahead = other;
                 Err(self)
             }
         }
     }
 }
 
+impl<I: Timer, E: SessionType, D: Deadline, S: SessionType, T: SessionType, P: Protocol> Channel<P, I, E, Timed<D, S, T>> {
+    /// Wait for the peer to begin `S`, and advance to its handler, or to
+    /// the handler of `T` once the deadline has passed. Fails if nothing
+    /// was received but there is still time. If the peer hung up or sent
+    /// anything else, the channel is closed.
+    pub fn wait(mut self) -> Result<Defer<P, I>, Channel<P, I, E, Timed<D, S, T>>>
+        where P: Handler<I, E, S> + Handler<I, E, T>
+    {
+        unsafe { self.io.flush() };
+
+        match unsafe { self.io.recv_discriminant_within(D::duration()) } {
+            Wait::Arrived(IN_TIME) => {
+                unsafe { self.io.send_discriminant(IN_TIME) };
+
+                Ok(unsafe { self.into_session::<S>() }.defer())
+            },
+            Wait::Expired => {
+                unsafe {
+                    self.io.send_discriminant(TIMED_OUT);
+                    self.io.skip_discriminant();
+                }
+
+                Ok(unsafe { self.into_session::<T>() }.defer())
+            },
+            Wait::Arrived(_) | Wait::Closed => Ok(self.hang_up()),
+            Wait::Pending => Err(self)
+        }
+    }
+}
+
+impl<I: IO, E: SessionType, D: Deadline, S: SessionType, T: SessionType, P: Protocol> Channel<P, I, E, Due<D, S, T>> {
+    /// Tell the peer that `S` is beginning, and wait for its verdict on
+    /// whether that was in time.
+    pub fn answer(mut self) -> Channel<P, I, E, Verdict<S, T>> {
+        unsafe {
+            self.io.send_discriminant(IN_TIME);
+            self.io.flush();
+        }
+
+        self.advance()
+    }
+}
+
+impl<I: IO, E: SessionType, S: SessionType, T: SessionType, P: Protocol> Channel<P, I, E, Verdict<S, T>> {
+    /// Advance to the handler of `S` if the answer was in time, or of `T`
+    /// if the peer had given up on it. Fails if the verdict hasn't
+    /// arrived. If the peer sent anything else, the channel is closed.
+    pub fn verdict(mut self) -> Result<Defer<P, I>, Channel<P, I, E, Verdict<S, T>>>
+        where P: Handler<I, E, S> + Handler<I, E, T>
+    {
+        unsafe { self.io.flush() };
+
+        match unsafe { self.io.recv_discriminant() } {
+            Some(IN_TIME) => Ok(unsafe { self.into_session::<S>() }.defer()),
+            Some(TIMED_OUT) => Ok(unsafe { self.into_session::<T>() }.defer()),
+            Some(_) => Ok(self.hang_up()),
+            None => Err(self)
+        }
+    }
+}
+
+impl<I: Transfers<Vec<u32>>, E: SessionType, V: SessionType, P: Protocol> Channel<P, I, E, Offer<V>> {
+    /// Tell the peer that we speak every version in `V`. To leave some of
+    /// them out, `send` their numbers instead.
+    pub fn offer(self) -> Channel<P, I, E, Accept<End, V>>
+        where P: Acceptor<I, E, V>
+    {
+        let versions = <P as Acceptor<I, E, V>>::branches() as u32;
+
+        self.send((1..versions + 1).collect())
+    }
+}
+
+impl<I: IO, E: SessionType, V: SessionType, P: Protocol> Channel<P, I, E, Choose<End, V>> {
+    /// Choose the highest version in `V` which is also in `theirs`, the
+    /// versions the peer speaks, and advance to its handler. If there is
+    /// none, the peer is told so and the channel is closed.
+    pub fn select(mut self, theirs: &[u32]) -> Defer<P, I>
+        where P: Selector<I, E, V>
+    {
+        let ours = <P as Selector<I, E, V>>::versions();
+
+        match (1..ours + 1).rev().find(|&num| theirs.contains(&(num as u32))) {
+            Some(num) => {
+                unsafe { self.io.send_discriminant(num) };
+
+                <P as Selector<I, E, V>>::with(self, num - 1)
+            },
+            None => {
+                unsafe { self.io.send_discriminant(0) };
+
+                self.hang_up()
+            }
+        }
+    }
+}
 
 struct Dummy<P, I, E, S>(PhantomData<(P, I, E, S)>);
 impl<I, P: Protocol, E: SessionType, S: SessionType> Dummy<P, I, E, S> {
@@ -1,15 +1,26 @@
 use std::marker::PhantomData;
-use super::SessionType;
+use super::{SessionType, shape_of};
+use super::sealed::NotSame;
 use protocol::{Channel, Protocol, Handler, Defer};
 
 /// This trait effectively posits that a protocol which handles `T` must
 /// additionally handle other types. If `T` is an `Accept<S, Q>` the
 /// protocol must handle `S` *and* be an `Acceptor` of `Q`. If `T` is 
 /// a `Finally<S>` it must handle `S`.
+#[rustc_on_unimplemented(message="`{Self}` does not handle every branch of `{T}` in environment `{E}`", label="a branch is not handled")]
 pub trait Acceptor<I, E: SessionType, T>: Protocol + Sized {
 	fn with<X: SessionType, Y: SessionType>(chan: Channel<Self, I, E, Accept<X, Y>>, usize) -> Defer<Self, I>;
+
+	/// The number of branches in `T`. Discriminants at or beyond this
+	/// can only come from a misbehaving peer.
+	fn branches() -> usize;
 }
 impl<I, E: SessionType, H: Protocol + Handler<I, E, S> + Acceptor<I, E, Q>, S: SessionType, Q: SessionType> Acceptor<I, E, Accept<S, Q>> for H {
+	#[inline(always)]
+	fn branches() -> usize {
+		<Self as Acceptor<I, E, Q>>::branches() + 1
+	}
+
 	#[inline(always)]
 	fn with<X: SessionType, Y: SessionType>(chan: Channel<Self, I, E, Accept<X, Y>>, num: usize) -> Defer<H, I> {
 		if num == 0 {
@@ -20,6 +31,11 @@ impl<I, E: SessionType, H: Protocol + Handler<I, E, S> + Acceptor<I, E, Q>, S: S
 	}
 }
 impl<I, E: SessionType, H: Protocol + Handler<I, E, S>,                     S: SessionType>                 Acceptor<I, E, Finally<S>>   for H {
+	#[inline(always)]
+	fn branches() -> usize {
+		1
+	}
+
 	#[inline(always)]
 	fn with<X: SessionType, Y: SessionType>(chan: Channel<Self, I, E, Accept<X, Y>>, _: usize) -> Defer<H, I> {
 		// regardless of num we cannot proceed further than Finally
@@ -32,14 +48,13 @@ pub struct Choose<S: SessionType, Q: SessionType>(PhantomData<(S, Q)>);
 
 unsafe impl<S: SessionType, Q: SessionType> SessionType for Choose<S, Q> {
 	type Dual = Accept<S::Dual, Q::Dual>;
-}
 
-trait NotSame { }
-impl NotSame for .. { }
-impl<A> !NotSame for (A, A) { }
+	fn shape(out: &mut String) { shape_of(out, "Choose", &[S::shape, Q::shape]) }
+}
 
 /// This trait selects for the de-Bruijn index of a protocol embedded within
 /// a `Choose` decision tree.
+#[rustc_on_unimplemented(message="branch `{T}` is not offered by `{Self}`", label="branch not offered")]
 pub trait Chooser<T> {
 	fn num() -> usize;
 }
@@ -66,6 +81,8 @@ pub struct Accept<S: SessionType, Q: SessionType>(PhantomData<(S, Q)>);
 
 unsafe impl<S: SessionType, Q: SessionType> SessionType for Accept<S, Q> {
 	type Dual = Choose<S::Dual, Q::Dual>;
+
+	fn shape(out: &mut String) { shape_of(out, "Accept", &[S::shape, Q::shape]) }
 }
 
 /// Finally choose `S`.
@@ -73,6 +90,8 @@ pub struct Finally<S: SessionType>(PhantomData<S>);
 
 unsafe impl<S: SessionType> SessionType for Finally<S> {
 	type Dual = Finally<S::Dual>;
+
+	fn shape(out: &mut String) { shape_of(out, "Finally", &[S::shape]) }
 }
 
 #[test]
@@ -0,0 +1,86 @@
+use std::marker::PhantomData;
+use peano::{Peano, Pop};
+use super::{SessionType, shape_of, Bind, Lookup, Send, Recv, SendMany, RecvMany, Choose, Accept};
+
+/// A block `Body` which either side may abandon for `OnError`. Whenever
+/// it is a side's turn to send, it may `raise` an exception instead, and
+/// the peer, which is waiting to receive, must `catch` it before
+/// receiving, so both sides leave `Body` at the same point; until it
+/// does, its receives fail as if nothing had arrived. Inside
+/// `Body`, escapes and `Var`s may leave the block as usual.
+///
+/// A side which is waiting to receive can't raise, because the peer may
+/// already be sending.
+pub struct Try<Body: SessionType, OnError: SessionType> ( PhantomData<(Body, OnError)> );
+
+unsafe impl<Body: SessionType, OnError: SessionType> SessionType for Try<Body, OnError> {
+    type Dual = Try<Body::Dual, OnError::Dual>;
+
+    fn shape(out: &mut String) { shape_of(out, "Try", &[Body::shape, OnError::shape]) }
+}
+
+/// The environment inside `Try<_, H>`, when it was entered in the
+/// environment `E`.
+pub struct Catch<H, E> ( PhantomData<(H, E)> );
+
+unsafe impl<H: SessionType, E: SessionType> SessionType for Catch<H, E> {
+    type Dual = Catch<H, E>;
+
+    fn in_try() -> bool { true }
+
+    fn shape(out: &mut String) { shape_of(out, "Catch", &[H::shape, E::shape]) }
+}
+
+/// This trait finds the innermost `Try` in an environment.
+pub trait Raise {
+    /// The session which handles the exception.
+    type OnError: SessionType;
+
+    /// The environment around the `Try`.
+    type Env: SessionType;
+}
+
+impl<H: SessionType, E: SessionType> Raise for Catch<H, E> {
+    type OnError = H;
+    type Env = E;
+}
+
+impl<A, B: Raise> Raise for (A, B) {
+    type OnError = B::OnError;
+    type Env = B::Env;
+}
+
+impl<L, S, E: Raise> Raise for Bind<L, S, E> {
+    type OnError = E::OnError;
+    type Env = E::Env;
+}
+
+/// The states in which it is our turn to send, so that we may raise an
+/// exception instead.
+pub trait Sending: SessionType { }
+
+impl<T: ?Sized, S: SessionType> Sending for Send<T, S> { }
+impl<T, S: SessionType> Sending for SendMany<T, S> { }
+impl<S: SessionType, Q: SessionType> Sending for Choose<S, Q> { }
+
+/// The states in which we wait for the peer to send, so that it may have
+/// raised an exception instead.
+pub trait Receiving: SessionType { }
+
+impl<T: ?Sized, S: SessionType> Receiving for Recv<T, S> { }
+impl<T, S: SessionType> Receiving for RecvMany<T, S> { }
+impl<S: SessionType, Q: SessionType> Receiving for Accept<S, Q> { }
+
+// Escaping or recursing out of a `Try` leaves it behind.
+impl<N: Peano, H, E: Pop<N>> Pop<N> for Catch<H, E> {
+    type Head = E::Head;
+    type Tail = E::Tail;
+}
+
+impl<L, H, E: Lookup<L>> Lookup<L> for Catch<H, E> {
+    type Body = E::Body;
+    type Env = E::Env;
+
+    #[inline(always)]
+    fn depth() -> usize { E::depth() + 1 }
+}
@@ -6,10 +6,32 @@
 //! be in state `End`, which means it can do nothing except close the channel.
 
 mod choose;
+mod exception;
+mod recursion;
+mod subtype;
+mod timed;
+mod versions;
+mod well_formed;
 
 use std::marker::PhantomData;
+use type_name;
 use peano::*;
 pub use self::choose::{Chooser,Accept,Choose,Finally,Acceptor};
+pub use self::exception::{Try, Catch, Raise, Sending, Receiving};
+pub use self::recursion::{Rec, Var, Bind, Lookup};
+pub use self::subtype::Subtype;
+pub use self::timed::{Deadline, Timed, Due, Verdict};
+pub use self::versions::{Offer, Select, Selector, Compatible};
+pub use self::well_formed::{WellFormed, WellFormedIn};
+
+// Traits which public bounds rely on, but which can't be named, and so
+// neither implemented nor required, outside the crate.
+mod sealed {
+    /// Holds for every pair of different types.
+    pub trait NotSame { }
+    impl NotSame for .. { }
+    impl<A> !NotSame for (A, A) { }
+}
 
 /// All session types have duality. Two clients that communicate will
 /// always have a session type that is the dual of their counterpart.
@@ -19,6 +41,36 @@ pub use self::choose::{Chooser,Accept,Choose,Finally,Acceptor};
 /// while the other expects to send T and switch to the dual of S.
 pub unsafe trait SessionType {
     type Dual: SessionType;
+
+    #[doc(hidden)]
+    /// Whether this environment is inside a `Try`, where every payload is
+    /// announced so that the peer may raise an exception in its place.
+    fn in_try() -> bool { false }
+
+    #[doc(hidden)]
+    /// Write out the structure of this type, naming payloads, labels and
+    /// deadlines by their full paths. Checkpoints identify states by it.
+    /// Session types defined outside this crate are written out as the
+    /// compiler names them.
+    fn shape(out: &mut String) { out.push_str(type_name::<Self>()) }
+}
+
+// Write `name(..)` with the shapes of `parts`, for `SessionType::shape`.
+fn shape_of(out: &mut String, name: &str, parts: &[fn(&mut String)]) {
+    out.push_str(name);
+    out.push('(');
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        part(out);
+    }
+    out.push(')');
+}
+
+// The shape of a type which isn't a session, such as a payload.
+fn named<T: ?Sized>(out: &mut String) {
+    out.push_str(type_name::<T>());
 }
 
 /// The session is at the end of communication.
@@ -27,20 +79,73 @@ pub struct End;
 
 unsafe impl SessionType for End {
     type Dual = End;
+
+    fn shape(out: &mut String) { out.push_str("End") }
 }
 
-/// The session expects to send `T` and proceed to session `S`.
-pub struct Send<T, S: SessionType> ( PhantomData<(T, S)> );
+/// The session expects to send `T` and proceed to session `S`. `T` may
+/// be unsized, such as `[u8]` or `str`, if it is sent by reference.
+pub struct Send<T: ?Sized, S: SessionType> ( PhantomData<(PhantomData<T>, S)> );
 
-unsafe impl<T, S: SessionType> SessionType for Send<T, S> {
+unsafe impl<T: ?Sized, S: SessionType> SessionType for Send<T, S> {
     type Dual = Recv<T, S::Dual>;
+
+    fn shape(out: &mut String) { shape_of(out, "Send", &[named::<T>, S::shape]) }
 }
 
-/// The session expects to receive `T` and proceed to session `S`.
-pub struct Recv<T, S: SessionType> ( PhantomData<(T, S)> );
+/// The session expects to receive `T` and proceed to session `S`. An
+/// unsized `T` is received in its owned form.
+pub struct Recv<T: ?Sized, S: SessionType> ( PhantomData<(PhantomData<T>, S)> );
 
-unsafe impl<T, S: SessionType> SessionType for Recv<T, S> {
+unsafe impl<T: ?Sized, S: SessionType> SessionType for Recv<T, S> {
     type Dual = Send<T, S::Dual>;
+
+    fn shape(out: &mut String) { shape_of(out, "Recv", &[named::<T>, S::shape]) }
+}
+
+/// The session expects to send any number of `T`, announcing how many
+/// up front, and proceed to session `S`.
+pub struct SendMany<T, S: SessionType> ( PhantomData<(T, S)> );
+
+unsafe impl<T, S: SessionType> SessionType for SendMany<T, S> {
+    type Dual = RecvMany<T, S::Dual>;
+
+    fn shape(out: &mut String) { shape_of(out, "SendMany", &[named::<T>, S::shape]) }
+}
+
+/// The session expects to receive a count and then that many `T`, and
+/// proceed to session `S`.
+pub struct RecvMany<T, S: SessionType> ( PhantomData<(T, S)> );
+
+unsafe impl<T, S: SessionType> SessionType for RecvMany<T, S> {
+    type Dual = SendMany<T, S::Dual>;
+
+    fn shape(out: &mut String) { shape_of(out, "RecvMany", &[named::<T>, S::shape]) }
+}
+
+/// The session is part way through a `RecvMany`: the channel holds the
+/// number of `T` still to be received before proceeding to session `S`.
+/// A `RecvIter` which runs out of items early is paused into this state,
+/// so that its handler can defer until the rest arrive.
+pub struct Remaining<T, S: SessionType> ( PhantomData<(T, S)> );
+
+unsafe impl<T, S: SessionType> SessionType for Remaining<T, S> {
+    type Dual = InFlight<T, S::Dual>;
+
+    fn shape(out: &mut String) { shape_of(out, "Remaining", &[named::<T>, S::shape]) }
+}
+
+/// The peer of a `Remaining`: part way through a `SendMany` whose count
+/// and items have all been sent, but not all received yet. `send_iter`
+/// sends them at once, so no channel is ever in this state; it stops a
+/// paused receiver from pairing with a peer which would send another
+/// count.
+pub struct InFlight<T, S: SessionType> ( PhantomData<(T, S)> );
+
+unsafe impl<T, S: SessionType> SessionType for InFlight<T, S> {
+    type Dual = Remaining<T, S::Dual>;
+
+    fn shape(out: &mut String) { shape_of(out, "InFlight", &[named::<T>, S::shape]) }
 }
 
 /// Protocols ocassionally do not follow a linear path of behavior. It may
@@ -53,6 +158,8 @@ pub struct Nest<S: SessionType> ( PhantomData<S> );
 
 unsafe impl<S: SessionType> SessionType for Nest<S> {
     type Dual = Nest<S::Dual>;
+
+    fn shape(out: &mut String) { shape_of(out, "Nest", &[S::shape]) }
 }
 
 /// Escape from a nested scope by an arbitrary number of layers `N`, using
@@ -61,13 +168,47 @@ pub struct Escape<N: Peano> ( PhantomData<N> );
 
 unsafe impl<N: Peano> SessionType for Escape<N> {
     type Dual = Escape<N>;
+
+    fn shape(out: &mut String) { out.push_str(&format!("Escape({})", N::to_usize())) }
+}
+
+/// The session runs `S1` and `S2` at the same time, each over a channel
+/// of its own, and proceeds to `Next` once both have ended. See
+/// `Channel::split`.
+pub struct Par<S1: SessionType, S2: SessionType, Next: SessionType> ( PhantomData<(S1, S2, Next)> );
+
+unsafe impl<S1: SessionType, S2: SessionType, Next: SessionType> SessionType for Par<S1, S2, Next> {
+    type Dual = Par<S1::Dual, S2::Dual, Next::Dual>;
+
+    fn shape(out: &mut String) { shape_of(out, "Par", &[S1::shape, S2::shape, Next::shape]) }
 }
 
 // TODO: understand the interactions and needs of these impls
 unsafe impl SessionType for () {
     type Dual = ();
+
+    fn shape(out: &mut String) { out.push_str("()") }
 }
 
 unsafe impl<S: SessionType, Q: SessionType> SessionType for (S, Q) {
     type Dual = (S, Q);
+
+    fn in_try() -> bool { Q::in_try() }
+
+    fn shape(out: &mut String) { shape_of(out, "", &[S::shape, Q::shape]) }
+}
+
+#[doc(hidden)]
+/// The environment `E`, or the session `E`, marked with `T`. `session!`
+/// marks the blocks of its `offer`s and `choose`s, so that states which
+/// are otherwise the same get handlers of their own. Escapes look through
+/// the mark, and nothing is sent for it.
+pub struct Mark<T, E> ( PhantomData<(T, E)> );
+
+unsafe impl<T, E: SessionType> SessionType for Mark<T, E> {
+    type Dual = Mark<T, E::Dual>;
+
+    fn in_try() -> bool { E::in_try() }
+
+    fn shape(out: &mut String) { shape_of(out, "Mark", &[named::<T>, E::shape]) }
 }
@@ -0,0 +1,78 @@
+use std::marker::PhantomData;
+use super::{SessionType, shape_of, named};
+use super::sealed::NotSame;
+
+/// A recursive session labelled `L`. Anywhere in `S`, `Var<L>` returns to
+/// the start of `S`. Unlike `Escape<N>`, which counts the `Nest` scopes
+/// it leaves, a `Var` names the loop it returns to, so wrapping a loop in
+/// another does not change what its `Var`s mean. Labels are ordinary
+/// types, usually empty structs, and loops which enclose one another
+/// must have different labels.
+pub struct Rec<L, S: SessionType> ( PhantomData<(L, S)> );
+
+unsafe impl<L, S: SessionType> SessionType for Rec<L, S> {
+    type Dual = Rec<L, S::Dual>;
+
+    fn shape(out: &mut String) { shape_of(out, "Rec", &[named::<L>, S::shape]) }
+}
+
+/// Return to the start of the enclosing `Rec` labelled `L`.
+pub struct Var<L> ( PhantomData<L> );
+
+unsafe impl<L> SessionType for Var<L> {
+    type Dual = Var<L>;
+
+    fn shape(out: &mut String) { shape_of(out, "Var", &[named::<L>]) }
+}
+
+/// The environment inside `Rec<L, S>`, when it was entered in the
+/// environment `E`. `Escape` cannot leave a `Nest` which encloses this
+/// frame; use a `Var` instead.
+pub struct Bind<L, S, E> ( PhantomData<(L, S, E)> );
+
+unsafe impl<L, S: SessionType, E: SessionType> SessionType for Bind<L, S, E> {
+    type Dual = Bind<L, S, E>;
+
+    fn in_try() -> bool { E::in_try() }
+
+    fn shape(out: &mut String) { shape_of(out, "Bind", &[named::<L>, S::shape, E::shape]) }
+}
+
+/// This trait finds the `Rec` labelled `L` in an environment, skipping
+/// the frames of other loops and of `Nest` scopes.
+pub trait Lookup<L> {
+    /// The body of the `Rec`.
+    type Body: SessionType;
+
+    /// The environment inside the `Rec`.
+    type Env: SessionType;
+
+    /// The number of frames entered since the `Rec`.
+    fn depth() -> usize;
+}
+
+impl<L, S: SessionType, E: SessionType> Lookup<L> for Bind<L, S, E> {
+    type Body = S;
+    type Env = Bind<L, S, E>;
+
+    #[inline(always)]
+    fn depth() -> usize { 0 }
+}
+
+impl<L, M, S, E: Lookup<L>> Lookup<L> for Bind<M, S, E>
+    where (L, M): NotSame
+{
+    type Body = E::Body;
+    type Env = E::Env;
+
+    #[inline(always)]
+    fn depth() -> usize { E::depth() + 1 }
+}
+
+impl<L, A, B: Lookup<L>> Lookup<L> for (A, B) {
+    type Body = B::Body;
+    type Env = B::Env;
+
+    #[inline(always)]
+    fn depth() -> usize { B::depth() + 1 }
+}
@@ -0,0 +1,46 @@
+use super::*;
+
+/// `Self: Subtype<T>` holds when a channel in state `Self` can be used
+/// as a channel in state `T`, so that a peer which follows the dual of
+/// `Self` stays in step with a handler written for `T`.
+///
+/// This allows protocols to grow at the end of their branch lists. A
+/// `Choose` may be narrowed to a prefix of its branches, because choosing
+/// among fewer options never surprises the peer. An `Accept` may be
+/// widened with extra branches at the end, because the peer will never
+/// pick them. Since only trailing branches are added or dropped, every
+/// remaining branch keeps its discriminant. The relation carries through
+/// `Send`, `Recv`, `Nest` and `Rec` to their continuations, and into
+/// both halves of a `Par` and both blocks of a `Try`.
+pub trait Subtype<T: SessionType>: SessionType { }
+
+impl Subtype<End> for End { }
+
+impl<N: Peano> Subtype<Escape<N>> for Escape<N> { }
+
+impl<T: ?Sized, S: Subtype<S2>, S2: SessionType> Subtype<Send<T, S2>> for Send<T, S> { }
+impl<T: ?Sized, S: Subtype<S2>, S2: SessionType> Subtype<Recv<T, S2>> for Recv<T, S> { }
+impl<T, S: Subtype<S2>, S2: SessionType> Subtype<SendMany<T, S2>> for SendMany<T, S> { }
+impl<T, S: Subtype<S2>, S2: SessionType> Subtype<RecvMany<T, S2>> for RecvMany<T, S> { }
+
+impl<S: Subtype<S2>, S2: SessionType> Subtype<Nest<S2>> for Nest<S> { }
+
+impl<L, S: Subtype<S2>, S2: SessionType> Subtype<Rec<L, S2>> for Rec<L, S> { }
+impl<L> Subtype<Var<L>> for Var<L> { }
+
+impl<B: Subtype<B2>, H: Subtype<H2>, B2: SessionType, H2: SessionType> Subtype<Try<B2, H2>> for Try<B, H> { }
+
+impl<D: Deadline, S: Subtype<S2>, T: Subtype<T2>, S2: SessionType, T2: SessionType> Subtype<Timed<D, S2, T2>> for Timed<D, S, T> { }
+impl<D: Deadline, S: Subtype<S2>, T: Subtype<T2>, S2: SessionType, T2: SessionType> Subtype<Due<D, S2, T2>> for Due<D, S, T> { }
+impl<S: Subtype<S2>, T: Subtype<T2>, S2: SessionType, T2: SessionType> Subtype<Verdict<S2, T2>> for Verdict<S, T> { }
+
+impl<S1: Subtype<T1>, S2: Subtype<T2>, Next: Subtype<N2>, T1: SessionType, T2: SessionType, N2: SessionType> Subtype<Par<T1, T2, N2>> for Par<S1, S2, Next> { }
+
+// Choose: the same branches, or only the first of them.
+impl<S: Subtype<S2>, Q: Subtype<Q2>, S2: SessionType, Q2: SessionType> Subtype<Choose<S2, Q2>> for Choose<S, Q> { }
+impl<S: Subtype<S2>, Q: SessionType, S2: SessionType> Subtype<Finally<S2>> for Choose<S, Q> { }
+impl<S: Subtype<S2>, S2: SessionType> Subtype<Finally<S2>> for Finally<S> { }
+
+// Accept: the same branches, or more after the last one.
+impl<S: Subtype<S2>, Q: Subtype<Q2>, S2: SessionType, Q2: SessionType> Subtype<Accept<S2, Q2>> for Accept<S, Q> { }
+impl<S: Subtype<S2>, S2: SessionType, Q2: SessionType> Subtype<Accept<S2, Q2>> for Finally<S> { }
@@ -0,0 +1,39 @@
+use std::marker::PhantomData;
+use std::time::Duration;
+use super::{SessionType, shape_of, named};
+
+/// A deadline which is part of a session type, usually an empty struct.
+pub trait Deadline {
+    /// How long the peer has.
+    fn duration() -> Duration;
+}
+
+/// Wait at most `D` for the peer to begin `S`, or proceed to `OnTimeout`.
+/// Either way the peer is told which, so both sides continue in step.
+pub struct Timed<D: Deadline, S: SessionType, OnTimeout: SessionType> ( PhantomData<(D, S, OnTimeout)> );
+
+unsafe impl<D: Deadline, S: SessionType, OnTimeout: SessionType> SessionType for Timed<D, S, OnTimeout> {
+    type Dual = Due<D, S::Dual, OnTimeout::Dual>;
+
+    fn shape(out: &mut String) { shape_of(out, "Timed", &[named::<D>, S::shape, OnTimeout::shape]) }
+}
+
+/// Begin `S` within `D`, and find out from the peer whether that was in
+/// time or the session proceeds to `OnTimeout`.
+pub struct Due<D: Deadline, S: SessionType, OnTimeout: SessionType> ( PhantomData<(D, S, OnTimeout)> );
+
+unsafe impl<D: Deadline, S: SessionType, OnTimeout: SessionType> SessionType for Due<D, S, OnTimeout> {
+    type Dual = Timed<D, S::Dual, OnTimeout::Dual>;
+
+    fn shape(out: &mut String) { shape_of(out, "Due", &[named::<D>, S::shape, OnTimeout::shape]) }
+}
+
+/// Wait for the peer to say whether `Due` was met, and proceed to `S` if
+/// it was or `OnTimeout` if not.
+pub struct Verdict<S: SessionType, OnTimeout: SessionType> ( PhantomData<(S, OnTimeout)> );
+
+unsafe impl<S: SessionType, OnTimeout: SessionType> SessionType for Verdict<S, OnTimeout> {
+    type Dual = Verdict<S::Dual, OnTimeout::Dual>;
+
+    fn shape(out: &mut String) { shape_of(out, "Verdict", &[S::shape, OnTimeout::shape]) }
+}
@@ -0,0 +1,76 @@
+use super::*;
+use protocol::{Channel, Protocol, Handler, Defer};
+
+/// Version negotiation from the side which serves: send the version
+/// numbers we speak, then accept the peer's selection from `V`.
+///
+/// `V` is an `Accept` over the sessions of every version, oldest first,
+/// so that version `n` is branch `n` of the `Accept<End, V>`. Branch 0 is
+/// taken when the peer speaks none of our versions. New versions are
+/// added at the end, which keeps the discriminant of every old version.
+pub type Offer<V> = Send<Vec<u32>, Accept<End, V>>;
+
+/// Version negotiation from the side which decides: receive the version
+/// numbers the peer speaks, then choose the highest of `V` among them.
+///
+/// `V` is a `Choose` over the sessions of every version, oldest first.
+pub type Select<V> = Recv<Vec<u32>, Choose<End, V>>;
+
+/// This trait posits that a protocol which can choose from the versions
+/// `T` handles the session of every version in it, in the same way as
+/// `Acceptor` does for the branches of an `Accept`.
+#[rustc_on_unimplemented(message="`{Self}` does not handle every version in `{T}` in environment `{E}`", label="a version is not handled")]
+pub trait Selector<I, E: SessionType, T>: Protocol + Sized {
+    fn with<X: SessionType>(chan: Channel<Self, I, E, X>, usize) -> Defer<Self, I>;
+
+    /// The number of versions in `T`.
+    fn versions() -> usize;
+}
+
+impl<I, E: SessionType, H: Protocol + Handler<I, E, S> + Selector<I, E, Q>, S: SessionType, Q: SessionType> Selector<I, E, Choose<S, Q>> for H {
+    fn with<X: SessionType>(chan: Channel<Self, I, E, X>, num: usize) -> Defer<H, I> {
+        if num == 0 {
+            <Self as Handler<I, E, S>>::with(unsafe { chan.into_session::<S>() })
+        } else {
+            <Self as Selector<I, E, Q>>::with(chan, num - 1)
+        }
+    }
+
+    fn versions() -> usize {
+        <Self as Selector<I, E, Q>>::versions() + 1
+    }
+}
+
+impl<I, E: SessionType, H: Protocol + Handler<I, E, S>, S: SessionType> Selector<I, E, Finally<S>> for H {
+    fn with<X: SessionType>(chan: Channel<Self, I, E, X>, _: usize) -> Defer<H, I> {
+        <Self as Handler<I, E, S>>::with(unsafe { chan.into_session::<S>() })
+    }
+
+    fn versions() -> usize {
+        1
+    }
+}
+
+/// `Self: Compatible<T>` holds when a peer which offers or selects
+/// versions as `Self` can negotiate with one which does so as `T`: every
+/// version which both sides list has dual sessions on the two sides.
+/// Either side may list versions the other doesn't know of yet.
+///
+/// Lists which don't line up are reported as such; a version whose
+/// sessions aren't dual is reported as a type mismatch on its `Dual`.
+#[rustc_on_unimplemented(message="the versions of `{Self}` do not match those of `{T}`", label="versions do not match")]
+pub trait Compatible<T> { }
+
+impl<V: SessionType + Compatible<W>, W: SessionType> Compatible<Select<W>> for Offer<V> { }
+impl<V: SessionType + Compatible<W>, W: SessionType> Compatible<Offer<W>> for Select<V> { }
+
+impl<S: SessionType<Dual = T>, Q: SessionType + Compatible<Q2>, T: SessionType, Q2: SessionType> Compatible<Choose<T, Q2>> for Accept<S, Q> { }
+impl<S: SessionType<Dual = T>, Q: SessionType + Compatible<Q2>, T: SessionType, Q2: SessionType> Compatible<Accept<T, Q2>> for Choose<S, Q> { }
+
+// one side has run out of versions, so the rest of the other's are never
+// selected
+impl<S: SessionType<Dual = T>, T: SessionType, Q: SessionType> Compatible<Choose<T, Q>> for Finally<S> { }
+impl<S: SessionType<Dual = T>, T: SessionType, Q: SessionType> Compatible<Accept<T, Q>> for Finally<S> { }
+impl<S: SessionType<Dual = T>, T: SessionType, Q: SessionType> Compatible<Finally<T>> for Accept<S, Q> { }
+impl<S: SessionType<Dual = T>, T: SessionType, Q: SessionType> Compatible<Finally<T>> for Choose<S, Q> { }
+impl<S: SessionType<Dual = T>, T: SessionType> Compatible<Finally<T>> for Finally<S> { }
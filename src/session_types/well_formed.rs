@@ -0,0 +1,45 @@
+use peano::{Peano, Pop};
+use super::*;
+
+/// A session type is well formed if every `Escape` leaves at most as many
+/// `Nest` scopes as enclose it, and every path through it finishes in
+/// `End` or an `Escape`. `channel`, `channel_dual` and the backends'
+/// constructors require it of the session they start, so a malformed
+/// protocol is rejected where it is first used rather than in whichever
+/// handler happens to `pop`.
+pub trait WellFormed: SessionType { }
+
+impl<S: SessionType + WellFormedIn<()>> WellFormed for S { }
+
+/// `Self` is well formed inside the `Nest` bodies `Ctx`, which is a stack
+/// of bodies like the environment of a `Channel`, innermost first.
+pub trait WellFormedIn<Ctx> { }
+
+impl<Ctx> WellFormedIn<Ctx> for End { }
+
+impl<T: ?Sized, S: SessionType + WellFormedIn<Ctx>, Ctx> WellFormedIn<Ctx> for Send<T, S> { }
+impl<T: ?Sized, S: SessionType + WellFormedIn<Ctx>, Ctx> WellFormedIn<Ctx> for Recv<T, S> { }
+impl<T, S: SessionType + WellFormedIn<Ctx>, Ctx> WellFormedIn<Ctx> for SendMany<T, S> { }
+impl<T, S: SessionType + WellFormedIn<Ctx>, Ctx> WellFormedIn<Ctx> for RecvMany<T, S> { }
+
+impl<S: SessionType + WellFormedIn<(S, Ctx)>, Ctx> WellFormedIn<Ctx> for Nest<S> { }
+
+impl<N: Peano, Ctx: Pop<N>> WellFormedIn<Ctx> for Escape<N> { }
+
+impl<L, S: SessionType + WellFormedIn<Bind<L, S, Ctx>>, Ctx> WellFormedIn<Ctx> for Rec<L, S> { }
+
+impl<L, Ctx: Lookup<L>> WellFormedIn<Ctx> for Var<L> { }
+
+impl<B: SessionType + WellFormedIn<Catch<H, Ctx>>, H: SessionType + WellFormedIn<Ctx>, Ctx> WellFormedIn<Ctx> for Try<B, H> { }
+
+impl<D: Deadline, S: SessionType + WellFormedIn<Ctx>, T: SessionType + WellFormedIn<Ctx>, Ctx> WellFormedIn<Ctx> for Timed<D, S, T> { }
+impl<D: Deadline, S: SessionType + WellFormedIn<Ctx>, T: SessionType + WellFormedIn<Ctx>, Ctx> WellFormedIn<Ctx> for Due<D, S, T> { }
+impl<S: SessionType + WellFormedIn<Ctx>, T: SessionType + WellFormedIn<Ctx>, Ctx> WellFormedIn<Ctx> for Verdict<S, T> { }
+
+// The halves of a `Par` start afresh, so they can't escape to the
+// scopes around it.
+impl<S1: SessionType + WellFormedIn<()>, S2: SessionType + WellFormedIn<()>, Next: SessionType + WellFormedIn<Ctx>, Ctx> WellFormedIn<Ctx> for Par<S1, S2, Next> { }
+
+impl<S: SessionType + WellFormedIn<Ctx>, Q: SessionType + WellFormedIn<Ctx>, Ctx> WellFormedIn<Ctx> for Choose<S, Q> { }
+impl<S: SessionType + WellFormedIn<Ctx>, Q: SessionType + WellFormedIn<Ctx>, Ctx> WellFormedIn<Ctx> for Accept<S, Q> { }
+impl<S: SessionType + WellFormedIn<Ctx>, Ctx> WellFormedIn<Ctx> for Finally<S> { }
@@ -0,0 +1,1095 @@
+//! A small textual language for describing two-party protocols, in the
+//! style of Scribble, for people who would rather not read session types:
+//!
+//! ```text
+//! global protocol Atm(role Server, role Client) {
+//!     account(String) from Client to Server;
+//!     rec Menu {
+//!         choice at Client {
+//!             deposit(u64) from Client to Server;
+//!             balance(u64) from Server to Client;
+//!             continue Menu;
+//!         } or {
+//!             quit() from Client to Server;
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! Messages carry an optional label and a Rust type. Whatever follows a
+//! `choice` is part of each of its branches, and the protocol ends when a
+//! block runs out of statements. A `rec` block is entered once and left
+//! only through `continue`, so nothing may follow it. Besides these,
+//! `par { .. } and { .. }` runs two blocks at the same time before going
+//! on, `try { .. } catch { .. }` lets either role abandon the first block
+//! for the second, and `timed(Deadline) at A { .. } else { .. }` has `A`
+//! wait at most `Deadline` for the other role to begin the first block.
+//! Like `choice`, whatever follows `try` or `timed` is part of both
+//! blocks.
+//!
+//! `parse` reads a description, `Spec::project` gives the session of one
+//! role, and `Spec::generate` writes a `proto! { mod .. }` declaration of
+//! the first role as the server, suitable for a build script.
+//! `Spec::skeleton` writes the handlers a role must implement for that
+//! declaration, to be filled in. Going the other way, `Session::of`
+//! describes a session type, and `Session::to_spec` turns it back into
+//! text so that specifications can be compared with the code.
+
+use std::fmt;
+use std::iter;
+use {type_name, normalize};
+use peano::Peano;
+use session_types::*;
+
+/// A parsed protocol description.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Spec {
+    pub name: String,
+    /// The two roles, in the order they were declared.
+    pub roles: (String, String),
+    pub body: Vec<Stmt>
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Stmt {
+    /// `label(Type) from A to B;`. The label may be empty, and a message
+    /// without a type carries `()`.
+    Message {
+        label: String,
+        payload: String,
+        from: String,
+        to: String
+    },
+    /// `choice at A { .. } or { .. }`; `A` decides which branch is taken.
+    Choice {
+        at: String,
+        branches: Vec<Vec<Stmt>>
+    },
+    /// `rec Label { .. }`
+    Rec {
+        label: String,
+        body: Vec<Stmt>
+    },
+    /// `continue Label;`
+    Continue(String),
+    /// `par { .. } and { .. }`; the blocks run at the same time, each over
+    /// a channel of its own, and what follows once both have ended.
+    Par(Vec<Stmt>, Vec<Stmt>),
+    /// `try { .. } catch { .. }`; either role may abandon `body` for
+    /// `handler`.
+    Try {
+        body: Vec<Stmt>,
+        handler: Vec<Stmt>
+    },
+    /// `timed(Deadline) at A { .. } else { .. }`; `A` waits at most
+    /// `deadline` for the other role to begin `body`, or goes on to
+    /// `timeout`.
+    Timed {
+        deadline: String,
+        at: String,
+        body: Vec<Stmt>,
+        timeout: Vec<Stmt>
+    }
+}
+
+/// A description which could not be parsed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Error {
+    pub line: usize,
+    pub message: String
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// The session of one role, in the shape of the session types.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Session {
+    Send(String, Box<Session>),
+    Recv(String, Box<Session>),
+    Choose(Vec<Session>),
+    Accept(Vec<Session>),
+    Nest(Box<Session>),
+    Escape(usize),
+    Rec(String, Box<Session>),
+    Var(String),
+    Par(Box<Session>, Box<Session>, Box<Session>),
+    Try(Box<Session>, Box<Session>),
+    Timed(String, Box<Session>, Box<Session>),
+    Due(String, Box<Session>, Box<Session>),
+    End
+}
+
+/// Parse a protocol description. Besides syntax errors this rejects
+/// roles which weren't declared, messages a role sends to itself,
+/// `continue` to a label which doesn't enclose it or from inside `par`,
+/// and statements after a `rec` or `continue`.
+pub fn parse(src: &str) -> Result<Spec, Error> {
+    let mut parser = Parser {
+        src: src.chars().collect(),
+        pos: 0,
+        line: 1,
+        roles: (String::new(), String::new())
+    };
+
+    parser.spec()
+}
+
+struct Parser {
+    src: Vec<char>,
+    pos: usize,
+    line: usize,
+    roles: (String, String)
+}
+
+impl Parser {
+    fn error<T>(&self, message: String) -> Result<T, Error> {
+        Err(Error {
+            line: self.line,
+            message: message
+        })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src.get(self.pos).cloned()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        self.pos += 1;
+        c
+    }
+
+    fn skip_space(&mut self) {
+        loop {
+            match self.peek() {
+                Some(c) if c.is_whitespace() => { self.bump(); },
+                Some('/') if self.src.get(self.pos + 1) == Some(&'/') => {
+                    while self.peek().map_or(false, |c| c != '\n') {
+                        self.bump();
+                    }
+                },
+                _ => return
+            }
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, Error> {
+        self.skip_space();
+
+        let mut ident = String::new();
+        while let Some(c) = self.peek() {
+            if c == '_' || c.is_alphanumeric() && (!ident.is_empty() || !c.is_numeric()) {
+                ident.push(c);
+                self.bump();
+            } else {
+                break;
+            }
+        }
+
+        if ident.is_empty() {
+            self.found("a name")
+        } else {
+            Ok(ident)
+        }
+    }
+
+    fn found<T>(&mut self, expected: &str) -> Result<T, Error> {
+        self.skip_space();
+        match self.peek() {
+            Some(c) => self.error(format!("expected {}, found `{}`", expected, c)),
+            None => self.error(format!("expected {}, found the end of the input", expected))
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_space();
+        if self.peek() == Some(c) {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), Error> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            self.found(&format!("`{}`", c))
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str) -> Result<(), Error> {
+        self.skip_space();
+        let line = self.line;
+        let word = try!(self.ident());
+        if word == keyword {
+            Ok(())
+        } else {
+            Err(Error {
+                line: line,
+                message: format!("expected `{}`, found `{}`", keyword, word)
+            })
+        }
+    }
+
+    fn at_keyword(&mut self, keyword: &str) -> bool {
+        self.skip_space();
+        let end = self.pos + keyword.len();
+        end <= self.src.len()
+            && self.src[self.pos..end].iter().cloned().eq(keyword.chars())
+            && self.src.get(end).map_or(true, |&c| c != '_' && !c.is_alphanumeric())
+    }
+
+    fn role(&mut self) -> Result<String, Error> {
+        self.skip_space();
+        let line = self.line;
+        let role = try!(self.ident());
+        if role == self.roles.0 || role == self.roles.1 {
+            Ok(role)
+        } else {
+            Err(Error {
+                line: line,
+                message: format!("`{}` is not a role of this protocol", role)
+            })
+        }
+    }
+
+    fn spec(&mut self) -> Result<Spec, Error> {
+        if self.at_keyword("global") {
+            try!(self.keyword("global"));
+        }
+        try!(self.keyword("protocol"));
+        let name = try!(self.ident());
+
+        try!(self.expect('('));
+        try!(self.keyword("role"));
+        let first = try!(self.ident());
+        try!(self.expect(','));
+        try!(self.keyword("role"));
+        let second = try!(self.ident());
+        try!(self.expect(')'));
+
+        if first == second {
+            return self.error(format!("both roles are called `{}`", first));
+        }
+        self.roles = (first, second);
+
+        let body = try!(self.block(&mut vec![]));
+
+        self.skip_space();
+        if self.peek().is_some() {
+            return self.found("the end of the input");
+        }
+
+        Ok(Spec {
+            name: name,
+            roles: self.roles.clone(),
+            body: body
+        })
+    }
+
+    fn block(&mut self, recs: &mut Vec<String>) -> Result<Vec<Stmt>, Error> {
+        try!(self.expect('{'));
+
+        let mut stmts = vec![];
+        loop {
+            if self.eat('}') {
+                return Ok(stmts);
+            }
+
+            let terminated = match stmts.last() {
+                Some(&Stmt::Rec { .. }) => Some("a `rec` block"),
+                Some(&Stmt::Continue(_)) => Some("`continue`"),
+                _ => None
+            };
+            if let Some(what) = terminated {
+                return self.error(format!("nothing may follow {}", what));
+            }
+
+            stmts.push(try!(self.stmt(recs)));
+        }
+    }
+
+    fn stmt(&mut self, recs: &mut Vec<String>) -> Result<Stmt, Error> {
+        if self.at_keyword("choice") {
+            try!(self.keyword("choice"));
+            try!(self.keyword("at"));
+            let at = try!(self.role());
+
+            let mut branches = vec![try!(self.block(recs))];
+            while self.at_keyword("or") {
+                try!(self.keyword("or"));
+                branches.push(try!(self.block(recs)));
+            }
+
+            return Ok(Stmt::Choice {
+                at: at,
+                branches: branches
+            });
+        }
+
+        if self.at_keyword("rec") {
+            try!(self.keyword("rec"));
+            let label = try!(self.ident());
+
+            recs.push(label.clone());
+            let body = self.block(recs);
+            recs.pop();
+
+            return Ok(Stmt::Rec {
+                label: label,
+                body: try!(body)
+            });
+        }
+
+        if self.at_keyword("par") {
+            try!(self.keyword("par"));
+            // each block runs over a channel of its own, so it can't
+            // continue a loop outside it
+            let first = try!(self.block(&mut vec![]));
+            try!(self.keyword("and"));
+            let second = try!(self.block(&mut vec![]));
+
+            return Ok(Stmt::Par(first, second));
+        }
+
+        if self.at_keyword("try") {
+            try!(self.keyword("try"));
+            let body = try!(self.block(recs));
+            try!(self.keyword("catch"));
+            let handler = try!(self.block(recs));
+
+            return Ok(Stmt::Try {
+                body: body,
+                handler: handler
+            });
+        }
+
+        if self.at_keyword("timed") {
+            try!(self.keyword("timed"));
+            try!(self.expect('('));
+            let deadline = try!(self.parenthesized());
+            if deadline.is_empty() {
+                return self.error("`timed` needs a deadline".to_string());
+            }
+            try!(self.keyword("at"));
+            let at = try!(self.role());
+            let body = try!(self.block(recs));
+            try!(self.keyword("else"));
+            let timeout = try!(self.block(recs));
+
+            return Ok(Stmt::Timed {
+                deadline: deadline,
+                at: at,
+                body: body,
+                timeout: timeout
+            });
+        }
+
+        if self.at_keyword("continue") {
+            try!(self.keyword("continue"));
+            self.skip_space();
+            let line = self.line;
+            let label = try!(self.ident());
+            if !recs.contains(&label) {
+                return Err(Error {
+                    line: line,
+                    message: format!("`continue {}` is not inside `rec {}`", label, label)
+                });
+            }
+            try!(self.expect(';'));
+
+            return Ok(Stmt::Continue(label));
+        }
+
+        self.message()
+    }
+
+    // Everything up to the `)` which closes one already read, trimmed.
+    fn parenthesized(&mut self) -> Result<String, Error> {
+        let mut inner = String::new();
+        let mut depth = 0;
+        loop {
+            match self.bump() {
+                Some(')') if depth == 0 => break,
+                Some(c) => {
+                    match c {
+                        '(' | '[' => depth += 1,
+                        ')' | ']' => depth -= 1,
+                        _ => {}
+                    }
+                    inner.push(c);
+                },
+                None => return self.found("`)`")
+            }
+        }
+
+        Ok(inner.trim().to_string())
+    }
+
+    fn message(&mut self) -> Result<Stmt, Error> {
+        self.skip_space();
+        let label = if self.peek() == Some('(') {
+            String::new()
+        } else {
+            try!(self.ident())
+        };
+
+        try!(self.expect('('));
+        let payload = match try!(self.parenthesized()) {
+            ref payload if payload.is_empty() => "()".to_string(),
+            payload => payload
+        };
+
+        try!(self.keyword("from"));
+        let from = try!(self.role());
+        try!(self.keyword("to"));
+        let to = try!(self.role());
+        if from == to {
+            return self.error(format!("`{}` sends a message to itself", from));
+        }
+        try!(self.expect(';'));
+
+        Ok(Stmt::Message {
+            label: label,
+            payload: payload,
+            from: from,
+            to: to
+        })
+    }
+}
+
+impl Spec {
+    /// The session of `role`, or `None` if it isn't one of the roles.
+    pub fn project(&self, role: &str) -> Option<Session> {
+        if role != self.roles.0 && role != self.roles.1 {
+            return None;
+        }
+
+        let stmts: Vec<&Stmt> = self.body.iter().collect();
+        Some(project(&stmts, role, &mut vec![]))
+    }
+
+    /// A `proto! { pub mod .. }` declaration of this protocol, with the
+    /// first role as the server. Each `rec` becomes a `loop` state named
+    /// after its label, and each branch of a `choice` a state named after
+    /// the label of its first message.
+    pub fn generate(&self) -> String {
+        let (start, session, decls) = self.states();
+
+        let mut out = String::new();
+        out.push_str("proto! {\n");
+        out.push_str(&format!("    pub mod {} {{\n", snake_case(&self.name)));
+        out.push_str(&format!("        {} = {{{}}};\n", start, session));
+        write_decls(&mut out, &decls, 2);
+        out.push_str("    }\n}\n");
+
+        out
+    }
+
+    /// The handlers `role` must implement for the declaration `generate`
+    /// writes, for the protocol type `protocol`, or `None` if `role` isn't
+    /// one of the roles. The bodies are left `unimplemented!()`, and every
+    /// handler requires its IO to transfer every payload in the protocol.
+    pub fn skeleton(&self, role: &str, protocol: &str) -> Option<String> {
+        let (side, kind) = if role == self.roles.0 {
+            ("server", "Server")
+        } else if role == self.roles.1 {
+            ("client", "Client")
+        } else {
+            return None;
+        };
+
+        let module = snake_case(&self.name);
+        let (start, _, decls) = self.states();
+
+        let mut payloads = vec![];
+        payloads_of(&self.body, &mut payloads);
+        let bounds: String = payloads.iter().map(|payload| format!(" + Transfers<{}>", payload)).collect();
+
+        let mut out = String::new();
+        out.push_str(&format!("impl Protocol for {} {{\n", protocol));
+        out.push_str(&format!("    type Initial = {}::{};\n", module, kind));
+        out.push_str("}\n\n");
+        out.push_str(&format!("impl<I: IO{}, E: SessionType> {}::{}Handlers<I, E> for {} {{ }}\n", bounds, module, kind, protocol));
+
+        let skeleton = Skeleton {
+            states: format!("{}::{}", module, side),
+            bounds: bounds,
+            protocol: protocol
+        };
+        skeleton.handler(&mut out, &start, "E");
+        skeleton.handlers(&mut out, &decls, "E");
+
+        Some(out)
+    }
+
+    // The name and session of the first state, and the declarations of
+    // the others.
+    fn states(&self) -> (String, String, Vec<Decl>) {
+        let mut names = vec![];
+        let mut decls = vec![];
+
+        let start = unique(&mut names, "Start");
+        let stmts: Vec<&Stmt> = self.body.iter().collect();
+        let session = generate(&stmts, &self.roles.0, true, &mut vec![], &mut names, &mut decls);
+
+        (start, session, decls)
+    }
+}
+
+struct Skeleton<'a> {
+    // the path of the module the states are declared in
+    states: String,
+    bounds: String,
+    protocol: &'a str
+}
+
+impl<'a> Skeleton<'a> {
+    fn handler(&self, out: &mut String, name: &str, env: &str) {
+        let state = format!("{}::{}", self.states, name);
+        out.push_str(&format!("\nimpl<I: IO{}, E: SessionType> Handler<I, {}, {}> for {} {{\n", self.bounds, env, state, self.protocol));
+        out.push_str(&format!("    fn with(this: Channel<Self, I, {}, {}>) -> Defer<Self, I> {{\n", env, state));
+        out.push_str("        unimplemented!()\n");
+        out.push_str("    }\n}\n");
+    }
+
+    fn handlers(&self, out: &mut String, decls: &[Decl], env: &str) {
+        for decl in decls {
+            match *decl {
+                Decl::State(ref name, _) => self.handler(out, name, env),
+                Decl::Loop(ref name, _, ref inner) => {
+                    // a loop's state is only reached once it was entered
+                    let env = format!("({}::{}, {})", self.states, name, env);
+                    self.handler(out, name, &env);
+                    self.handlers(out, inner, &env);
+                }
+            }
+        }
+    }
+}
+
+// Every payload type in `stmts`, once each, in the order they appear.
+fn payloads_of(stmts: &[Stmt], payloads: &mut Vec<String>) {
+    for stmt in stmts {
+        match *stmt {
+            Stmt::Message { ref payload, .. } => {
+                if !payloads.contains(payload) {
+                    payloads.push(payload.clone());
+                }
+            },
+            Stmt::Choice { ref branches, .. } => {
+                for branch in branches {
+                    payloads_of(branch, payloads);
+                }
+            },
+            Stmt::Rec { ref body, .. } => payloads_of(body, payloads),
+            Stmt::Continue(_) => {},
+            Stmt::Par(ref first, ref second) => {
+                payloads_of(first, payloads);
+                payloads_of(second, payloads);
+            },
+            Stmt::Try { ref body, ref handler } => {
+                payloads_of(body, payloads);
+                payloads_of(handler, payloads);
+            },
+            Stmt::Timed { ref body, ref timeout, .. } => {
+                payloads_of(body, payloads);
+                payloads_of(timeout, payloads);
+            }
+        }
+    }
+}
+
+fn project(stmts: &[&Stmt], role: &str, recs: &mut Vec<String>) -> Session {
+    let (first, rest) = match stmts.split_first() {
+        Some(split) => split,
+        None => return Session::End
+    };
+
+    match **first {
+        Stmt::Message { ref payload, ref from, .. } => {
+            let next = Box::new(project(rest, role, recs));
+            if from == role {
+                Session::Send(normalize(payload), next)
+            } else {
+                Session::Recv(normalize(payload), next)
+            }
+        },
+        Stmt::Choice { ref at, ref branches } => {
+            let branches = branches.iter().map(|branch| {
+                project(&then(branch, rest), role, recs)
+            }).collect();
+
+            if at == role {
+                Session::Choose(branches)
+            } else {
+                Session::Accept(branches)
+            }
+        },
+        Stmt::Rec { ref label, ref body } => {
+            recs.push(label.clone());
+            let stmts: Vec<&Stmt> = body.iter().collect();
+            let body = project(&stmts, role, recs);
+            recs.pop();
+
+            Session::Nest(Box::new(body))
+        },
+        Stmt::Continue(ref label) => {
+            Session::Escape(escape_depth(recs, label))
+        },
+        Stmt::Par(ref first, ref second) => {
+            let first = project(&then(first, &[]), role, &mut vec![]);
+            let second = project(&then(second, &[]), role, &mut vec![]);
+
+            Session::Par(Box::new(first), Box::new(second), Box::new(project(rest, role, recs)))
+        },
+        Stmt::Try { ref body, ref handler } => {
+            let body = project(&then(body, rest), role, recs);
+            let handler = project(&then(handler, rest), role, recs);
+
+            Session::Try(Box::new(body), Box::new(handler))
+        },
+        Stmt::Timed { ref deadline, ref at, ref body, ref timeout } => {
+            let body = Box::new(project(&then(body, rest), role, recs));
+            let timeout = Box::new(project(&then(timeout, rest), role, recs));
+
+            if at == role {
+                Session::Timed(normalize(deadline), body, timeout)
+            } else {
+                Session::Due(normalize(deadline), body, timeout)
+            }
+        }
+    }
+}
+
+// A block followed by the statements after the one it belongs to.
+fn then<'a>(block: &'a [Stmt], rest: &[&'a Stmt]) -> Vec<&'a Stmt> {
+    let mut stmts: Vec<&Stmt> = block.iter().collect();
+    stmts.extend(rest.iter().cloned());
+    stmts
+}
+
+fn escape_depth(recs: &[String], label: &str) -> usize {
+    recs.iter().rev().position(|rec| rec == label).expect("parse checks that every continue has its rec")
+}
+
+enum Decl {
+    State(String, String),
+    Loop(String, String, Vec<Decl>)
+}
+
+fn unique(names: &mut Vec<String>, name: &str) -> String {
+    let mut candidate = name.to_string();
+    let mut n = 1;
+    while names.contains(&candidate) {
+        n += 1;
+        candidate = format!("{}{}", name, n);
+    }
+    names.push(candidate.clone());
+
+    candidate
+}
+
+fn camel_case(label: &str) -> String {
+    label.split('_').filter(|word| !word.is_empty()).map(|word| {
+        let mut chars = word.chars();
+        match chars.next() {
+            Some(c) => c.to_uppercase().chain(chars).collect(),
+            None => String::new()
+        }
+    }).collect()
+}
+
+fn snake_case(name: &str) -> String {
+    let mut out = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                out.push('_');
+            }
+            out.extend(c.to_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+
+    out
+}
+
+// The session of `role` in `proto!` syntax. While `named`, each branch of
+// a `choice` and each `rec` becomes a state of its own in `decls`; inside
+// `par` and `try` blocks, whose handlers run in other environments, they
+// are written out in place.
+fn generate(stmts: &[&Stmt], role: &str, named: bool, recs: &mut Vec<String>, names: &mut Vec<String>, decls: &mut Vec<Decl>) -> String {
+    let (first, rest) = match stmts.split_first() {
+        Some(split) => split,
+        None => return "End".to_string()
+    };
+
+    match **first {
+        Stmt::Message { ref payload, ref from, .. } => {
+            let next = generate(rest, role, named, recs, names, decls);
+            let dir = if from == role { "Send" } else { "Recv" };
+
+            format!("{} {}, {}", dir, payload, next)
+        },
+        Stmt::Choice { ref at, ref branches } => {
+            let mut gotos = vec![];
+            for branch in branches {
+                let session = generate(&then(branch, rest), role, named, recs, names, decls);
+                if !named {
+                    gotos.push(format!("{{{}}}", session));
+                    continue;
+                }
+
+                let label = first_label(branch).map(|label| camel_case(&label));
+                let name = unique(names, &label.unwrap_or("Branch".to_string()));
+
+                decls.push(Decl::State(name.clone(), session));
+                gotos.push(format!("{{goto {}}}", name));
+            }
+
+            let dir = if at == role { "Choose" } else { "Accept" };
+            format!("{} {{ {} }}", dir, gotos.join(", "))
+        },
+        Stmt::Rec { ref label, ref body } => {
+            let name = if named { Some(unique(names, label)) } else { None };
+
+            recs.push(label.clone());
+            let mut inner = vec![];
+            let stmts: Vec<&Stmt> = body.iter().collect();
+            let session = generate(&stmts, role, named, recs, names, &mut inner);
+            recs.pop();
+
+            match name {
+                Some(name) => {
+                    decls.push(Decl::Loop(name.clone(), session, inner));
+                    format!("loop {{ goto {} }}", name)
+                },
+                None => format!("loop {{ {} }}", session)
+            }
+        },
+        Stmt::Continue(ref label) => {
+            match escape_depth(recs, label) {
+                0 => "continue".to_string(),
+                n => format!("continue {}", n)
+            }
+        },
+        Stmt::Par(ref first, ref second) => {
+            let first = generate(&then(first, &[]), role, false, &mut vec![], names, decls);
+            let second = generate(&then(second, &[]), role, false, &mut vec![], names, decls);
+            let next = generate(rest, role, named, recs, names, decls);
+
+            format!("Par {{ {{{}}}, {{{}}} }}, {}", first, second, next)
+        },
+        Stmt::Try { ref body, ref handler } => {
+            let body = generate(&then(body, rest), role, false, recs, names, decls);
+            let handler = generate(&then(handler, rest), role, false, recs, names, decls);
+
+            format!("try {{ {} }} catch {{ {} }}", body, handler)
+        },
+        Stmt::Timed { ref deadline, ref at, ref body, ref timeout } => {
+            let body = generate(&then(body, rest), role, named, recs, names, decls);
+            let timeout = generate(&then(timeout, rest), role, named, recs, names, decls);
+            let dir = if at == role { "Timed" } else { "Due" };
+
+            format!("{} {} {{ {} }} else {{ {} }}", dir, deadline, body, timeout)
+        }
+    }
+}
+
+// The label of the first labelled message in `stmts`, looking into the
+// blocks which are run first.
+fn first_label(stmts: &[Stmt]) -> Option<String> {
+    stmts.iter().filter_map(|stmt| match *stmt {
+        Stmt::Message { ref label, .. } if !label.is_empty() => Some(label.clone()),
+        Stmt::Try { ref body, .. } | Stmt::Timed { ref body, .. } => first_label(body),
+        _ => None
+    }).next()
+}
+
+fn write_decls(out: &mut String, decls: &[Decl], depth: usize) {
+    let indent: String = iter::repeat("    ").take(depth).collect();
+    for decl in decls {
+        match *decl {
+            Decl::State(ref name, ref session) => {
+                out.push_str(&format!("{}{} = {{{}}};\n", indent, name, session));
+            },
+            Decl::Loop(ref name, ref session, ref inner) => {
+                out.push_str(&format!("{}loop {} = {{{}}} {{\n", indent, name, session));
+                write_decls(out, inner, depth + 1);
+                out.push_str(&format!("{}}}\n", indent));
+            }
+        }
+    }
+}
+
+impl Session {
+    /// Describe the session type `S`.
+    pub fn of<S: Describe>() -> Session {
+        S::describe()
+    }
+
+    /// Write this session out as the protocol `name`, between `us`, whose
+    /// session it is, and `them`. Session types don't record labels, so
+    /// messages are unlabelled and `rec` blocks are named `L0`, `L1` and
+    /// so on by depth.
+    pub fn to_spec(&self, name: &str, us: &str, them: &str) -> Spec {
+        Spec {
+            name: name.to_string(),
+            roles: (us.to_string(), them.to_string()),
+            body: self.stmts(us, them, 0)
+        }
+    }
+
+    fn stmts(&self, us: &str, them: &str, depth: usize) -> Vec<Stmt> {
+        let message = |payload: &String, from: &str, to: &str| Stmt::Message {
+            label: String::new(),
+            payload: payload.clone(),
+            from: from.to_string(),
+            to: to.to_string()
+        };
+
+        let (stmt, next) = match *self {
+            Session::Send(ref payload, ref next) => (message(payload, us, them), next),
+            Session::Recv(ref payload, ref next) => (message(payload, them, us), next),
+            Session::Choose(ref branches) | Session::Accept(ref branches) => {
+                let at = match *self {
+                    Session::Choose(_) => us,
+                    _ => them
+                };
+
+                return vec![Stmt::Choice {
+                    at: at.to_string(),
+                    branches: branches.iter().map(|branch| branch.stmts(us, them, depth)).collect()
+                }];
+            },
+            Session::Nest(ref body) => {
+                return vec![Stmt::Rec {
+                    label: format!("L{}", depth),
+                    body: body.stmts(us, them, depth + 1)
+                }];
+            },
+            Session::Escape(n) => {
+                let label = depth.checked_sub(n + 1).expect("escape from outside every rec");
+                return vec![Stmt::Continue(format!("L{}", label))];
+            },
+            Session::Rec(ref label, ref body) => {
+                return vec![Stmt::Rec {
+                    label: label.clone(),
+                    body: body.stmts(us, them, depth)
+                }];
+            },
+            Session::Var(ref label) => return vec![Stmt::Continue(label.clone())],
+            Session::Par(ref first, ref second, ref next) => {
+                // escapes can't leave either half, so their labels start over
+                (Stmt::Par(first.stmts(us, them, 0), second.stmts(us, them, 0)), next)
+            },
+            Session::Try(ref body, ref handler) => {
+                return vec![Stmt::Try {
+                    body: body.stmts(us, them, depth),
+                    handler: handler.stmts(us, them, depth)
+                }];
+            },
+            Session::Timed(ref deadline, ref body, ref timeout) | Session::Due(ref deadline, ref body, ref timeout) => {
+                let at = match *self {
+                    Session::Timed(..) => us,
+                    _ => them
+                };
+
+                return vec![Stmt::Timed {
+                    deadline: deadline.clone(),
+                    at: at.to_string(),
+                    body: body.stmts(us, them, depth),
+                    timeout: timeout.stmts(us, them, depth)
+                }];
+            },
+            Session::End => return vec![]
+        };
+
+        let mut stmts = vec![stmt];
+        stmts.extend(next.stmts(us, them, depth));
+        stmts
+    }
+}
+
+impl fmt::Display for Spec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "global protocol {}(role {}, role {}) ", self.name, self.roles.0, self.roles.1));
+        try!(write_block(f, &self.body, 0));
+        writeln!(f, "")
+    }
+}
+
+fn write_block(f: &mut fmt::Formatter, stmts: &[Stmt], depth: usize) -> fmt::Result {
+    let indent: String = iter::repeat("    ").take(depth + 1).collect();
+
+    try!(writeln!(f, "{{"));
+    for stmt in stmts {
+        match *stmt {
+            Stmt::Message { ref label, ref payload, ref from, ref to } => {
+                let payload = if payload == "()" { "" } else { payload };
+                try!(writeln!(f, "{}{}({}) from {} to {};", indent, label, payload, from, to));
+            },
+            Stmt::Choice { ref at, ref branches } => {
+                try!(write!(f, "{}choice at {} ", indent, at));
+                for (i, branch) in branches.iter().enumerate() {
+                    if i > 0 {
+                        try!(write!(f, " or "));
+                    }
+                    try!(write_block(f, branch, depth + 1));
+                }
+                try!(writeln!(f, ""));
+            },
+            Stmt::Rec { ref label, ref body } => {
+                try!(write!(f, "{}rec {} ", indent, label));
+                try!(write_block(f, body, depth + 1));
+                try!(writeln!(f, ""));
+            },
+            Stmt::Continue(ref label) => {
+                try!(writeln!(f, "{}continue {};", indent, label));
+            },
+            Stmt::Par(ref first, ref second) => {
+                try!(write!(f, "{}par ", indent));
+                try!(write_block(f, first, depth + 1));
+                try!(write!(f, " and "));
+                try!(write_block(f, second, depth + 1));
+                try!(writeln!(f, ""));
+            },
+            Stmt::Try { ref body, ref handler } => {
+                try!(write!(f, "{}try ", indent));
+                try!(write_block(f, body, depth + 1));
+                try!(write!(f, " catch "));
+                try!(write_block(f, handler, depth + 1));
+                try!(writeln!(f, ""));
+            },
+            Stmt::Timed { ref deadline, ref at, ref body, ref timeout } => {
+                try!(write!(f, "{}timed({}) at {} ", indent, deadline, at));
+                try!(write_block(f, body, depth + 1));
+                try!(write!(f, " else "));
+                try!(write_block(f, timeout, depth + 1));
+                try!(writeln!(f, ""));
+            }
+        }
+    }
+    write!(f, "{}}}", &indent[4..])
+}
+
+/// Session types which can be described as a `Session`.
+pub trait Describe: SessionType {
+    fn describe() -> Session;
+}
+
+/// The branches of a `Choose` or `Accept`.
+pub trait Branches: SessionType {
+    fn branches() -> Vec<Session>;
+}
+
+impl Describe for End {
+    fn describe() -> Session {
+        Session::End
+    }
+}
+
+impl<T: ?Sized, S: Describe> Describe for Send<T, S> {
+    fn describe() -> Session {
+        Session::Send(normalize(type_name::<T>()), Box::new(S::describe()))
+    }
+}
+
+impl<T: ?Sized, S: Describe> Describe for Recv<T, S> {
+    fn describe() -> Session {
+        Session::Recv(normalize(type_name::<T>()), Box::new(S::describe()))
+    }
+}
+
+impl<S: Describe> Describe for Nest<S> {
+    fn describe() -> Session {
+        Session::Nest(Box::new(S::describe()))
+    }
+}
+
+impl<N: Peano> Describe for Escape<N> {
+    fn describe() -> Session {
+        Session::Escape(N::to_usize())
+    }
+}
+
+impl<S: Describe, Q: Branches> Describe for Choose<S, Q> {
+    fn describe() -> Session {
+        Session::Choose(<Self as Branches>::branches())
+    }
+}
+
+impl<S: Describe, Q: Branches> Describe for Accept<S, Q> {
+    fn describe() -> Session {
+        Session::Accept(<Self as Branches>::branches())
+    }
+}
+
+impl<S: Describe, Q: Branches> Branches for Choose<S, Q> {
+    fn branches() -> Vec<Session> {
+        let mut branches = vec![S::describe()];
+        branches.extend(Q::branches());
+        branches
+    }
+}
+
+impl<S: Describe, Q: Branches> Branches for Accept<S, Q> {
+    fn branches() -> Vec<Session> {
+        let mut branches = vec![S::describe()];
+        branches.extend(Q::branches());
+        branches
+    }
+}
+
+impl<S: Describe> Branches for Finally<S> {
+    fn branches() -> Vec<Session> {
+        vec![S::describe()]
+    }
+}
+
+impl<L, S: Describe> Describe for Rec<L, S> {
+    fn describe() -> Session {
+        Session::Rec(normalize(type_name::<L>()), Box::new(S::describe()))
+    }
+}
+
+impl<L> Describe for Var<L> {
+    fn describe() -> Session {
+        Session::Var(normalize(type_name::<L>()))
+    }
+}
+
+impl<S1: Describe, S2: Describe, Next: Describe> Describe for Par<S1, S2, Next> {
+    fn describe() -> Session {
+        Session::Par(Box::new(S1::describe()), Box::new(S2::describe()), Box::new(Next::describe()))
+    }
+}
+
+impl<B: Describe, H: Describe> Describe for Try<B, H> {
+    fn describe() -> Session {
+        Session::Try(Box::new(B::describe()), Box::new(H::describe()))
+    }
+}
+
+impl<D: Deadline, S: Describe, T: Describe> Describe for Timed<D, S, T> {
+    fn describe() -> Session {
+        Session::Timed(normalize(type_name::<D>()), Box::new(S::describe()), Box::new(T::describe()))
+    }
+}
+
+impl<D: Deadline, S: Describe, T: Describe> Describe for Due<D, S, T> {
+    fn describe() -> Session {
+        Session::Due(normalize(type_name::<D>()), Box::new(S::describe()), Box::new(T::describe()))
+    }
+}
@@ -0,0 +1,379 @@
+//! A compact binary encoding for values that cross an `IO` boundary as
+//! bytes. Integers which tend to be small (lengths, discriminants) are
+//! written as variable length integers, as suggested by `IO`.
+
+/// Types which can be written to a byte buffer.
+pub trait Encode {
+    /// Append the encoding of `self` to `out`.
+    fn encode(&self, out: &mut Vec<u8>);
+
+    /// Append the encoding of `self` to `out`, except for a trailing run
+    /// of bytes which `self` already holds. Those are returned instead,
+    /// so that byte backends can write them without copying.
+    fn encode_split<'a>(&'a self, out: &mut Vec<u8>) -> Option<&'a [u8]> {
+        self.encode(out);
+        None
+    }
+}
+
+/// Types which can be read back from a byte buffer. Decoding must
+/// never panic on malformed input; it returns `None` instead.
+pub trait Decode: Sized {
+    /// Decode a value from the front of `input`, advancing it past the
+    /// bytes which were consumed.
+    fn decode(input: &mut &[u8]) -> Option<Self>;
+}
+
+/// The longest list of zero-sized values `Decode` accepts, since their
+/// length is all there is on the wire.
+pub const MAX_EMPTY: usize = 1 << 16;
+
+/// Encode `val` into a fresh buffer.
+pub fn to_bytes<T: Encode + ?Sized>(val: &T) -> Vec<u8> {
+    let mut out = vec![];
+    val.encode(&mut out);
+    out
+}
+
+/// Decode a `T` which must occupy all of `bytes`.
+pub fn from_bytes<T: Decode>(mut bytes: &[u8]) -> Option<T> {
+    match T::decode(&mut bytes) {
+        Some(val) => if bytes.is_empty() { Some(val) } else { None },
+        None => None
+    }
+}
+
+/// Render bytes as lowercase hex, for diagnostics.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Write a variable length integer: seven bits per byte, least
+/// significant group first, with the high bit set on every byte
+/// except the last.
+pub fn write_varint(out: &mut Vec<u8>, mut num: u64) {
+    while num >= 0x80 {
+        out.push((num as u8) | 0x80);
+        num >>= 7;
+    }
+    out.push(num as u8);
+}
+
+/// Read a variable length integer written by `write_varint`.
+pub fn read_varint(input: &mut &[u8]) -> Option<u64> {
+    let mut num: u64 = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = match input.first() {
+            Some(&byte) => byte,
+            None => return None
+        };
+        *input = &input[1..];
+
+        if shift == 63 && byte > 1 {
+            // overflows a u64
+            return None;
+        }
+
+        num |= ((byte & 0x7f) as u64) << shift;
+
+        if byte & 0x80 == 0 {
+            return Some(num);
+        }
+
+        shift += 7;
+    }
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if input.len() < len {
+        return None;
+    }
+
+    let (head, tail) = input.split_at(len);
+    *input = tail;
+
+    Some(head)
+}
+
+macro_rules! fixed {
+    ($($t:ty, $len:expr);*) => ($(
+        impl Encode for $t {
+            fn encode(&self, out: &mut Vec<u8>) {
+                let mut val = *self as u64;
+                for _ in 0..$len {
+                    out.push(val as u8);
+                    val >>= 8;
+                }
+            }
+        }
+
+        impl Decode for $t {
+            fn decode(input: &mut &[u8]) -> Option<$t> {
+                take(input, $len).map(|bytes| {
+                    bytes.iter().rev().fold(0u64, |acc, &b| (acc << 8) | b as u64) as $t
+                })
+            }
+        }
+    )*)
+}
+
+fixed!(u8, 1; u16, 2; u32, 4; u64, 8; i8, 1; i16, 2; i32, 4; i64, 8);
+
+impl Encode for usize {
+    fn encode(&self, out: &mut Vec<u8>) {
+        write_varint(out, *self as u64);
+    }
+}
+
+impl Decode for usize {
+    fn decode(input: &mut &[u8]) -> Option<usize> {
+        read_varint(input).and_then(|num| {
+            if num > usize::max_value() as u64 { None } else { Some(num as usize) }
+        })
+    }
+}
+
+impl Encode for isize {
+    fn encode(&self, out: &mut Vec<u8>) {
+        // zig-zag so that small negative numbers stay short
+        let num = *self as i64;
+        write_varint(out, ((num << 1) ^ (num >> 63)) as u64);
+    }
+}
+
+impl Decode for isize {
+    fn decode(input: &mut &[u8]) -> Option<isize> {
+        read_varint(input).and_then(|num| {
+            let num = ((num >> 1) as i64) ^ -((num & 1) as i64);
+            if num > isize::max_value() as i64 || num < isize::min_value() as i64 {
+                None
+            } else {
+                Some(num as isize)
+            }
+        })
+    }
+}
+
+impl Encode for bool {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+}
+
+impl Decode for bool {
+    fn decode(input: &mut &[u8]) -> Option<bool> {
+        match take(input, 1).map(|bytes| bytes[0]) {
+            Some(0) => Some(false),
+            Some(1) => Some(true),
+            _ => None
+        }
+    }
+}
+
+impl Encode for char {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as u32).encode(out)
+    }
+}
+
+impl Decode for char {
+    fn decode(input: &mut &[u8]) -> Option<char> {
+        u32::decode(input).and_then(::std::char::from_u32)
+    }
+}
+
+impl Encode for () {
+    fn encode(&self, _: &mut Vec<u8>) { }
+}
+
+impl Decode for () {
+    fn decode(_: &mut &[u8]) -> Option<()> {
+        Some(())
+    }
+}
+
+impl Encode for [u8] {
+    fn encode(&self, out: &mut Vec<u8>) {
+        write_varint(out, self.len() as u64);
+        out.extend_from_slice(self);
+    }
+
+    fn encode_split<'a>(&'a self, out: &mut Vec<u8>) -> Option<&'a [u8]> {
+        write_varint(out, self.len() as u64);
+        Some(self)
+    }
+}
+
+impl Encode for str {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.as_bytes().encode(out)
+    }
+
+    fn encode_split<'a>(&'a self, out: &mut Vec<u8>) -> Option<&'a [u8]> {
+        self.as_bytes().encode_split(out)
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.as_bytes().encode(out)
+    }
+
+    fn encode_split<'a>(&'a self, out: &mut Vec<u8>) -> Option<&'a [u8]> {
+        self.as_bytes().encode_split(out)
+    }
+}
+
+impl Decode for String {
+    fn decode(input: &mut &[u8]) -> Option<String> {
+        let len = match usize::decode(input) {
+            Some(len) => len,
+            None => return None
+        };
+
+        take(input, len).and_then(|bytes| String::from_utf8(bytes.to_vec()).ok())
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        write_varint(out, self.len() as u64);
+        for item in self {
+            item.encode(out);
+        }
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(input: &mut &[u8]) -> Option<Vec<T>> {
+        let len = match usize::decode(input) {
+            Some(len) => len,
+            None => return None
+        };
+
+        // The length is untrusted, so we don't reserve space for it up
+        // front. Every element of a sized type takes at least a byte, so
+        // a longer list can't be in the input; zero-sized elements take
+        // none, and are only accepted up to `MAX_EMPTY`.
+        if ::std::mem::size_of::<T>() != 0 && len > input.len() {
+            return None;
+        }
+        if ::std::mem::size_of::<T>() == 0 && len > MAX_EMPTY {
+            return None;
+        }
+
+        let mut items = vec![];
+        for _ in 0..len {
+            match T::decode(input) {
+                Some(item) => items.push(item),
+                None => return None
+            }
+        }
+
+        Some(items)
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            Some(ref val) => {
+                out.push(1);
+                val.encode(out);
+            },
+            None => out.push(0)
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode(input: &mut &[u8]) -> Option<Option<T>> {
+        match bool::decode(input) {
+            Some(true) => T::decode(input).map(Some),
+            Some(false) => Some(None),
+            None => None
+        }
+    }
+}
+
+impl<'a, T: Encode + ?Sized> Encode for &'a T {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (**self).encode(out)
+    }
+
+    fn encode_split<'b>(&'b self, out: &mut Vec<u8>) -> Option<&'b [u8]> {
+        (**self).encode_split(out)
+    }
+}
+
+impl<T: Encode + ?Sized> Encode for Box<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (**self).encode(out)
+    }
+
+    fn encode_split<'a>(&'a self, out: &mut Vec<u8>) -> Option<&'a [u8]> {
+        (**self).encode_split(out)
+    }
+}
+
+impl<T: Decode> Decode for Box<T> {
+    fn decode(input: &mut &[u8]) -> Option<Box<T>> {
+        T::decode(input).map(Box::new)
+    }
+}
+
+macro_rules! tuple {
+    ($($name:ident),*) => (
+        impl<$($name: Encode),*> Encode for ($($name,)*) {
+            #[allow(non_snake_case)]
+            fn encode(&self, out: &mut Vec<u8>) {
+                let ($(ref $name,)*) = *self;
+                $($name.encode(out);)*
+            }
+        }
+
+        impl<$($name: Decode),*> Decode for ($($name,)*) {
+            fn decode(input: &mut &[u8]) -> Option<($($name,)*)> {
+                Some(($(match $name::decode(input) {
+                    Some(val) => val,
+                    None => return None
+                },)*))
+            }
+        }
+    )
+}
+
+tuple!(A);
+tuple!(A, B);
+tuple!(A, B, C);
+tuple!(A, B, C, D);
+
+#[test]
+fn check_round_trips() {
+    fn round_trip<T: Encode + Decode + PartialEq + ::std::fmt::Debug>(val: T) {
+        assert_eq!(from_bytes::<T>(&to_bytes(&val)), Some(val));
+    }
+
+    round_trip(0u8);
+    round_trip(0xdeadbeefu32);
+    round_trip(-5i16);
+    round_trip(300usize);
+    round_trip(-300isize);
+    round_trip(true);
+    round_trip('x');
+    round_trip(String::from("nemo"));
+    round_trip(vec![1u64, 2, 3]);
+    round_trip(Some((1u8, String::from("a"))));
+
+    assert_eq!(to_bytes(&300usize), vec![0xac, 0x02]);
+
+    // malformed input never panics
+    assert_eq!(from_bytes::<String>(&[5, b'a']), None);
+    assert_eq!(from_bytes::<Vec<u64>>(&[0xff, 0xff, 0xff, 0xff, 0x0f]), None);
+    assert_eq!(from_bytes::<Vec<()>>(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]), None);
+    assert_eq!(from_bytes::<Vec<()>>(&[3]), Some(vec![(), (), ()]));
+    assert_eq!(from_bytes::<bool>(&[2]), None);
+    assert_eq!(read_varint(&mut &[0xff; 11][..]), None);
+}
@@ -0,0 +1,18 @@
+extern crate nemo;
+use nemo::*;
+use nemo::session_types::*;
+
+fn main() {
+    use nemo::channels::Blocking;
+
+    struct MyProtocol;
+
+    impl Protocol for MyProtocol {
+        type Initial = Send<u64, Recv<u64, End>>;
+    }
+
+    let (client, _) = Blocking::new::<MyProtocol>(MyProtocol, MyProtocol);
+
+    // there is no handler for `Recv<u64, End>`
+    client.send(10).defer(); //~ ERROR no Handler for state
+}
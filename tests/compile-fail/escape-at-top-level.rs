@@ -0,0 +1,16 @@
+extern crate nemo;
+use nemo::*;
+use nemo::session_types::*;
+use nemo::peano::*;
+
+fn main() {
+    use nemo::channels::Blocking;
+
+    struct MyProtocol;
+
+    impl Protocol for MyProtocol {
+        type Initial = Recv<usize, Escape<Z>>;
+    }
+
+    let _ = Blocking::new::<MyProtocol>(MyProtocol, MyProtocol); //~ ERROR cannot escape
+}
@@ -0,0 +1,17 @@
+extern crate nemo;
+use nemo::*;
+use nemo::session_types::*;
+use nemo::peano::*;
+
+fn main() {
+    use nemo::channels::Blocking;
+
+    struct MyProtocol;
+
+    // escapes two scopes, but only one encloses it
+    impl Protocol for MyProtocol {
+        type Initial = Nest<Send<usize, Escape<S<Z>>>>;
+    }
+
+    let _ = Blocking::new::<MyProtocol>(MyProtocol, MyProtocol); //~ ERROR cannot escape
+}
@@ -0,0 +1,13 @@
+extern crate nemo;
+use nemo::session_types::*;
+
+fn compatible<A: Compatible<B>, B>() { }
+
+fn main() {
+    type Server = Offer<Accept<Recv<u64, End>, Finally<Recv<u64, Send<u64, End>>>>>;
+
+    // the client's version 2 sends a string where the server expects a u64
+    type Client = Select<Choose<Send<u64, End>, Finally<Send<String, Recv<u64, End>>>>>;
+
+    compatible::<Server, Client>(); //~ ERROR type mismatch resolving
+}
@@ -0,0 +1,19 @@
+extern crate nemo;
+use nemo::*;
+use nemo::session_types::*;
+
+fn main() {
+    use nemo::channels::Blocking;
+
+    struct MyProtocol;
+
+    impl Protocol for MyProtocol {
+        type Initial = Par<Send<usize, End>, End, End>;
+    }
+
+    let (client, _) = Blocking::new(MyProtocol, MyProtocol);
+    let _ = client.split(|data, done, rest| {
+        // the first half still has a `usize` to send
+        rest.join(data, done) //~ ERROR mismatched types
+    });
+}
@@ -0,0 +1,21 @@
+extern crate nemo;
+use nemo::*;
+use nemo::session_types::*;
+
+fn main() {
+    use nemo::channels::Blocking;
+
+    struct MyProtocol;
+
+    impl Protocol for MyProtocol {
+        type Initial = Par<End, End, End>;
+    }
+
+    let (a, b) = Blocking::new(MyProtocol, MyProtocol);
+    let _ = a.split(|x, y, _| {
+        b.split(|_, _, rest| {
+            // the halves of `a` can't resume `b`
+            let _ = rest.join(x, y); //~ ERROR cannot infer an appropriate lifetime
+        })
+    });
+}
@@ -0,0 +1,9 @@
+#![feature(type_macros)]
+#[macro_use]
+extern crate nemo;
+use nemo::session_types::*;
+
+fn main() {
+    // loop labels are types, not lifetimes
+    let _: Option<proto!(loop 'outer { Send u64, continue 'outer })> = None; //~ ERROR loops are labelled with a type
+}
@@ -0,0 +1,51 @@
+#![feature(type_macros)]
+
+#[macro_use]
+extern crate nemo;
+use nemo::*;
+use nemo::session_types::*;
+use nemo::peano::*;
+
+proto! {
+    mod echo {
+        Start = {loop { goto Menu }};
+
+        loop Menu = {Accept { {goto Echo}, {goto Quit} }} {
+            Echo = {Recv u64, Send u64, continue};
+            Quit = {End};
+        }
+    }
+}
+
+struct Echo;
+
+impl Protocol for Echo {
+    type Initial = echo::Server;
+}
+
+handlers!(
+    Echo(u64);
+
+    this(echo::server::Menu => echo::server::Menu) => {
+        match this.accept() {
+            Ok(d) => d,
+            Err(this) => this.defer()
+        }
+    }
+
+    this(echo::server::Menu => echo::server::Echo) => {
+        match this.recv() {
+            Ok((num, this)) => this.send(num).pop().defer(),
+            Err(this) => this.defer()
+        }
+    }
+
+    this(echo::server::Menu => echo::server::Quit) => {
+        this.close()
+    }
+);
+
+// the handler for `Start` was forgotten
+impl<I: Transfers<u64>, E: SessionType> echo::ServerHandlers<I, E> for Echo { } //~ ERROR no Handler for state
+
+fn main() { }
@@ -0,0 +1,17 @@
+extern crate nemo;
+use nemo::*;
+use nemo::session_types::*;
+
+fn main() {
+    use nemo::channels::Blocking;
+
+    struct MyProtocol;
+
+    // the loop body finishes in neither `End` nor an `Escape`
+    impl Protocol for MyProtocol {
+        type Initial = Nest<Send<usize, ()>>;
+    }
+
+    let _ = Blocking::new::<MyProtocol>(MyProtocol, MyProtocol); //~ ERROR the trait bound
+    //~| ERROR the trait bound
+}
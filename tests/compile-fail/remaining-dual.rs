@@ -0,0 +1,9 @@
+extern crate nemo;
+use nemo::session_types::*;
+
+fn paired<S: SessionType<Dual = D>, D: SessionType>() { }
+
+fn main() {
+    // the peer of a paused receiver has already sent its count
+    paired::<Remaining<u64, End>, SendMany<u64, End>>(); //~ ERROR type mismatch
+}
@@ -0,0 +1,11 @@
+extern crate nemo;
+use nemo::session_types::*;
+
+fn compatible<A: Compatible<B>, B>() { }
+
+fn main() {
+    type Server = Offer<Accept<Recv<u64, End>, Finally<Recv<u64, Send<u64, End>>>>>;
+
+    // two servers each wait for the other to select a version
+    compatible::<Server, Server>(); //~ ERROR the versions of
+}
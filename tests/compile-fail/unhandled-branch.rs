@@ -0,0 +1,20 @@
+extern crate nemo;
+use nemo::*;
+use nemo::session_types::*;
+
+fn main() {
+    use nemo::channels::Blocking;
+
+    struct MyProtocol;
+
+    impl Protocol for MyProtocol {
+        type Initial = Accept<Recv<u64, End>, Finally<End>>;
+    }
+
+    let (server, _) = Blocking::new::<MyProtocol>(MyProtocol, MyProtocol);
+
+    // neither branch has a handler
+    let _ = server.accept();
+    //~^ ERROR no Handler for state `nemo::session_types::Recv
+    //~| ERROR no Handler for state `nemo::session_types::End
+}
@@ -0,0 +1,17 @@
+extern crate nemo;
+use nemo::*;
+use nemo::session_types::*;
+
+fn main() {
+    use nemo::channels::Blocking;
+
+    struct MyProtocol;
+
+    impl Protocol for MyProtocol {
+        type Initial = Choose<Send<u64, End>, Finally<End>>;
+    }
+
+    let (client, _) = Blocking::new::<MyProtocol>(MyProtocol, MyProtocol);
+
+    client.choose::<Send<String, End>>(); //~ ERROR is not offered by
+}
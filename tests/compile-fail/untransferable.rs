@@ -0,0 +1,27 @@
+extern crate nemo;
+use nemo::*;
+use nemo::session_types::*;
+
+// can only carry numbers
+struct Numbers;
+
+unsafe impl IO for Numbers {
+    unsafe fn close(&mut self) { }
+    unsafe fn send_discriminant(&mut self, _: usize) { }
+    unsafe fn recv_discriminant(&mut self) -> Option<usize> { None }
+}
+
+unsafe impl Transfers<u64> for Numbers {
+    unsafe fn send(&mut self, _: u64) { }
+    unsafe fn recv(&mut self) -> Option<u64> { None }
+}
+
+fn main() {
+    struct MyProtocol;
+
+    impl Protocol for MyProtocol {
+        type Initial = Send<String, End>;
+    }
+
+    channel(Numbers, MyProtocol).send(String::new()); //~ ERROR cannot transfer a
+}
@@ -1,20 +1,192 @@
-extern crate compiletest_rs as compiletest;
+//! Runs the programs in `tests/run-fail` and `tests/compile-fail` against the
+//! built crate.
+//!
+//! A run-fail program must build, and then fail at run time with every
+//! `// error-pattern:` message in its output. A compile-fail program must not
+//! build, and rustc must report an error containing `msg` on each line marked
+//! `//~ ERROR msg` (`//~^` for the line above, `//~|` for the same line as the
+//! previous marker), and no error anywhere else.
 
-use std::path::PathBuf;
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
 
-fn run_mode(mode: &'static str) {
+/// `target/<profile>`, where cargo leaves `libnemo.rlib` next to `deps`.
+fn target_dir() -> PathBuf {
+    let exe = env::current_exe().unwrap();
+    exe.parent().unwrap().parent().unwrap().to_path_buf()
+}
+
+fn sources(mode: &str) -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join(mode);
+    let mut files: Vec<PathBuf> = match fs::read_dir(&dir) {
+        Ok(entries) => entries.map(|e| e.unwrap().path())
+                              .filter(|p| p.extension().map_or(false, |e| e == "rs"))
+                              .collect(),
+        Err(_) => vec![],
+    };
+    files.sort();
+    files
+}
+
+fn read(path: &Path) -> String {
+    let mut s = String::new();
+    fs::File::open(path).unwrap().read_to_string(&mut s).unwrap();
+    s
+}
+
+fn rustc(src: &Path) -> (PathBuf, Output) {
+    let dir = target_dir();
+    let out = dir.join("fail-tests");
+    let _ = fs::create_dir_all(&out);
+    let out = out.join(src.file_stem().unwrap());
+
+    let rustc = env::var("RUSTC").unwrap_or("rustc".to_string());
+    let output = Command::new(rustc)
+        .arg(src)
+        .arg("-o").arg(&out)
+        .arg("-L").arg(dir.join("deps"))
+        .arg("--extern").arg(format!("nemo={}", dir.join("libnemo.rlib").display()))
+        .arg("--error-format=short")
+        .output()
+        .unwrap();
+
+    (out, output)
+}
 
-    let mut config = compiletest::default_config();
-    let cfg_mode = mode.parse().ok().expect("Invalid mode");
+fn text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+/// The `(line, message)` pairs a compile-fail program expects.
+fn expectations(src: &str) -> Vec<(usize, String)> {
+    let mut expected: Vec<(usize, String)> = vec![];
+
+    for (i, line) in src.lines().enumerate() {
+        let at = match line.find("//~") {
+            Some(at) => at + 3,
+            None => continue
+        };
+        let rest = &line[at..];
+        let marker = rest.find(' ').unwrap_or(rest.len());
+        let (marker, rest) = rest.split_at(marker);
+        let rest = rest.trim();
+        if !rest.starts_with("ERROR") {
+            continue;
+        }
+        let msg = rest["ERROR".len()..].trim().to_string();
+
+        let line = if marker == "|" {
+            expected.last().expect("`//~|` follows another marker").0
+        } else {
+            i + 1 - marker.len()
+        };
+
+        expected.push((line, msg));
+    }
 
-    config.mode = cfg_mode;
-    config.target_rustcflags = Some("-L target/debug -L target/debug/deps".to_string());
-    config.src_base = PathBuf::from(format!("tests/{}", mode));
+    expected
+}
+
+/// The `(line, message)` pairs of the errors in `--error-format=short` output.
+fn errors(path: &Path, stderr: &str) -> Vec<(Option<usize>, String)> {
+    let prefix = format!("{}:", path.display());
+
+    stderr.lines().filter_map(|line| {
+        if line.starts_with("error") {
+            if line.starts_with("error: aborting due to") {
+                None
+            } else {
+                Some((None, line.to_string()))
+            }
+        } else if line.starts_with(&prefix) && line.contains(" - error") {
+            let n = line[prefix.len()..].split(':').next().unwrap().parse().ok();
+            Some((n, line.to_string()))
+        } else {
+            None
+        }
+    }).collect()
+}
 
-    compiletest::run_tests(&config);
+fn check_compile_fail(path: &Path) -> Result<(), String> {
+    let expected = expectations(&read(path));
+    let (_, output) = rustc(path);
+    let stderr = text(&output.stderr);
+
+    if output.status.success() {
+        return Err("compiled successfully".to_string());
+    }
+
+    let found = errors(path, &stderr);
+    let mut problems = vec![];
+
+    for &(line, ref msg) in &expected {
+        if !found.iter().any(|&(n, ref e)| n == Some(line) && e.contains(&msg[..])) {
+            problems.push(format!("expected an error on line {} containing {:?}", line, msg));
+        }
+    }
+    for &(n, ref e) in &found {
+        if !expected.iter().any(|&(line, _)| n == Some(line)) {
+            problems.push(format!("unexpected error: {}", e));
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("{}\n--- stderr ---\n{}", problems.join("\n"), stderr))
+    }
+}
+
+fn check_run_fail(path: &Path) -> Result<(), String> {
+    let src = read(path);
+    let patterns: Vec<&str> = src.lines()
+                                 .filter(|l| l.starts_with("// error-pattern:"))
+                                 .map(|l| l["// error-pattern:".len()..].trim())
+                                 .collect();
+
+    let (bin, output) = rustc(path);
+    if !output.status.success() {
+        return Err(format!("failed to compile\n--- stderr ---\n{}", text(&output.stderr)));
+    }
+
+    let output = Command::new(&bin).output().unwrap();
+    let all = format!("{}{}", text(&output.stdout), text(&output.stderr));
+
+    if output.status.success() {
+        return Err(format!("ran successfully\n--- output ---\n{}", all));
+    }
+    for pattern in patterns {
+        if !all.contains(pattern) {
+            return Err(format!("output lacks {:?}\n--- output ---\n{}", pattern, all));
+        }
+    }
+
+    Ok(())
+}
+
+fn run_mode(mode: &str, check: fn(&Path) -> Result<(), String>) {
+    let mut failures = vec![];
+
+    for path in sources(mode) {
+        if let Err(e) = check(&path) {
+            failures.push(format!("{}: {}", path.display(), e));
+        }
+    }
+
+    if !failures.is_empty() {
+        panic!("{} {} test(s) failed:\n\n{}", failures.len(), mode, failures.join("\n\n"));
+    }
+}
+
+#[test]
+fn run_fail() {
+    run_mode("run-fail", check_run_fail);
 }
 
 #[test]
-fn compile_test() {
-    run_mode("run-fail");
+fn compile_fail() {
+    run_mode("compile-fail", check_compile_fail);
 }
@@ -27,6 +27,8 @@ fn test_proto_macro() {
     same!(Send<usize, End> = proto!(Send usize, End));
     same!(Send<usize, Recv<u8, End>> = proto!(Send usize, Recv u8, End));
     same!(Recv<usize, Send<u8, End>> = proto!(Recv usize, Send u8, End));
+    same!(SendMany<u8, End> = proto!(SendMany u8, End));
+    same!(RecvMany<String, Send<u8, End>> = proto!(RecvMany String, Send u8, End));
 
 
     // Nest/Escape
@@ -433,4 +435,2662 @@ fn initialize_protocol() {
     assert_eq!(true, client1.with()); // receives 10 from client2
     assert_eq!(false, client2.with()); // End
     assert_eq!(false, client1.with()); // End
-}
\ No newline at end of file
+}
+
+#[test]
+fn record_and_replay() {
+    use nemo::channels::{Blocking, Record, Replay, Trace, Op};
+    use std::env;
+
+    struct MyProtocol;
+
+    type Doubler = Send<usize, Recv<usize, End>>;
+
+    impl Protocol for MyProtocol {
+        type Initial = Doubler;
+    }
+
+    impl<I: Transfers<usize>, E: SessionType> Handler<I, E, Doubler> for MyProtocol {
+        fn with(this: Channel<Self, I, E, Doubler>) -> Defer<Self, I> {
+            this.send(21).defer()
+        }
+    }
+
+    impl<I: Transfers<usize>, E: SessionType> Handler<I, E, Recv<usize, End>> for MyProtocol {
+        fn with(this: Channel<Self, I, E, Recv<usize, End>>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((msg, this)) => {
+                    assert_eq!(msg, 42);
+                    this.close()
+                },
+                Err(_) => panic!("should have received a message")
+            }
+        }
+    }
+
+    impl<I: Transfers<usize>, E: SessionType> Handler<I, E, Recv<usize, Send<usize, End>>> for MyProtocol {
+        fn with(this: Channel<Self, I, E, Recv<usize, Send<usize, End>>>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((msg, this)) => this.send(msg * 2).close(),
+                Err(_) => panic!("should have received a message")
+            }
+        }
+    }
+
+    let path = env::temp_dir().join("nemo-record-and-replay.trace");
+
+    {
+        let (io1, io2) = Blocking::pair();
+        let mut client1 = channel(Record::create(io1, &path).unwrap(), MyProtocol).defer();
+        let mut client2 = channel_dual(io2, MyProtocol).defer();
+
+        assert_eq!(true, client1.with()); // sends 21
+        assert_eq!(false, client2.with()); // doubles it
+        assert_eq!(false, client1.with()); // receives 42
+    }
+
+    let trace = Trace::open(&path).unwrap();
+    assert_eq!(trace.ops.len(), 3);
+    assert_eq!(trace.ops[2], Op::Close);
+
+    let mut replayed = channel(Replay::new(trace), MyProtocol).defer();
+    assert_eq!(true, replayed.with());
+    assert_eq!(false, replayed.with());
+}
+
+#[test]
+#[should_panic(expected = "replay diverged at operation 0")]
+fn replay_divergence() {
+    use nemo::channels::{Replay, Trace, Op};
+    use nemo::wire;
+
+    struct MyProtocol;
+
+    impl Protocol for MyProtocol {
+        type Initial = Send<usize, End>;
+    }
+
+    impl<I: Transfers<usize>, E: SessionType> Handler<I, E, Send<usize, End>> for MyProtocol {
+        fn with(this: Channel<Self, I, E, Send<usize, End>>) -> Defer<Self, I> {
+            this.send(1).close()
+        }
+    }
+
+    let trace = Trace { ops: vec![Op::Send(wire::to_bytes(&2usize)), Op::Close] };
+
+    channel(Replay::new(trace), MyProtocol).defer().with();
+}
+
+#[test]
+fn simulated_network() {
+    use nemo::channels::Sim;
+
+    #[derive(Copy, Clone)]
+    struct MyProtocol;
+
+    type Ask = Send<usize, Recv<usize, End>>;
+    type Answer = Recv<usize, Send<usize, End>>;
+
+    impl Protocol for MyProtocol {
+        type Initial = Ask;
+    }
+
+    impl<I: Transfers<usize>, E: SessionType> Handler<I, E, Ask> for MyProtocol {
+        fn with(this: Channel<Self, I, E, Ask>) -> Defer<Self, I> {
+            this.send(21).defer()
+        }
+    }
+
+    impl<I: Transfers<usize>, E: SessionType> Handler<I, E, Recv<usize, End>> for MyProtocol {
+        fn with(this: Channel<Self, I, E, Recv<usize, End>>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((msg, this)) => {
+                    assert_eq!(msg, 42);
+                    this.close()
+                },
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<usize>, E: SessionType> Handler<I, E, Answer> for MyProtocol {
+        fn with(this: Channel<Self, I, E, Answer>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((msg, this)) => this.send(msg * 2).close(),
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    // slow, but reliable
+    let sim = Sim::new(1).latency(10, 50);
+    let mut sessions = vec![];
+    for _ in 0..10 {
+        let (client, server) = sim.connect(MyProtocol, MyProtocol);
+        sessions.push(client.defer());
+        sessions.push(server.defer());
+    }
+
+    assert_eq!(sim.run(&mut sessions, 1000), 0);
+    assert!(sim.now() >= 20);
+
+    // every connection is reset, so nobody ever gets an answer
+    let sim = Sim::new(1).drops(1.0);
+    let (client, server) = sim.connect(MyProtocol, MyProtocol);
+    let mut sessions = vec![client.defer(), server.defer()];
+
+    assert_eq!(sim.run(&mut sessions, 1000), 2);
+
+    // the same seed always produces the same schedule
+    let finish = |seed| {
+        let sim = Sim::new(seed).latency(1, 100).drops(0.2);
+        let mut sessions = vec![];
+        for _ in 0..10 {
+            let (client, server) = sim.connect(MyProtocol, MyProtocol);
+            sessions.push(client.defer());
+            sessions.push(server.defer());
+        }
+
+        (sim.run(&mut sessions, 1000), sim.now())
+    };
+
+    assert_eq!(finish(7), finish(7));
+}
+
+#[test]
+fn traced_backend() {
+    use nemo::channels::{Blocking, Traced};
+    use nemo::channels::traced::{Direction, Event, Operation};
+    use std::sync::{Arc, Mutex};
+
+    struct MyProtocol;
+
+    type Orig = Send<usize, Choose<Recv<String, End>, Finally<End>>>;
+
+    impl Protocol for MyProtocol {
+        type Initial = Orig;
+    }
+
+    impl<I: Transfers<usize> + Transfers<String>, E: SessionType> Handler<I, E, Orig> for MyProtocol {
+        fn with(this: Channel<Self, I, E, Orig>) -> Defer<Self, I> {
+            match this.send(10).choose::<Recv<String, End>>().recv() {
+                Ok((msg, this)) => {
+                    assert_eq!(msg, "hello");
+                    this.close()
+                },
+                Err(_) => panic!("should have received a message")
+            }
+        }
+    }
+
+    let events = Arc::new(Mutex::new(vec![]));
+    let sink = {
+        let events = events.clone();
+        move |event: &Event| events.lock().unwrap().push(event.clone())
+    };
+
+    let (io1, mut io2) = Blocking::pair();
+    let mut client = channel(Traced::new(io1, 7, sink), MyProtocol).defer();
+
+    unsafe { Transfers::<String>::send(&mut io2, "hello".into()); }
+
+    assert_eq!(false, client.with());
+
+    let events = events.lock().unwrap();
+    assert_eq!(events.len(), 4);
+    assert!(events.iter().all(|event| event.session == 7));
+    assert_eq!(events[0].type_name, Some("usize"));
+    assert_eq!((events[1].operation, events[1].discriminant), (Operation::Discriminant, Some(0)));
+    assert_eq!((events[2].direction, events[2].received), (Direction::Inbound, true));
+    assert_eq!(events[3].operation, Operation::Close);
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn session_metrics() {
+    use nemo::channels::Blocking;
+    use nemo::metrics;
+
+    struct MyProtocol;
+
+    type Orig = Choose<Send<usize, End>, Finally<End>>;
+    type DualOrig = Accept<Recv<usize, End>, Finally<End>>;
+
+    impl Protocol for MyProtocol {
+        type Initial = Orig;
+    }
+
+    impl<I: Transfers<usize>, E: SessionType> Handler<I, E, Orig> for MyProtocol {
+        fn with(this: Channel<Self, I, E, Orig>) -> Defer<Self, I> {
+            this.choose::<Send<usize, End>>().send(1).close()
+        }
+    }
+
+    impl<I: Transfers<usize>, E: SessionType> Handler<I, E, DualOrig> for MyProtocol {
+        fn with(this: Channel<Self, I, E, DualOrig>) -> Defer<Self, I> {
+            this.accept().ok().unwrap()
+        }
+    }
+
+    impl<I: Transfers<usize>, E: SessionType> Handler<I, E, Recv<usize, End>> for MyProtocol {
+        fn with(this: Channel<Self, I, E, Recv<usize, End>>) -> Defer<Self, I> {
+            this.recv().ok().unwrap().1.close()
+        }
+    }
+
+    impl<I: Transfers<usize>, E: SessionType> Handler<I, E, End> for MyProtocol {
+        fn with(this: Channel<Self, I, E, End>) -> Defer<Self, I> {
+            this.close()
+        }
+    }
+
+    let (client1, client2) = Blocking::new::<MyProtocol>(MyProtocol, MyProtocol);
+    let mut client1 = client1.defer();
+    let mut client2 = client2.defer();
+
+    let protocol = metrics::name::<MyProtocol>();
+    let snapshot = metrics::snapshot();
+    assert_eq!(snapshot.deferred[&(protocol, metrics::name::<Orig>())], 1);
+    assert_eq!(snapshot.deferred[&(protocol, metrics::name::<DualOrig>())], 1);
+
+    assert_eq!(false, client1.with());
+    assert_eq!(false, client2.with());
+
+    let snapshot = metrics::snapshot();
+    assert_eq!(snapshot.deferred[&(protocol, metrics::name::<Orig>())], 0);
+    assert_eq!(snapshot.deferred_seconds[&(protocol, metrics::name::<Orig>())].count, 1);
+    assert_eq!(snapshot.branches[&(protocol, metrics::name::<Orig>(), metrics::Side::Choose, 0)], 1);
+    assert_eq!(snapshot.branches[&(protocol, metrics::name::<DualOrig>(), metrics::Side::Accept, 0)], 1);
+    assert_eq!(snapshot.closed[protocol], 2);
+
+    let text = snapshot.to_prometheus();
+    assert!(text.contains("# TYPE nemo_sessions_deferred gauge"));
+    assert!(text.contains(&format!("nemo_sessions_closed_total{{protocol=\"{}\"}} 2", protocol)));
+}
+
+#[test]
+fn random_conforming_peer() {
+    use nemo::fuzz;
+
+    struct Doubler {
+        strict: bool
+    }
+
+    type Menu = Accept<Double, Finally<End>>;
+    type Double = Recv<u64, Send<u64, Escape<Z>>>;
+
+    impl Protocol for Doubler {
+        type Initial = Nest<Menu>;
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, E, Nest<Menu>> for Doubler {
+        fn with(this: Channel<Self, I, E, Nest<Menu>>) -> Defer<Self, I> {
+            this.enter().defer()
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, (Menu, E), Menu> for Doubler {
+        fn with(this: Channel<Self, I, (Menu, E), Menu>) -> Defer<Self, I> {
+            match this.accept() {
+                Ok(d) => d,
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, (Menu, E), Double> for Doubler {
+        fn with(this: Channel<Self, I, (Menu, E), Double>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((num, this)) => {
+                    if this.proto.strict {
+                        assert!(num < 1000, "number too large");
+                    }
+                    this.send(num.wrapping_mul(2)).pop().defer()
+                },
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, (Menu, E), End> for Doubler {
+        fn with(this: Channel<Self, I, (Menu, E), End>) -> Defer<Self, I> {
+            this.close()
+        }
+    }
+
+    for seed in 0..20 {
+        let trace = fuzz::run(Doubler { strict: false }, seed, 100).unwrap();
+        assert_eq!(trace[0], fuzz::Step::Entered);
+        assert_eq!(trace, fuzz::run(Doubler { strict: false }, seed, 100).unwrap());
+    }
+
+    let failure = (0..20).filter_map(|seed| fuzz::run(Doubler { strict: true }, seed, 100).err())
+                         .next()
+                         .expect("some seed should send a large number");
+    assert!(failure.message.contains("number too large"));
+    assert!(failure.trace.iter().any(|step| match *step {
+        fuzz::Step::Sent("u64", _) => true,
+        _ => false
+    }));
+    assert!(format!("{}", failure).contains("peer trace:"));
+}
+
+#[test]
+fn fuzzing_long_loops() {
+    use nemo::fuzz;
+
+    // the random peer only follows along, for as long as we like
+    struct Counter {
+        left: usize
+    }
+
+    type Menu = Choose<Send<u64, Escape<Z>>, Finally<End>>;
+
+    impl Protocol for Counter {
+        type Initial = Nest<Menu>;
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, E, Nest<Menu>> for Counter {
+        fn with(this: Channel<Self, I, E, Nest<Menu>>) -> Defer<Self, I> {
+            this.enter().defer()
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, (Menu, E), Menu> for Counter {
+        fn with(mut this: Channel<Self, I, (Menu, E), Menu>) -> Defer<Self, I> {
+            if this.proto.left == 0 {
+                return this.choose::<End>().close();
+            }
+
+            this.proto.left -= 1;
+            let left = this.proto.left as u64;
+            this.choose::<Send<u64, Escape<Z>>>().send(left).pop().defer()
+        }
+    }
+
+    // far more iterations than the peer's stack would hold if every one
+    // of them nested a call
+    let trace = fuzz::run(Counter { left: 50000 }, 0, 200000).unwrap();
+    assert_eq!(trace.len(), 1 + 3 * 50000 + 2);
+    assert_eq!(trace[0], fuzz::Step::Entered);
+    assert_eq!(trace[1], fuzz::Step::Accepted(0));
+    assert_eq!(trace[trace.len() - 2], fuzz::Step::Accepted(1));
+    assert_eq!(trace[trace.len() - 1], fuzz::Step::Closed);
+}
+
+#[test]
+fn hostile_input() {
+    use nemo::channels::{Replay, Trace, Op};
+    use nemo::{fuzz, wire};
+
+    struct Counter {
+        fragile: bool
+    }
+
+    type Menu = Accept<Add, Finally<End>>;
+    type Add = Recv<u64, Send<u64, Escape<Z>>>;
+
+    impl Protocol for Counter {
+        type Initial = Nest<Menu>;
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, E, Nest<Menu>> for Counter {
+        fn with(this: Channel<Self, I, E, Nest<Menu>>) -> Defer<Self, I> {
+            this.enter().defer()
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, (Menu, E), Menu> for Counter {
+        fn with(this: Channel<Self, I, (Menu, E), Menu>) -> Defer<Self, I> {
+            match this.accept() {
+                Ok(d) => d,
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, (Menu, E), Add> for Counter {
+        fn with(this: Channel<Self, I, (Menu, E), Add>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((num, this)) => this.send(num.wrapping_add(1)).pop().defer(),
+                Err(this) => {
+                    if this.proto.fragile {
+                        panic!("peer sent garbage");
+                    }
+                    this.defer()
+                }
+            }
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, (Menu, E), End> for Counter {
+        fn with(this: Channel<Self, I, (Menu, E), End>) -> Defer<Self, I> {
+            this.close()
+        }
+    }
+
+    let trace = Trace { ops: vec![
+        Op::RecvDiscriminant(Some(0)),
+        Op::Recv(Some(wire::to_bytes(&41u64))),
+        Op::Send(wire::to_bytes(&42u64)),
+        Op::RecvDiscriminant(Some(1)),
+        Op::Close
+    ] };
+
+    // an out of range discriminant closes the session, rather than
+    // taking the last branch or waiting for another
+    let mut session = channel(Replay::lenient(Trace { ops: vec![Op::RecvDiscriminant(Some(7))] }),
+                              Counter { fragile: true }).defer();
+    assert_eq!(true, session.with()); // enters
+    assert_eq!(false, session.with()); // rejects 7 and closes
+
+    fuzz::hostile(&trace, 3, 200, |io| channel(io, Counter { fragile: false }).defer()).unwrap();
+
+    let failure = fuzz::hostile(&trace, 3, 200, |io| channel(io, Counter { fragile: true }).defer())
+                       .err()
+                       .expect("corrupted payloads should reach the fragile handler");
+    assert!(failure.message.contains("peer sent garbage"));
+
+    // and the same traffic as bytes which a `Framed` backend has to parse
+    fuzz::hostile_stream(&trace, 3, 200, |io| channel(io, Counter { fragile: false }).defer()).unwrap();
+
+    let failure = fuzz::hostile_stream(&trace, 3, 200, |io| channel(io, Counter { fragile: true }).defer())
+                       .err()
+                       .expect("corrupted frames should reach the fragile handler");
+    assert!(failure.message.contains("peer sent garbage"));
+    assert!(format!("{}", failure).contains("corrupted stream:"));
+}
+
+#[test]
+fn byte_pipe() {
+    use nemo::channels::BytePipe;
+
+    #[derive(Copy, Clone)]
+    struct MyProtocol;
+
+    type Ask = Send<String, Choose<Recv<Vec<u64>, End>, Finally<End>>>;
+    type Answer = Recv<String, Accept<Send<Vec<u64>, End>, Finally<End>>>;
+
+    impl Protocol for MyProtocol {
+        type Initial = Ask;
+    }
+
+    impl<I: Transfers<String> + Transfers<Vec<u64>>, E: SessionType> Handler<I, E, Ask> for MyProtocol {
+        fn with(this: Channel<Self, I, E, Ask>) -> Defer<Self, I> {
+            this.send(String::from("lengths")).choose::<Recv<Vec<u64>, End>>().defer()
+        }
+    }
+
+    impl<I: Transfers<Vec<u64>>, E: SessionType> Handler<I, E, Recv<Vec<u64>, End>> for MyProtocol {
+        fn with(this: Channel<Self, I, E, Recv<Vec<u64>, End>>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((msg, this)) => {
+                    assert_eq!(msg, vec![1, 300, u64::max_value()]);
+                    this.close()
+                },
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<String> + Transfers<Vec<u64>>, E: SessionType> Handler<I, E, Answer> for MyProtocol {
+        fn with(this: Channel<Self, I, E, Answer>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((msg, this)) => {
+                    assert_eq!(msg, "lengths");
+                    this.defer()
+                },
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<Vec<u64>>, E: SessionType> Handler<I, E, Accept<Send<Vec<u64>, End>, Finally<End>>> for MyProtocol {
+        fn with(this: Channel<Self, I, E, Accept<Send<Vec<u64>, End>, Finally<End>>>) -> Defer<Self, I> {
+            match this.accept() {
+                Ok(d) => d,
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<Vec<u64>>, E: SessionType> Handler<I, E, Send<Vec<u64>, End>> for MyProtocol {
+        fn with(this: Channel<Self, I, E, Send<Vec<u64>, End>>) -> Defer<Self, I> {
+            this.send(vec![1, 300, u64::max_value()]).close()
+        }
+    }
+
+    impl<I: IO, E: SessionType> Handler<I, E, End> for MyProtocol {
+        fn with(this: Channel<Self, I, E, End>) -> Defer<Self, I> {
+            this.close()
+        }
+    }
+
+    let (client, server) = BytePipe::new(MyProtocol, MyProtocol);
+    let mut server = server.defer();
+    let mut client = client.defer();
+
+    // the server has nothing to read yet, so it waits
+    assert!(server.with());
+
+    let (mut client_open, mut server_open) = (true, true);
+    let mut rounds = 0;
+    while client_open || server_open {
+        if client_open { client_open = client.with(); }
+        if server_open { server_open = server.with(); }
+
+        rounds += 1;
+        assert!(rounds < 10);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn stdio_child_process() {
+    use nemo::channels::Stdio;
+    use std::process::{Command, Stdio as Pipe};
+
+    // `cat` sends every frame back, so to it any protocol which receives
+    // what it just sent runs as its own dual
+    struct Parrot;
+
+    type Repeat = proto!(Send String, Recv String, Send u64, Recv u64, End);
+
+    impl Protocol for Parrot {
+        type Initial = Repeat;
+    }
+
+    impl<I: Transfers<String> + Transfers<u64>, E: SessionType> Handler<I, E, Repeat> for Parrot {
+        fn with(this: Channel<Self, I, E, Repeat>) -> Defer<Self, I> {
+            match this.send(String::from("polly")).recv() {
+                Ok((name, this)) => {
+                    assert_eq!(name, "polly");
+                    match this.send(42).recv() {
+                        Ok((num, this)) => {
+                            assert_eq!(num, 42);
+                            this.close()
+                        },
+                        Err(_) => panic!("cat hung up")
+                    }
+                },
+                Err(_) => panic!("cat hung up")
+            }
+        }
+    }
+
+    let mut child = Command::new("cat")
+                            .stdin(Pipe::piped())
+                            .stdout(Pipe::piped())
+                            .spawn()
+                            .unwrap();
+
+    let io = Stdio::child(&mut child).unwrap();
+    let mut session = channel(io, Parrot).defer();
+    assert!(!session.with());
+
+    // dropping the session closes cat's stdin, so it exits; whether it
+    // managed to echo the close frame into a pipe nobody reads any more
+    // doesn't matter
+    drop(session);
+    child.wait().unwrap();
+}
+
+#[test]
+fn multiplexed_sessions() {
+    use nemo::channels::{Mux, PipeStream};
+
+    struct Double;
+    struct Greet;
+
+    type Ask = Send<u64, Recv<u64, End>>;
+    type Answer = Recv<u64, Send<u64, End>>;
+    type Hello = Send<String, End>;
+    type Listen = Recv<String, End>;
+
+    impl Protocol for Double {
+        type Initial = Ask;
+    }
+
+    impl Protocol for Greet {
+        type Initial = Hello;
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, E, Ask> for Double {
+        fn with(this: Channel<Self, I, E, Ask>) -> Defer<Self, I> {
+            this.send(21).defer()
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, E, Recv<u64, End>> for Double {
+        fn with(this: Channel<Self, I, E, Recv<u64, End>>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((msg, this)) => {
+                    assert_eq!(msg, 42);
+                    this.close()
+                },
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, E, Answer> for Double {
+        fn with(this: Channel<Self, I, E, Answer>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((msg, this)) => this.send(msg * 2).close(),
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<String>, E: SessionType> Handler<I, E, Hello> for Greet {
+        fn with(this: Channel<Self, I, E, Hello>) -> Defer<Self, I> {
+            this.send(String::from("hello")).close()
+        }
+    }
+
+    impl<I: Transfers<String>, E: SessionType> Handler<I, E, Listen> for Greet {
+        fn with(this: Channel<Self, I, E, Listen>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((msg, this)) => {
+                    assert_eq!(msg, "hello");
+                    this.close()
+                },
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    let (a, b) = PipeStream::pair();
+    let mut client = Mux::client(a);
+    let mut server = Mux::server(b);
+
+    let mut double = channel(client.open("double"), Double).defer();
+    let mut greet = channel(client.open("greet"), Greet).defer();
+
+    let (name, stream) = server.accept().unwrap();
+    assert_eq!(name, "double");
+    let mut answer = channel_dual(stream, Double).defer();
+
+    let (name, stream) = server.accept().unwrap();
+    assert_eq!(name, "greet");
+    let mut listen = channel_dual(stream, Greet).defer();
+
+    assert!(server.accept().is_none());
+
+    // the two sessions interleave on the one connection
+    let (mut a, mut b, mut c, mut d) = (true, true, true, true);
+    let mut rounds = 0;
+    while a || b || c || d {
+        if a { a = double.with(); }
+        if b { b = greet.with(); }
+        if c { c = answer.with(); }
+        if d { d = listen.with(); }
+
+        rounds += 1;
+        assert!(rounds < 10);
+    }
+}
+
+#[test]
+fn bounded_repetition() {
+    use nemo::channels::{BytePipe, Sim};
+
+    // a random peer sends whatever total it likes
+    struct MyProtocol { check: bool, total: usize }
+
+    type Upload = proto!(SendMany String, Recv usize, End);
+    type Download = proto!(RecvMany String, Send usize, End);
+
+    impl Protocol for MyProtocol {
+        type Initial = Upload;
+    }
+
+    impl<I: Transfers<String> + Transfers<usize>, E: SessionType> Handler<I, E, Upload> for MyProtocol {
+        fn with(this: Channel<Self, I, E, Upload>) -> Defer<Self, I> {
+            let names = vec!["alice", "bob", "carol"];
+
+            this.send_iter(names.into_iter().map(String::from)).defer()
+        }
+    }
+
+    impl<I: Transfers<usize>, E: SessionType> Handler<I, E, Recv<usize, End>> for MyProtocol {
+        fn with(this: Channel<Self, I, E, Recv<usize, End>>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((total, this)) => {
+                    if this.proto.check {
+                        assert_eq!(total, 13);
+                    }
+                    this.close()
+                },
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    // names which haven't arrived yet are waited for in `Remaining`
+    fn count<I: Transfers<String> + Transfers<usize>, E: SessionType>(mut names: RecvIter<MyProtocol, I, E, String, Send<usize, End>>) -> Defer<MyProtocol, I> {
+        let total = names.by_ref().map(|name| name.len()).sum::<usize>();
+
+        match names.finish() {
+            Ok(this) => {
+                let total = this.proto.total + total;
+                this.send(total).close()
+            },
+            Err(names) => {
+                let mut this = names.pause();
+                this.proto.total += total;
+                this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<String> + Transfers<usize>, E: SessionType> Handler<I, E, Download> for MyProtocol {
+        fn with(this: Channel<Self, I, E, Download>) -> Defer<Self, I> {
+            match this.recv_iter() {
+                Ok(names) => count(names),
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<String> + Transfers<usize>, E: SessionType> Handler<I, E, Remaining<String, Send<usize, End>>> for MyProtocol {
+        fn with(this: Channel<Self, I, E, Remaining<String, Send<usize, End>>>) -> Defer<Self, I> {
+            count(this.recv_iter())
+        }
+    }
+
+    let (client, server) = BytePipe::new(MyProtocol { check: true, total: 0 }, MyProtocol { check: true, total: 0 });
+    let mut server = server.defer();
+    let mut client = client.defer();
+
+    assert!(server.with());
+    assert!(client.with());
+    assert!(!server.with());
+    assert!(!client.with());
+
+    // a paused receiver pairs with a sender whose names are in flight
+    fn paired<S: SessionType<Dual = D>, D: SessionType>() { }
+    paired::<Remaining<String, Send<usize, End>>, InFlight<String, Recv<usize, End>>>();
+
+    // the names trickle in over a slow network, so the server waits for
+    // some of them in `Remaining`
+    let sim = Sim::new(2).latency(1, 20);
+    let (client, server) = sim.connect(MyProtocol { check: true, total: 0 }, MyProtocol { check: true, total: 0 });
+    let mut sessions = vec![client.defer(), server.defer()];
+    assert_eq!(sim.run(&mut sessions, 1000), 0);
+
+    // and against a random peer
+    for seed in 0..20 {
+        fuzz::run(MyProtocol { check: false, total: 0 }, seed, 50).unwrap();
+    }
+}
+
+#[test]
+fn borrowed_sends() {
+    use nemo::channels::{Blocking, BytePipe};
+
+    struct MyProtocol;
+
+    type Upload = Send<str, Send<[u8], Recv<usize, End>>>;
+    type Download = Recv<str, Recv<[u8], Send<usize, End>>>;
+
+    impl Protocol for MyProtocol {
+        type Initial = Upload;
+    }
+
+    impl<I: TransfersRef<str> + TransfersRef<[u8]> + Transfers<usize>, E: SessionType> Handler<I, E, Upload> for MyProtocol {
+        fn with(this: Channel<Self, I, E, Upload>) -> Defer<Self, I> {
+            let blob = vec![7u8; 100000];
+
+            this.send_ref("blob").send_ref(&blob[..]).defer()
+        }
+    }
+
+    impl<I: Transfers<usize>, E: SessionType> Handler<I, E, Recv<usize, End>> for MyProtocol {
+        fn with(this: Channel<Self, I, E, Recv<usize, End>>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((len, this)) => {
+                    assert_eq!(len, 100000);
+                    this.close()
+                },
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<String> + Transfers<Vec<u8>> + Transfers<usize>, E: SessionType> Handler<I, E, Download> for MyProtocol {
+        fn with(this: Channel<Self, I, E, Download>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((name, this)) => {
+                    assert_eq!(name, "blob");
+                    match this.recv() {
+                        Ok((blob, this)) => {
+                            assert!(blob.iter().all(|&b| b == 7));
+                            this.send(blob.len()).close()
+                        },
+                        Err(_) => panic!("the blob was sent with the name")
+                    }
+                },
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    let (client, server) = BytePipe::new(MyProtocol, MyProtocol);
+    let (mut client, mut server) = (client.defer(), server.defer());
+    assert!(server.with());
+    assert!(client.with());
+    assert!(!server.with());
+    assert!(!client.with());
+
+    let (client, server) = Blocking::new::<MyProtocol>(MyProtocol, MyProtocol);
+    let (mut client, mut server) = (client.defer(), server.defer());
+    assert!(client.with());
+    assert!(!server.with());
+    assert!(!client.with());
+}
+
+#[test]
+fn coalesced_writes() {
+    use nemo::channels::{Framed, PipeStream};
+    use std::cell::Cell;
+    use std::io::{self, Read, Write};
+    use std::rc::Rc;
+
+    struct Counting {
+        inner: PipeStream,
+        writes: Rc<Cell<usize>>
+    }
+
+    impl Read for Counting {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for Counting {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes.set(self.writes.get() + 1);
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct MyProtocol;
+
+    type Orig = Send<u64, Choose<Send<String, Recv<u64, End>>, Finally<End>>>;
+
+    impl Protocol for MyProtocol {
+        type Initial = Orig;
+    }
+
+    impl<I: Transfers<u64> + Transfers<String>, E: SessionType> Handler<I, E, Orig> for MyProtocol {
+        fn with(this: Channel<Self, I, E, Orig>) -> Defer<Self, I> {
+            this.send(1).choose::<Send<String, Recv<u64, End>>>().send(String::from("two")).defer()
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, E, Recv<u64, End>> for MyProtocol {
+        fn with(this: Channel<Self, I, E, Recv<u64, End>>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((_, this)) => this.close(),
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    let (a, b) = PipeStream::pair();
+    let writes = Rc::new(Cell::new(0));
+    let io = Framed::new(Counting { inner: a, writes: writes.clone() });
+
+    let mut client = channel(io, MyProtocol).defer();
+    assert!(client.with());
+    assert_eq!(writes.get(), 1);
+    assert!(b.available() > 0);
+
+    // still waiting for the answer, so nothing more is written
+    assert!(client.with());
+    assert_eq!(writes.get(), 1);
+}
+
+
+#[test]
+fn session_subtyping() {
+    use nemo::channels::Blocking;
+    use std::marker::PhantomData;
+
+    fn is_subtype<S: Subtype<T>, T: SessionType>() -> PhantomData<(S, T)> { PhantomData }
+
+    type Deposit = Recv<u64, Send<u64, End>>;
+    type Withdraw = Recv<u64, Send<bool, End>>;
+    type Balance = Send<u64, End>;
+
+    type ServerV1 = Accept<Deposit, Finally<Withdraw>>;
+    type ServerV2 = Accept<Deposit, Accept<Withdraw, Finally<Balance>>>;
+    type ClientV2 = Choose<Send<u64, Recv<u64, End>>, Choose<Send<u64, Recv<bool, End>>, Finally<Recv<u64, End>>>>;
+    type ClientV1 = Choose<Send<u64, Recv<u64, End>>, Finally<Send<u64, Recv<bool, End>>>>;
+
+    // a server may accept more than the client chooses from, and a
+    // client may choose from fewer branches than the server offers
+    is_subtype::<ServerV1, ServerV2>();
+    is_subtype::<ClientV2, ClientV1>();
+    is_subtype::<Nest<Send<u8, ServerV1>>, Nest<Send<u8, ServerV2>>>();
+
+    // an old client, speaking to a server which serves it with the new
+    // handlers
+    struct Old;
+    struct New;
+
+    impl Protocol for Old {
+        type Initial = ClientV1;
+    }
+
+    impl Protocol for New {
+        type Initial = ServerV1;
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, E, ClientV1> for Old {
+        fn with(this: Channel<Self, I, E, ClientV1>) -> Defer<Self, I> {
+            this.choose::<Send<u64, Recv<u64, End>>>().send(10).defer()
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, E, Recv<u64, End>> for Old {
+        fn with(this: Channel<Self, I, E, Recv<u64, End>>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((balance, this)) => {
+                    assert_eq!(balance, 110);
+                    this.close()
+                },
+                Err(_) => panic!("the server should answer")
+            }
+        }
+    }
+
+    impl<I: Transfers<u64> + Transfers<bool>, E: SessionType> Handler<I, E, ServerV1> for New {
+        fn with(this: Channel<Self, I, E, ServerV1>) -> Defer<Self, I> {
+            this.upcast::<ServerV2>().defer()
+        }
+    }
+
+    impl<I: Transfers<u64> + Transfers<bool>, E: SessionType> Handler<I, E, ServerV2> for New {
+        fn with(this: Channel<Self, I, E, ServerV2>) -> Defer<Self, I> {
+            match this.accept() {
+                Ok(d) => d,
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, E, Deposit> for New {
+        fn with(this: Channel<Self, I, E, Deposit>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((amount, this)) => this.send(100 + amount).close(),
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<u64> + Transfers<bool>, E: SessionType> Handler<I, E, Withdraw> for New {
+        fn with(this: Channel<Self, I, E, Withdraw>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((_, this)) => this.send(false).close(),
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, E, Balance> for New {
+        fn with(this: Channel<Self, I, E, Balance>) -> Defer<Self, I> {
+            this.send(100).close()
+        }
+    }
+
+    let (io1, io2) = Blocking::pair();
+    let mut client = channel(io1, Old).defer();
+    let mut server = channel(io2, New).defer();
+
+    assert!(client.with());
+    assert!(server.with());
+    assert!(!server.with());
+    assert!(!client.with());
+}
+
+#[test]
+fn version_negotiation() {
+    use nemo::channels::Blocking;
+    use std::marker::PhantomData;
+
+    fn get<T>() -> PhantomData<T> { PhantomData }
+    fn compatible<A: Compatible<B>, B>() { }
+
+    // version 1 only listens, version 2 also answers with the double
+    type Server = proto!(Offer {
+        {Recv u64, End},
+        {Recv u64, Send u64, End}
+    });
+
+    // version 3 is yet unknown to the server
+    type NewVersions = proto!(Select {
+        {Send u64, End},
+        {Send u64, Recv u64, End},
+        {Send String, End}
+    });
+
+    let _: PhantomData<Server> = get::<Send<Vec<u32>, Accept<End, Accept<Recv<u64, End>, Finally<Recv<u64, Send<u64, End>>>>>>>();
+
+    compatible::<Server, proto!(Select { {Send u64, End} })>();
+    compatible::<Server, NewVersions>();
+    compatible::<NewVersions, Server>();
+
+    struct MyServer {
+        retired: bool
+    }
+    struct OldClient;
+    struct NewClient;
+
+    impl Protocol for MyServer {
+        type Initial = Server;
+    }
+
+    impl Protocol for OldClient {
+        type Initial = proto!(Select { {Send u64, End} });
+    }
+
+    impl Protocol for NewClient {
+        type Initial = NewVersions;
+    }
+
+    impl<I: Transfers<Vec<u32>> + Transfers<u64>, E: SessionType> Handler<I, E, Server> for MyServer {
+        fn with(this: Channel<Self, I, E, Server>) -> Defer<Self, I> {
+            if this.proto.retired {
+                this.send(vec![2]).defer()
+            } else {
+                this.offer().defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, E, Accept<End, Accept<Recv<u64, End>, Finally<Recv<u64, Send<u64, End>>>>>> for MyServer {
+        fn with(this: Channel<Self, I, E, Accept<End, Accept<Recv<u64, End>, Finally<Recv<u64, Send<u64, End>>>>>>) -> Defer<Self, I> {
+            match this.accept() {
+                Ok(d) => d,
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: IO, E: SessionType> Handler<I, E, End> for MyServer {
+        fn with(this: Channel<Self, I, E, End>) -> Defer<Self, I> {
+            this.close()
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, E, Recv<u64, End>> for MyServer {
+        fn with(this: Channel<Self, I, E, Recv<u64, End>>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((num, this)) => {
+                    assert_eq!(num, 42);
+                    this.close()
+                },
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, E, Recv<u64, Send<u64, End>>> for MyServer {
+        fn with(this: Channel<Self, I, E, Recv<u64, Send<u64, End>>>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((num, this)) => this.send(num * 2).close(),
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<Vec<u32>> + Transfers<u64>, E: SessionType> Handler<I, E, proto!(Select { {Send u64, End} })> for OldClient {
+        fn with(this: Channel<Self, I, E, proto!(Select { {Send u64, End} })>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((theirs, this)) => this.select(&theirs),
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, E, Send<u64, End>> for OldClient {
+        fn with(this: Channel<Self, I, E, Send<u64, End>>) -> Defer<Self, I> {
+            this.send(42).close()
+        }
+    }
+
+    impl<I: Transfers<Vec<u32>> + Transfers<u64> + Transfers<String>, E: SessionType> Handler<I, E, NewVersions> for NewClient {
+        fn with(this: Channel<Self, I, E, NewVersions>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((theirs, this)) => this.select(&theirs),
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, E, Send<u64, End>> for NewClient {
+        fn with(_: Channel<Self, I, E, Send<u64, End>>) -> Defer<Self, I> {
+            panic!("the server speaks version 2")
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, E, Send<u64, Recv<u64, End>>> for NewClient {
+        fn with(this: Channel<Self, I, E, Send<u64, Recv<u64, End>>>) -> Defer<Self, I> {
+            this.send(21).defer()
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, E, Recv<u64, End>> for NewClient {
+        fn with(this: Channel<Self, I, E, Recv<u64, End>>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((num, this)) => {
+                    assert_eq!(num, 42);
+                    this.close()
+                },
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<String>, E: SessionType> Handler<I, E, Send<String, End>> for NewClient {
+        fn with(_: Channel<Self, I, E, Send<String, End>>) -> Defer<Self, I> {
+            panic!("the server doesn't speak version 3")
+        }
+    }
+
+    // a handler blocks until its peer sends, so each side gets its own
+    // thread; the old client settles on version 1
+    let (io1, io2) = Blocking::pair();
+    let mut client = channel(io1, OldClient).defer();
+    let mut server = channel(io2, MyServer { retired: false }).defer();
+
+    let peer = ::std::thread::spawn(move || while client.with() {});
+    while server.with() {}
+    peer.join().unwrap();
+
+    // the new client settles on version 2
+    let (io1, io2) = Blocking::pair();
+    let mut client = channel(io1, NewClient).defer();
+    let mut server = channel(io2, MyServer { retired: false }).defer();
+
+    let peer = ::std::thread::spawn(move || while client.with() {});
+    while server.with() {}
+    peer.join().unwrap();
+
+    // once version 1 is retired there is nothing in common with the old
+    // client, so it hangs up
+    let (io1, io2) = Blocking::pair();
+    let mut client = channel(io1, OldClient).defer();
+    let mut server = channel(io2, MyServer { retired: true }).defer();
+
+    let peer = ::std::thread::spawn(move || client.with());
+    assert!(server.with());
+    assert!(!server.with());
+    assert!(!peer.join().unwrap());
+}
+
+#[test]
+fn session_macro() {
+    use nemo::fuzz;
+    use std::marker::PhantomData;
+
+    fn get<T>() -> PhantomData<T> { PhantomData }
+
+    struct Doubler {
+        doubled: u64
+    }
+
+    session! {
+        Doubler(u64) = Start;
+
+        Start = {loop { goto Menu }} => |this| {
+            this.enter().defer()
+        }
+
+        loop Menu = {Accept { {goto Double}, {goto Total} }} {
+            Menu => |this| {
+                match this.accept() {
+                    Ok(d) => d,
+                    Err(this) => this.defer()
+                }
+            }
+
+            Double = {Recv u64, Send u64, continue} => |this| {
+                match this.recv() {
+                    Ok((num, mut this)) => {
+                        this.proto.doubled += 1;
+                        this.send(num.wrapping_mul(2)).pop().defer()
+                    },
+                    Err(this) => this.defer()
+                }
+            }
+
+            Total = {Send u64, End} => |this| {
+                let doubled = this.proto.doubled;
+                this.send(doubled).defer()
+            }
+
+            End => |this| {
+                this.close()
+            }
+        }
+    }
+
+    let _: PhantomData<Start> = get::<Nest<Accept<Recv<u64, Send<u64, Escape<Z>>>, Finally<Send<u64, End>>>>>();
+
+    for seed in 0..20 {
+        let trace = fuzz::run(Doubler { doubled: 0 }, seed, 100).unwrap();
+        assert_eq!(trace[0], fuzz::Step::Entered);
+    }
+}
+#[test]
+fn straight_line_sessions() {
+    use nemo::channels::Blocking;
+    use std::marker::PhantomData;
+
+    fn get<T>() -> PhantomData<T> { PhantomData }
+
+    struct Tally {
+        total: u64
+    }
+
+    struct Adder {
+        left: u64
+    }
+
+    session! {
+        Tally(u64) = |this| {
+            loop {
+                offer {
+                    {
+                        let num = recv::<u64>();
+                        this.proto.total += num;
+                        send::<u64>(this.proto.total);
+                        continue;
+                    },
+                    {
+                        send::<u64>(this.proto.total);
+                    }
+                }
+            }
+        }
+    }
+
+    session! {
+        Adder(u64) = |this| {
+            loop {
+                choose (this.proto.left > 0) {
+                    true => {
+                        send::<u64>(this.proto.left);
+                        this.proto.left -= 1;
+                        // `recv` starts a new handler, which only the
+                        // values received before it are carried over to
+                        let total = recv::<u64>();
+                        assert!(total > this.proto.left);
+                        continue;
+                    }
+                    false => {
+                        let total = recv::<u64>();
+                        assert_eq!(total, 6);
+                    }
+                }
+            }
+        }
+    }
+
+    type Server = Nest<Accept<Recv<u64, Send<u64, Escape<Z>>>, Finally<Send<u64, End>>>>;
+
+    let _: PhantomData<<Tally as Protocol>::Initial> = get::<Server>();
+    let _: PhantomData<<Adder as Protocol>::Initial> = get::<<Server as SessionType>::Dual>();
+
+    let (io1, io2) = Blocking::pair();
+    let mut client = channel(io1, Adder { left: 3 }).defer();
+    let mut server = channel(io2, Tally { total: 0 }).defer();
+
+    let peer = ::std::thread::spawn(move || while client.with() {});
+    while server.with() {}
+    peer.join().unwrap();
+}
+
+                        // `recv` starts a new handler, which only the
+                        // values received before it are carried over to
+// values outlive the handlers they were received in, and blocks of the
+// same shape, or which reach the same state, are handled apart
+#[test]
+fn straight_line_scopes() {
+    use nemo::channels::Blocking;
+    use nemo::fuzz::{self, Step};
+    use std::marker::PhantomData;
+
+    fn get<T>() -> PhantomData<T> { PhantomData }
+
+    struct Summer;
+
+    struct Asker {
+        second: bool
+    }
+
+    session! {
+        Summer(u64, bool) = |this| {
+            let a = recv::<u64>();
+            let b = recv::<u64>();
+            let sum: u64 = a + b;
+            send::<u64>(sum);
+            offer {
+                {
+                    send::<u64>(a);
+                    let c = recv::<u64>();
+                    send::<u64>(c * sum);
+                },
+                {
+                    send::<bool>(a < b);
+                    let c = recv::<u64>();
+                    send::<u64>(c + sum);
+                }
+            }
+        }
+    }
+
+    session! {
+        Asker(u64, bool) = |this| {
+            send::<u64>(2);
+            send::<u64>(3);
+            let sum = recv::<u64>();
+            choose (this.proto.second) {
+                false => {
+                    let a = recv::<u64>();
+                    send::<u64>(10);
+                    let product = recv::<u64>();
+                    assert_eq!((sum, a, product), (5, 2, 50));
+                }
+                true => {
+                    let less = recv::<bool>();
+                    send::<u64>(10);
+                    let total = recv::<u64>();
+                    assert!(less);
+                    assert_eq!((sum, total), (5, 15));
+                }
+            }
+        }
+    }
+
+    let _: PhantomData<<Asker as Protocol>::Initial> = get::<<<Summer as Protocol>::Initial as SessionType>::Dual>();
+
+    for &second in &[false, true] {
+        let (io1, io2) = Blocking::pair();
+        let mut client = channel(io1, Asker { second: second }).defer();
+        let mut server = channel(io2, Summer).defer();
+
+        let peer = ::std::thread::spawn(move || while client.with() {});
+        while server.with() {}
+        peer.join().unwrap();
+    }
+
+    struct Either;
+
+    session! {
+        Either(u64) = |this| {
+            offer {
+                { send::<u64>(1); },
+                { send::<u64>(2); }
+            }
+        }
+    }
+
+    // a peer can't choose between blocks of the same type, so let the
+    // random one
+    let mut seen = [false, false];
+    for seed in 0..20 {
+        let trace = fuzz::run(Either, seed, 10).unwrap();
+        match trace[0] {
+            Step::Chose(num) => {
+                assert_eq!(trace[1], Step::Received("u64", format!("{}", num + 1)));
+                seen[num] = true;
+            },
+            _ => panic!("the peer didn't choose a block")
+        }
+    }
+    assert_eq!(seen, [true, true]);
+}
+
+
+proto! {
+    mod counter {
+        Start = {Recv String, loop { goto Menu }};
+
+        loop Menu = {Accept { {goto Add}, {goto Quit} }} {
+            Add = {Recv u64, Send u64, continue};
+            Quit = {Send String, End};
+        }
+    }
+}
+
+#[test]
+fn both_endpoints_from_one_declaration() {
+    use nemo::fuzz;
+    use std::marker::PhantomData;
+    use counter::server::*;
+
+    fn get<T>() -> PhantomData<T> { PhantomData }
+
+    let _: PhantomData<counter::Client> = get::<<counter::Server as SessionType>::Dual>();
+    let _: PhantomData<counter::client::Menu> = get::<Choose<counter::client::Add, Finally<counter::client::Quit>>>();
+    let _: PhantomData<counter::client::Add> = get::<Send<u64, Recv<u64, Escape<Z>>>>();
+
+    struct Counter {
+        name: String,
+        total: u64
+    }
+
+    impl Protocol for Counter {
+        type Initial = counter::Server;
+    }
+
+    impl<I: Transfers<String> + Transfers<u64>, E: SessionType> counter::ServerHandlers<I, E> for Counter { }
+
+    handlers!(
+        Counter(String, u64);
+
+        this(Start) => {
+            match this.recv() {
+                Ok((name, mut this)) => {
+                    this.proto.name = name;
+                    this.enter().defer()
+                },
+                Err(this) => this.defer()
+            }
+        }
+
+        this(Menu => Menu) => {
+            match this.accept() {
+                Ok(d) => d,
+                Err(this) => this.defer()
+            }
+        }
+
+        this(Menu => Add) => {
+            match this.recv() {
+                Ok((num, mut this)) => {
+                    this.proto.total = this.proto.total.wrapping_add(num);
+                    let total = this.proto.total;
+                    this.send(total).pop().defer()
+                },
+                Err(this) => this.defer()
+            }
+        }
+
+        this(Menu => Quit) => {
+            let name = this.proto.name.clone();
+            this.send(name).close()
+        }
+    );
+
+    for seed in 0..20 {
+        fuzz::run(Counter { name: String::new(), total: 0 }, seed, 100).unwrap();
+    }
+}
+
+proto! {
+    pub mod atm {
+        Start = {Recv String, loop { goto Menu }};
+        loop Menu = {Accept { {goto Deposit}, {goto Quit} }} {
+            Deposit = {Recv u64, Send u64, continue};
+            Quit = {Recv (), End};
+        }
+    }
+}
+
+#[test]
+fn textual_specs() {
+    use nemo::spec::{self, Session};
+
+    let src = "
+        // a bank which only takes deposits
+        global protocol Atm(role Server, role Client) {
+            account(String) from Client to Server;
+            rec Menu {
+                choice at Client {
+                    deposit(u64) from Client to Server;
+                    balance(u64) from Server to Client;
+                    continue Menu;
+                } or {
+                    quit() from Client to Server;
+                }
+            }
+        }
+    ";
+
+    let spec = spec::parse(src).unwrap();
+
+    // the declaration above this test is what the generator writes
+    assert_eq!(spec.generate(), "\
+proto! {
+    pub mod atm {
+        Start = {Recv String, loop { goto Menu }};
+        loop Menu = {Accept { {goto Deposit}, {goto Quit} }} {
+            Deposit = {Recv u64, Send u64, continue};
+            Quit = {Recv (), End};
+        }
+    }
+}
+");
+
+    assert_eq!(spec.project("Server"), Some(Session::of::<atm::Server>()));
+    assert_eq!(spec.project("Client"), Some(Session::of::<atm::Client>()));
+    assert_eq!(spec.project("Bank"), None);
+
+    assert_eq!(spec::parse(&spec.to_string()).unwrap(), spec);
+
+    let described = Session::of::<atm::Server>().to_spec("Atm", "Server", "Client");
+    assert_eq!(described.to_string(), "\
+global protocol Atm(role Server, role Client) {
+    (String) from Client to Server;
+    rec L0 {
+        choice at Client {
+            (u64) from Client to Server;
+            (u64) from Server to Client;
+            continue L0;
+        } or {
+            () from Client to Server;
+        }
+    }
+}
+");
+    assert_eq!(described.project("Server"), spec.project("Server"));
+
+    let err = spec::parse("protocol P(role A, role B) {\n    rec Loop {\n        (u8) from A to B;\n        continue Lop;\n    }\n}").unwrap_err();
+    assert_eq!(err.line, 4);
+    assert!(err.message.contains("continue Lop"));
+
+    let err = spec::parse("protocol P(role A, role B) {\n    (u8) from A to A;\n}").unwrap_err();
+    assert_eq!(err.line, 2);
+
+    let err = spec::parse("protocol P(role A, role B) {\n    (u8) from A to C;\n}").unwrap_err();
+    assert_eq!(format!("{}", err), "line 2: `C` is not a role of this protocol");
+
+    let err = spec::parse("protocol P(role A, role B) {\n    rec L { continue L; }\n    (u8) from A to B;\n}").unwrap_err();
+    assert_eq!(err.line, 3);
+
+    let err = spec::parse("protocol P(role A, role B) {\n    rec L {\n        par { continue L; } and { }\n    }\n}").unwrap_err();
+    assert_eq!(err.line, 3);
+
+    // the handlers in `atm_client` below are what the skeleton is
+    assert_eq!(spec.skeleton("Client", "AtmClient").unwrap(), ATM_CLIENT);
+    assert_eq!(spec.skeleton("Bank", "AtmClient"), None);
+}
+
+const ATM_CLIENT: &'static str = "\
+impl Protocol for AtmClient {
+    type Initial = atm::Client;
+}
+
+impl<I: IO + Transfers<String> + Transfers<u64> + Transfers<()>, E: SessionType> atm::ClientHandlers<I, E> for AtmClient { }
+
+impl<I: IO + Transfers<String> + Transfers<u64> + Transfers<()>, E: SessionType> Handler<I, E, atm::client::Start> for AtmClient {
+    fn with(this: Channel<Self, I, E, atm::client::Start>) -> Defer<Self, I> {
+        unimplemented!()
+    }
+}
+
+impl<I: IO + Transfers<String> + Transfers<u64> + Transfers<()>, E: SessionType> Handler<I, (atm::client::Menu, E), atm::client::Menu> for AtmClient {
+    fn with(this: Channel<Self, I, (atm::client::Menu, E), atm::client::Menu>) -> Defer<Self, I> {
+        unimplemented!()
+    }
+}
+
+impl<I: IO + Transfers<String> + Transfers<u64> + Transfers<()>, E: SessionType> Handler<I, (atm::client::Menu, E), atm::client::Deposit> for AtmClient {
+    fn with(this: Channel<Self, I, (atm::client::Menu, E), atm::client::Deposit>) -> Defer<Self, I> {
+        unimplemented!()
+    }
+}
+
+impl<I: IO + Transfers<String> + Transfers<u64> + Transfers<()>, E: SessionType> Handler<I, (atm::client::Menu, E), atm::client::Quit> for AtmClient {
+    fn with(this: Channel<Self, I, (atm::client::Menu, E), atm::client::Quit>) -> Defer<Self, I> {
+        unimplemented!()
+    }
+}
+";
+
+#[allow(dead_code, unused_variables)]
+mod atm_client {
+    use nemo::*;
+    use nemo::session_types::*;
+    use atm;
+
+    pub struct AtmClient;
+
+    impl Protocol for AtmClient {
+        type Initial = atm::Client;
+    }
+
+    impl<I: IO + Transfers<String> + Transfers<u64> + Transfers<()>, E: SessionType> atm::ClientHandlers<I, E> for AtmClient { }
+
+    impl<I: IO + Transfers<String> + Transfers<u64> + Transfers<()>, E: SessionType> Handler<I, E, atm::client::Start> for AtmClient {
+        fn with(this: Channel<Self, I, E, atm::client::Start>) -> Defer<Self, I> {
+            unimplemented!()
+        }
+    }
+
+    impl<I: IO + Transfers<String> + Transfers<u64> + Transfers<()>, E: SessionType> Handler<I, (atm::client::Menu, E), atm::client::Menu> for AtmClient {
+        fn with(this: Channel<Self, I, (atm::client::Menu, E), atm::client::Menu>) -> Defer<Self, I> {
+            unimplemented!()
+        }
+    }
+
+    impl<I: IO + Transfers<String> + Transfers<u64> + Transfers<()>, E: SessionType> Handler<I, (atm::client::Menu, E), atm::client::Deposit> for AtmClient {
+        fn with(this: Channel<Self, I, (atm::client::Menu, E), atm::client::Deposit>) -> Defer<Self, I> {
+            unimplemented!()
+        }
+    }
+
+    impl<I: IO + Transfers<String> + Transfers<u64> + Transfers<()>, E: SessionType> Handler<I, (atm::client::Menu, E), atm::client::Quit> for AtmClient {
+        fn with(this: Channel<Self, I, (atm::client::Menu, E), atm::client::Quit>) -> Defer<Self, I> {
+            unimplemented!()
+        }
+    }
+}
+
+#[test]
+fn named_recursion() {
+    use nemo::fuzz;
+    use std::marker::PhantomData;
+
+    fn get<T>() -> PhantomData<T> { PhantomData }
+
+    struct Outer;
+    struct Inner;
+
+    // read a number, then repeat it until asked for a new one
+    type Server = proto!(loop Outer {
+        Recv u64,
+        loop Inner {
+            Accept {
+                {Send u64, continue Inner},
+                {continue Outer},
+                End
+            }
+        }
+    });
+
+    type OuterBody = Recv<u64, Rec<Inner, Menu>>;
+    type Menu = Accept<Send<u64, Var<Inner>>, Accept<Var<Outer>, Finally<End>>>;
+    type OuterEnv<E> = Bind<Outer, OuterBody, E>;
+    type InnerEnv<E> = Bind<Inner, Menu, OuterEnv<E>>;
+
+    let _: PhantomData<Server> = get::<Rec<Outer, OuterBody>>();
+    let _: PhantomData<<Server as SessionType>::Dual> = get::<Rec<Outer, Send<u64, Rec<Inner, Choose<Recv<u64, Var<Inner>>, Choose<Var<Outer>, Finally<End>>>>>>>();
+
+    struct Repeater {
+        num: u64
+    }
+
+    impl Protocol for Repeater {
+        type Initial = Server;
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, E, Server> for Repeater {
+        fn with(this: Channel<Self, I, E, Server>) -> Defer<Self, I> {
+            this.recurse().defer()
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, OuterEnv<E>, OuterBody> for Repeater {
+        fn with(this: Channel<Self, I, OuterEnv<E>, OuterBody>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((num, mut this)) => {
+                    this.proto.num = num;
+                    this.recurse().defer()
+                },
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, InnerEnv<E>, Menu> for Repeater {
+        fn with(this: Channel<Self, I, InnerEnv<E>, Menu>) -> Defer<Self, I> {
+            match this.accept() {
+                Ok(d) => d,
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, InnerEnv<E>, Send<u64, Var<Inner>>> for Repeater {
+        fn with(this: Channel<Self, I, InnerEnv<E>, Send<u64, Var<Inner>>>) -> Defer<Self, I> {
+            let num = this.proto.num;
+            this.send(num).recurse().defer()
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, InnerEnv<E>, Var<Outer>> for Repeater {
+        fn with(this: Channel<Self, I, InnerEnv<E>, Var<Outer>>) -> Defer<Self, I> {
+            // straight past the inner loop
+            this.recurse().defer()
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, InnerEnv<E>, End> for Repeater {
+        fn with(this: Channel<Self, I, InnerEnv<E>, End>) -> Defer<Self, I> {
+            this.close()
+        }
+    }
+
+    let mut restarted = false;
+    for seed in 0..20 {
+        let trace = fuzz::run(Repeater { num: 0 }, seed, 100).unwrap();
+        restarted |= trace.contains(&fuzz::Step::Escaped(1));
+    }
+    assert!(restarted);
+}
+
+#[test]
+fn named_recursion_around_nest() {
+    use nemo::fuzz;
+
+    struct Session;
+
+    // `continue` leaves the `Nest`; `continue Session` leaves both
+    type Server = proto!(loop Session {
+        Recv u64,
+        loop {
+            Accept {
+                {Send u64, continue},
+                {continue Session},
+                End
+            }
+        }
+    });
+
+    type Body = Recv<u64, Nest<Menu>>;
+    type Menu = Accept<Send<u64, Escape<Z>>, Accept<Var<Session>, Finally<End>>>;
+    type MenuEnv<E> = (Menu, Bind<Session, Body, E>);
+
+    struct Repeater {
+        num: u64
+    }
+
+    impl Protocol for Repeater {
+        type Initial = Server;
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, E, Server> for Repeater {
+        fn with(this: Channel<Self, I, E, Server>) -> Defer<Self, I> {
+            this.recurse().defer()
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, Bind<Session, Body, E>, Body> for Repeater {
+        fn with(this: Channel<Self, I, Bind<Session, Body, E>, Body>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((num, mut this)) => {
+                    this.proto.num = num;
+                    this.enter().defer()
+                },
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, MenuEnv<E>, Menu> for Repeater {
+        fn with(this: Channel<Self, I, MenuEnv<E>, Menu>) -> Defer<Self, I> {
+            match this.accept() {
+                Ok(d) => d,
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, MenuEnv<E>, Send<u64, Escape<Z>>> for Repeater {
+        fn with(this: Channel<Self, I, MenuEnv<E>, Send<u64, Escape<Z>>>) -> Defer<Self, I> {
+            let num = this.proto.num;
+            this.send(num).pop().defer()
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, MenuEnv<E>, Var<Session>> for Repeater {
+        fn with(this: Channel<Self, I, MenuEnv<E>, Var<Session>>) -> Defer<Self, I> {
+            this.recurse().defer()
+        }
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, MenuEnv<E>, End> for Repeater {
+        fn with(this: Channel<Self, I, MenuEnv<E>, End>) -> Defer<Self, I> {
+            this.close()
+        }
+    }
+
+    let mut restarted = false;
+    for seed in 0..20 {
+        let trace = fuzz::run(Repeater { num: 0 }, seed, 100).unwrap();
+        restarted |= trace.contains(&fuzz::Step::Escaped(1));
+    }
+    assert!(restarted);
+}
+
+#[test]
+fn textual_specs_of_named_loops() {
+    use nemo::spec::Session;
+
+    // named loops keep their labels
+    struct Outer;
+    type Named = Rec<Outer, Send<u8, Var<Outer>>>;
+
+    let described = Session::of::<Named>().to_spec("Named", "A", "B");
+    assert_eq!(described.to_string(), "\
+global protocol Named(role A, role B) {
+    rec Outer {
+        (u8) from A to B;
+        continue Outer;
+    }
+}
+");
+    assert_eq!(described.project("A"), Some(Session::of::<Nest<Send<u8, Escape<Z>>>>()));
+}
+
+pub struct Patience;
+
+impl Deadline for Patience {
+    fn duration() -> ::std::time::Duration {
+        ::std::time::Duration::from_millis(20)
+    }
+}
+
+proto! {
+    pub mod jobs {
+        Start = {Recv String, Due Patience { loop { goto Progress } } else { Par { {Recv (), End}, {loop { Send u8, continue }} }, Recv (), End }};
+        loop Progress = {Choose { {goto Step}, {goto Done} }} {
+            Step = {Send u8, continue};
+            Done = {try { Send u64, End } catch { Recv (), End }};
+        }
+    }
+}
+
+#[test]
+fn textual_specs_beyond_choice() {
+    use nemo::spec::{self, Session};
+
+    let src = "
+        protocol Jobs(role Worker, role Boss) {
+            job(String) from Boss to Worker;
+            timed(Patience) at Boss {
+                rec Progress {
+                    choice at Worker {
+                        step(u8) from Worker to Boss;
+                        continue Progress;
+                    } or {
+                        try {
+                            done(u64) from Worker to Boss;
+                        } catch {
+                            failed() from Boss to Worker;
+                        }
+                    }
+                }
+            } else {
+                par {
+                    ping() from Boss to Worker;
+                } and {
+                    rec Drain {
+                        (u8) from Worker to Boss;
+                        continue Drain;
+                    }
+                }
+                gone() from Boss to Worker;
+            }
+        }
+    ";
+
+    let spec = spec::parse(src).unwrap();
+
+    // loops and choices inside `par` and `try` are written out in place
+    assert_eq!(spec.generate(), "\
+proto! {
+    pub mod jobs {
+        Start = {Recv String, Due Patience { loop { goto Progress } } else { Par { {Recv (), End}, {loop { Send u8, continue }} }, Recv (), End }};
+        loop Progress = {Choose { {goto Step}, {goto Done} }} {
+            Step = {Send u8, continue};
+            Done = {try { Send u64, End } catch { Recv (), End }};
+        }
+    }
+}
+");
+
+    assert_eq!(spec.project("Worker"), Some(Session::of::<jobs::Server>()));
+    assert_eq!(spec.project("Boss"), Some(Session::of::<jobs::Client>()));
+    assert_eq!(spec::parse(&spec.to_string()).unwrap(), spec);
+
+    let described = Session::of::<jobs::Client>().to_spec("Jobs", "Boss", "Worker");
+    assert_eq!(spec::parse(&described.to_string()).unwrap(), described);
+    assert_eq!(described.project("Worker"), spec.project("Worker"));
+}
+
+#[test]
+fn parallel_sessions() {
+    use std::thread;
+    use nemo::channels::{Blocking, Mux, PipeStream};
+
+    struct Upload;
+
+    // a control stream and a data stream, then a verdict on the original
+    type Client = proto!(Par { {Send String, End}, {SendMany u64, End} }, Recv bool, End);
+
+    impl Protocol for Upload {
+        type Initial = Client;
+    }
+
+    let (client, server) = Blocking::new(Upload, Upload);
+
+    let peer = thread::spawn(move || {
+        server.split(|control, data, rest| {
+            let mut items = data.recv_iter().ok().unwrap();
+            let total: u64 = items.by_ref().sum();
+            let data = items.finish().ok().unwrap();
+            let (name, control) = control.recv().ok().unwrap();
+
+            rest.join(control, data).send(name == "numbers" && total == 6).close()
+        }).ok().unwrap();
+    });
+
+    let client = client.split(|control, data, rest| {
+        let control = control.send(String::from("numbers"));
+        let data = data.send_iter(vec![1, 2, 3]);
+        rest.join(control, data)
+    }).ok().unwrap();
+    let (ok, client) = client.recv().ok().unwrap();
+    assert!(ok);
+    client.close();
+    peer.join().unwrap();
+
+    // over a mux the halves are streams of their own
+    let (a, b) = PipeStream::pair();
+    let mut near = Mux::client(a);
+    let mut far = Mux::server(b);
+
+    let client = channel(near.open("upload"), Upload);
+    let (_, stream) = far.accept().unwrap();
+    let server = channel_dual(stream, Upload);
+
+    // the side which opened the stream hasn't split it yet
+    let server = server.split(|_, _, _| ()).err().unwrap();
+
+    let client = client.split(|control, data, rest| {
+        let data = data.send_iter(vec![1, 2, 3]);
+        let control = control.send(String::from("numbers"));
+        rest.join(control, data)
+    }).ok().unwrap();
+
+    server.split(|control, data, rest| {
+        let (name, control) = control.recv().ok().unwrap();
+        let mut items = data.recv_iter().ok().unwrap();
+        let total: u64 = items.by_ref().sum();
+        let data = items.finish().ok().unwrap();
+        rest.join(control, data).send(name == "numbers" && total == 6).close()
+    }).ok().unwrap();
+
+    let (ok, client) = client.recv().ok().unwrap();
+    assert!(ok);
+    client.close();
+}
+
+#[test]
+fn abandoned_blocks() {
+    use std::thread;
+    use nemo::channels::Blocking;
+
+    struct Summer {
+        total: u64,
+        aborted: usize
+    }
+
+    // upload numbers until the client asks for their sum, unless it
+    // gives up part way, and go back to the menu either way
+    type Server = proto!(loop {
+        Accept {
+            {try {
+                loop {
+                    Accept {
+                        {Recv u64, continue},
+                        {Send u64, continue 1}
+                    }
+                }
+            } catch {
+                continue
+            }},
+            End
+        }
+    });
+
+    type Menu = Accept<Upload, Finally<End>>;
+    type Upload = Try<Nest<Chunks>, Escape<Z>>;
+    type Chunks = Accept<Recv<u64, Escape<Z>>, Finally<Send<u64, Escape<S<Z>>>>>;
+    type MenuEnv = (Menu, ());
+    type ChunksEnv = (Chunks, Catch<Escape<Z>, MenuEnv>);
+
+    impl Protocol for Summer {
+        type Initial = Server;
+    }
+
+    impl<I: Transfers<u64>> Handler<I, (), Server> for Summer {
+        fn with(this: Channel<Self, I, (), Server>) -> Defer<Self, I> {
+            this.enter().defer()
+        }
+    }
+
+    impl<I: Transfers<u64>> Handler<I, MenuEnv, Menu> for Summer {
+        fn with(this: Channel<Self, I, MenuEnv, Menu>) -> Defer<Self, I> {
+            match this.accept() {
+                Ok(d) => d,
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<u64>> Handler<I, MenuEnv, Upload> for Summer {
+        fn with(mut this: Channel<Self, I, MenuEnv, Upload>) -> Defer<Self, I> {
+            this.proto.total = 0;
+            this.attempt().enter().defer()
+        }
+    }
+
+    // the client may raise whenever it is its turn to send, so every
+    // receive inside the block catches first
+    impl<I: Transfers<u64>> Handler<I, ChunksEnv, Chunks> for Summer {
+        fn with(this: Channel<Self, I, ChunksEnv, Chunks>) -> Defer<Self, I> {
+            match this.catch() {
+                Ok(d) => d,
+                Err(this) => match this.accept() {
+                    Ok(d) => d,
+                    Err(this) => this.defer()
+                }
+            }
+        }
+    }
+
+    impl<I: Transfers<u64>> Handler<I, ChunksEnv, Recv<u64, Escape<Z>>> for Summer {
+        fn with(this: Channel<Self, I, ChunksEnv, Recv<u64, Escape<Z>>>) -> Defer<Self, I> {
+            match this.catch() {
+                Ok(d) => d,
+                Err(this) => match this.recv() {
+                    Ok((num, mut this)) => {
+                        this.proto.total += num;
+                        this.pop().defer()
+                    },
+                    Err(this) => this.defer()
+                }
+            }
+        }
+    }
+
+    impl<I: Transfers<u64>> Handler<I, ChunksEnv, Send<u64, Escape<S<Z>>>> for Summer {
+        fn with(this: Channel<Self, I, ChunksEnv, Send<u64, Escape<S<Z>>>>) -> Defer<Self, I> {
+            let total = this.proto.total;
+            this.send(total).pop().defer()
+        }
+    }
+
+    impl<I: Transfers<u64>> Handler<I, MenuEnv, Escape<Z>> for Summer {
+        fn with(mut this: Channel<Self, I, MenuEnv, Escape<Z>>) -> Defer<Self, I> {
+            this.proto.aborted += 1;
+            this.pop().defer()
+        }
+    }
+
+    impl<I: Transfers<u64>> Handler<I, MenuEnv, End> for Summer {
+        fn with(this: Channel<Self, I, MenuEnv, End>) -> Defer<Self, I> {
+            assert_eq!(this.proto.aborted, 2);
+            this.close()
+        }
+    }
+
+    // the client's side of the upload
+    type UploadFrom = Try<Nest<ChunksFrom>, Escape<Z>>;
+    type ChunksFrom = Choose<Send<u64, Escape<Z>>, Finally<Recv<u64, Escape<S<Z>>>>>;
+
+    let (server, client) = Blocking::new(Summer { total: 0, aborted: 0 }, Summer { total: 0, aborted: 0 });
+
+    let peer = thread::spawn(move || {
+        let (total, client) = client.enter()
+                                    .choose::<UploadFrom>().attempt().enter()
+                                    .choose::<Send<u64, Escape<Z>>>().send(1).pop()
+                                    .choose::<Send<u64, Escape<Z>>>().send(2).pop()
+                                    .choose::<Recv<u64, Escape<S<Z>>>>().recv().ok().unwrap();
+        assert_eq!(total, 3);
+
+        // give up on the second upload after one number
+        let client = client.pop()
+                           .choose::<UploadFrom>().attempt().enter()
+                           .choose::<Send<u64, Escape<Z>>>().send(5).pop()
+                           .raise()
+                           .pop();
+
+        // and on the third, where it would have sent a number
+        let client = client.choose::<UploadFrom>().attempt().enter()
+                           .choose::<Send<u64, Escape<Z>>>()
+                           .raise()
+                           .pop();
+
+        let (total, client) = client.choose::<UploadFrom>().attempt().enter()
+                                    .choose::<Send<u64, Escape<Z>>>().send(4).pop()
+                                    .choose::<Recv<u64, Escape<S<Z>>>>().recv().ok().unwrap();
+        assert_eq!(total, 4);
+
+        client.pop().choose::<End>().close();
+    });
+
+    let mut server = server.defer();
+    while server.with() { }
+    peer.join().unwrap();
+}
+
+#[test]
+fn uncaught_exceptions() {
+    use nemo::channels::Blocking;
+
+    struct Reader;
+
+    impl Protocol for Reader {
+        type Initial = Try<Recv<u64, End>, End>;
+    }
+
+    impl<I: Transfers<u64>> Handler<I, (), End> for Reader {
+        fn with(this: Channel<Self, I, (), End>) -> Defer<Self, I> {
+            this.close()
+        }
+    }
+
+    let (server, client) = Blocking::new(Reader, Reader);
+    client.attempt().raise().close();
+
+    // receiving without catching first fails, and leaves the exception
+    // for `catch`
+    let server = server.attempt().recv().err().unwrap();
+    let server = server.recv().err().unwrap();
+
+    match server.catch() {
+        Ok(mut d) => assert!(!d.with()),
+        Err(_) => panic!("the exception was lost")
+    }
+}
+
+// `catch` receives a discriminant ahead of the `accept` which needs it,
+// and the channel keeps it when it moves on in between
+#[test]
+fn caught_ahead() {
+    use nemo::channels::Blocking;
+
+    struct Menu;
+
+    // a newer version of the menu, with a third branch
+    type Wider = Accept<Recv<u64, End>, Accept<End, Finally<Recv<String, End>>>>;
+
+    impl Protocol for Menu {
+        type Initial = Try<Accept<Recv<u64, End>, Finally<End>>, End>;
+    }
+
+    impl<I: Transfers<u64>, E: SessionType> Handler<I, E, Recv<u64, End>> for Menu {
+        fn with(this: Channel<Self, I, E, Recv<u64, End>>) -> Defer<Self, I> {
+            let (num, this) = this.recv().ok().unwrap();
+            assert_eq!(num, 7);
+            this.close()
+        }
+    }
+
+    impl<I, E: SessionType> Handler<I, E, End> for Menu {
+        fn with(_: Channel<Self, I, E, End>) -> Defer<Self, I> {
+            panic!("accepted the wrong branch");
+        }
+    }
+
+    impl<I, E: SessionType> Handler<I, E, Recv<String, End>> for Menu {
+        fn with(_: Channel<Self, I, E, Recv<String, End>>) -> Defer<Self, I> {
+            panic!("accepted the wrong branch");
+        }
+    }
+
+    let (server, client) = Blocking::new(Menu, Menu);
+    client.attempt().choose::<Send<u64, End>>().send(7).close();
+
+    let server = server.attempt().catch().err().unwrap();
+    let server = server.upcast::<Wider>();
+
+    // the handler of the first branch runs and closes the channel
+    assert!(server.accept().is_ok());
+}
+
+#[test]
+fn deadlines() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+    use nemo::channels::Sim;
+
+    struct Quiz {
+        log: Rc<RefCell<Vec<&'static str>>>
+    }
+
+    struct Patience;
+
+    impl Deadline for Patience {
+        fn duration() -> Duration {
+            Duration::from_millis(5)
+        }
+    }
+
+    // ask a question and wait a little for the answer, or for an excuse
+    type Server = proto!(
+        Send u64,
+        Timed Patience {
+            Recv u64, End
+        } else {
+            Recv String, End
+        }
+    );
+
+    type Answer = Due<Patience, Send<u64, End>, Send<String, End>>;
+
+    impl Protocol for Quiz {
+        type Initial = Server;
+    }
+
+    impl<I: Timer + Transfers<u64> + Transfers<String>> Handler<I, (), Server> for Quiz {
+        fn with(this: Channel<Self, I, (), Server>) -> Defer<Self, I> {
+            this.send(6).defer()
+        }
+    }
+
+    impl<I: Timer + Transfers<u64> + Transfers<String>> Handler<I, (), Timed<Patience, Recv<u64, End>, Recv<String, End>>> for Quiz {
+        fn with(this: Channel<Self, I, (), Timed<Patience, Recv<u64, End>, Recv<String, End>>>) -> Defer<Self, I> {
+            match this.wait() {
+                Ok(d) => d,
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Timer + Transfers<u64> + Transfers<String>> Handler<I, (), Recv<u64, End>> for Quiz {
+        fn with(this: Channel<Self, I, (), Recv<u64, End>>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((num, this)) => {
+                    assert_eq!(num, 42);
+                    this.proto.log.borrow_mut().push("answered");
+                    this.close()
+                },
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Timer + Transfers<u64> + Transfers<String>> Handler<I, (), Recv<String, End>> for Quiz {
+        fn with(this: Channel<Self, I, (), Recv<String, End>>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((_, this)) => {
+                    this.proto.log.borrow_mut().push("excused");
+                    this.close()
+                },
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Timer + Transfers<u64> + Transfers<String>> Handler<I, (), Recv<u64, Answer>> for Quiz {
+        fn with(this: Channel<Self, I, (), Recv<u64, Answer>>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((_, this)) => this.answer().defer(),
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Timer + Transfers<u64> + Transfers<String>> Handler<I, (), Verdict<Send<u64, End>, Send<String, End>>> for Quiz {
+        fn with(this: Channel<Self, I, (), Verdict<Send<u64, End>, Send<String, End>>>) -> Defer<Self, I> {
+            match this.verdict() {
+                Ok(d) => d,
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Timer + Transfers<u64> + Transfers<String>> Handler<I, (), Send<u64, End>> for Quiz {
+        fn with(this: Channel<Self, I, (), Send<u64, End>>) -> Defer<Self, I> {
+            this.proto.log.borrow_mut().push("in time");
+            this.send(42).close()
+        }
+    }
+
+    impl<I: Timer + Transfers<u64> + Transfers<String>> Handler<I, (), Send<String, End>> for Quiz {
+        fn with(this: Channel<Self, I, (), Send<String, End>>) -> Defer<Self, I> {
+            this.proto.log.borrow_mut().push("too late");
+            this.send(String::from("sorry")).close()
+        }
+    }
+
+    // the answer takes a tick or ten to arrive
+    for &(latency, expected) in &[(1, ["in time", "answered"]), (10, ["too late", "excused"])] {
+        let log = Rc::new(RefCell::new(vec![]));
+        let sim = Sim::new(0).latency(latency, latency);
+        let (server, client) = sim.connect(Quiz { log: log.clone() }, Quiz { log: log.clone() });
+
+        assert_eq!(sim.run(&mut [server.defer(), client.defer()], 1000), 0);
+        assert_eq!(*log.borrow(), expected);
+    }
+}
+
+#[test]
+fn blocking_deadlines() {
+    use std::marker::PhantomData;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use nemo::channels::Blocking;
+
+    struct Quiz<D>(PhantomData<D>);
+
+    struct Patience;
+    struct Forever;
+
+    impl Deadline for Patience {
+        fn duration() -> Duration {
+            Duration::from_millis(20)
+        }
+    }
+
+    impl Deadline for Forever {
+        fn duration() -> Duration {
+            Duration::from_secs(60)
+        }
+    }
+
+    impl<D: Deadline> Protocol for Quiz<D> {
+        type Initial = Timed<D, Recv<u64, End>, Recv<String, End>>;
+    }
+
+    impl<D: Deadline> Handler<Blocking, (), Timed<D, Recv<u64, End>, Recv<String, End>>> for Quiz<D> {
+        fn with(this: Channel<Self, Blocking, (), Timed<D, Recv<u64, End>, Recv<String, End>>>) -> Defer<Self, Blocking> {
+            match this.wait() {
+                Ok(d) => d,
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<D: Deadline> Handler<Blocking, (), Recv<u64, End>> for Quiz<D> {
+        fn with(_: Channel<Self, Blocking, (), Recv<u64, End>>) -> Defer<Self, Blocking> {
+            panic!("the answer was late");
+        }
+    }
+
+    impl<D: Deadline> Handler<Blocking, (), Recv<String, End>> for Quiz<D> {
+        fn with(this: Channel<Self, Blocking, (), Recv<String, End>>) -> Defer<Self, Blocking> {
+            match this.recv() {
+                Ok((excuse, this)) => {
+                    assert_eq!(excuse, "sorry");
+                    this.close()
+                },
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<D: Deadline> Handler<Blocking, (), Send<u64, End>> for Quiz<D> {
+        fn with(_: Channel<Self, Blocking, (), Send<u64, End>>) -> Defer<Self, Blocking> {
+            panic!("the answer was in time");
+        }
+    }
+
+    impl<D: Deadline> Handler<Blocking, (), Send<String, End>> for Quiz<D> {
+        fn with(this: Channel<Self, Blocking, (), Send<String, End>>) -> Defer<Self, Blocking> {
+            this.send(String::from("sorry")).close()
+        }
+    }
+
+    let (server, client) = Blocking::new(Quiz::<Patience>(PhantomData), Quiz(PhantomData));
+
+    let peer = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        let mut client = client.answer().verdict().ok().unwrap();
+        while client.with() { }
+    });
+
+    // the late answer arrives before the excuse, and is skipped
+    let mut server = server.defer();
+    while server.with() { }
+    peer.join().unwrap();
+
+    // a peer which hung up will never answer, so there is no point in
+    // waiting for the deadline
+    let (server, client) = Blocking::new(Quiz::<Forever>(PhantomData), Quiz(PhantomData));
+    drop(client);
+
+    let start = Instant::now();
+    assert_eq!(server.defer().with(), false);
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn checkpointed_sessions() {
+    use nemo::channels::Blocking;
+    use nemo::checkpoint::{self, Registry, Snapshot};
+    use nemo::wire::{self, Encode, Decode};
+
+    struct Tally {
+        sum: u64
+    }
+
+    impl Encode for Tally {
+        fn encode(&self, out: &mut Vec<u8>) {
+            self.sum.encode(out);
+        }
+    }
+
+    impl Decode for Tally {
+        fn decode(input: &mut &[u8]) -> Option<Tally> {
+            u64::decode(input).map(|sum| Tally { sum: sum })
+        }
+    }
+
+    // add up two numbers, with a restart in between
+    type Server = proto!(Recv u64, Recv u64, Send u64, End);
+
+    impl Protocol for Tally {
+        type Initial = Server;
+    }
+
+    impl<I: Transfers<u64>> Handler<I, (), Server> for Tally {
+        fn with(this: Channel<Self, I, (), Server>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((num, mut this)) => {
+                    this.proto.sum += num;
+                    this.defer()
+                },
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<u64>> Handler<I, (), Recv<u64, Send<u64, End>>> for Tally {
+        fn with(this: Channel<Self, I, (), Recv<u64, Send<u64, End>>>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((num, mut this)) => {
+                    this.proto.sum += num;
+                    this.defer()
+                },
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    impl<I: Transfers<u64>> Handler<I, (), Send<u64, End>> for Tally {
+        fn with(this: Channel<Self, I, (), Send<u64, End>>) -> Defer<Self, I> {
+            let sum = this.proto.sum;
+            this.send(sum).close()
+        }
+    }
+
+    impl<I: Transfers<u64>> Handler<I, (), Send<u64, Send<u64, Recv<u64, End>>>> for Tally {
+        fn with(this: Channel<Self, I, (), Send<u64, Send<u64, Recv<u64, End>>>>) -> Defer<Self, I> {
+            this.send(5).defer()
+        }
+    }
+
+    impl<I: Transfers<u64>> Handler<I, (), Send<u64, Recv<u64, End>>> for Tally {
+        fn with(this: Channel<Self, I, (), Send<u64, Recv<u64, End>>>) -> Defer<Self, I> {
+            this.send(7).defer()
+        }
+    }
+
+    impl<I: Transfers<u64>> Handler<I, (), Recv<u64, End>> for Tally {
+        fn with(this: Channel<Self, I, (), Recv<u64, End>>) -> Defer<Self, I> {
+            match this.recv() {
+                Ok((sum, this)) => {
+                    assert_eq!(sum, 12);
+                    this.close()
+                },
+                Err(this) => this.defer()
+            }
+        }
+    }
+
+    let (server, client) = Blocking::new(Tally { sum: 0 }, Tally { sum: 0 });
+    let (mut server, mut client) = (server.defer(), client.defer());
+    assert!(client.with());
+    assert!(server.with());
+
+    // both sides save their sessions to storage, and the process exits
+    let saved = wire::to_bytes(&(server.checkpoint().unwrap(), client.checkpoint().unwrap()));
+    drop((server, client));
+
+    let (server, client): (Snapshot, Snapshot) = wire::from_bytes(&saved).unwrap();
+    assert_eq!(server.state, checkpoint::state_id::<(), Recv<u64, Send<u64, End>>>());
+
+    let mut servers = Registry::<Tally, Blocking>::new();
+    servers.register::<(), Recv<u64, Send<u64, End>>>();
+    let mut clients = Registry::<Tally, Blocking>::new();
+    clients.register::<(), Send<u64, Recv<u64, End>>>();
+
+    // a snapshot of a state nobody registered, or of an older protocol
+    let mut stale = server.clone();
+    stale.protocol ^= 1;
+    unsafe {
+        assert_eq!(clients.resume(&server, Blocking::pair().0).err(), Some(checkpoint::Error::UnknownState));
+        assert_eq!(servers.resume(&stale, Blocking::pair().0).err(), Some(checkpoint::Error::ProtocolChanged));
+    }
+
+    let (a, b) = Blocking::pair();
+    let (mut server, mut client) = unsafe {
+        (servers.resume(&server, a).ok().unwrap(), clients.resume(&client, b).ok().unwrap())
+    };
+
+    assert!(client.with());
+    assert!(server.with());
+    assert!(!server.with());
+    assert!(!client.with());
+    assert!(server.checkpoint().is_none());
+}
+
+#[test]
+fn checkpointed_repetition() {
+    use nemo::channels::Blocking;
+    use nemo::checkpoint::Registry;
+    use nemo::wire::{Encode, Decode};
+
+    struct Inbox {
+        got: Vec<u64>
+    }
+
+    impl Encode for Inbox {
+        fn encode(&self, out: &mut Vec<u8>) {
+            self.got.encode(out);
+        }
+    }
+
+    impl Decode for Inbox {
+        fn decode(input: &mut &[u8]) -> Option<Inbox> {
+            Vec::decode(input).map(|got| Inbox { got: got })
+        }
+    }
+
+    impl Protocol for Inbox {
+        type Initial = RecvMany<u64, End>;
+    }
+
+    impl<I: Transfers<u64>> Handler<I, (), Remaining<u64, End>> for Inbox {
+        fn with(this: Channel<Self, I, (), Remaining<u64, End>>) -> Defer<Self, I> {
+            let mut items = this.recv_iter();
+            let got: Vec<u64> = items.by_ref().collect();
+
+            match items.finish() {
+                Ok(mut this) => {
+                    this.proto.got.extend(got);
+                    assert_eq!(this.proto.got, vec![1, 2, 3]);
+                    this.close()
+                },
+                Err(_) => panic!("items are missing")
+            }
+        }
+    }
+
+    // the peer of the resumed session, which sends the items left
+    struct Rest;
+
+    impl Protocol for Rest {
+        type Initial = Send<u64, Send<u64, End>>;
+    }
+
+    let (server, client) = Blocking::new(Inbox { got: vec![] }, Inbox { got: vec![] });
+    client.send_iter(vec![1, 2, 3]).close();
+
+    // receive one item, and save the session before the rest arrive
+    let mut items = server.recv_iter().ok().unwrap();
+    assert_eq!(items.next(), Some(1));
+    let mut server = items.pause();
+    server.proto.got.push(1);
+    let saved = server.defer().checkpoint().unwrap();
+    assert_eq!(saved.ahead, Some(2));
+
+    let mut inboxes = Registry::<Inbox, Blocking>::new();
+    inboxes.register::<(), Remaining<u64, End>>();
+
+    let (a, b) = Blocking::pair();
+    let mut server = unsafe { inboxes.resume(&saved, a) }.ok().unwrap();
+    channel(b, Rest).send(2).send(3).close();
+
+    assert!(!server.with());
+}
+
+#[test]
+#[should_panic(expected = "registered twice")]
+fn checkpointed_duplicates() {
+    use nemo::channels::Blocking;
+    use nemo::checkpoint::Registry;
+    use nemo::wire::{Encode, Decode};
+
+    struct Idle;
+
+    impl Encode for Idle {
+        fn encode(&self, _: &mut Vec<u8>) { }
+    }
+
+    impl Decode for Idle {
+        fn decode(_: &mut &[u8]) -> Option<Idle> {
+            Some(Idle)
+        }
+    }
+
+    impl Protocol for Idle {
+        type Initial = End;
+    }
+
+    impl<I: IO> Handler<I, (), End> for Idle {
+        fn with(this: Channel<Self, I, (), End>) -> Defer<Self, I> {
+            this.close()
+        }
+    }
+
+    let mut idle = Registry::<Idle, Blocking>::new();
+    idle.register::<(), End>();
+    idle.register::<(), End>();
+}